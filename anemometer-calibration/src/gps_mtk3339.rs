@@ -223,41 +223,10 @@ pub mod gps {
             self.uart.write(&CRLF).unwrap();
         }
 
-        // The MTK3339 sends frequent RMC sentences which are not valid.
-        // The contain unclear data between field 6 and field 7. So far
-        // it was not possible to find documentation of this behavior.
-        // As a hack, this function removes the data between field 6 und 7
-        // and creates a valid RMC sentence
+        // The MTK3339 sends frequent RMC sentences which are not valid,
+        // see anemometer_core::nmea::fix_rmc_sentence
         pub fn fix_rmc_sentence(s: String) -> String {
-            let v: Vec<_> = s.match_indices(',').map(|(i, _)| i).collect();
-            if s.contains("RMC") && v.len() > 12 {
-                let mut left = String::new();
-                let mut right = String::new();
-                let mut crc = 0;
-
-                let l = v[6];
-                if let Some(part) = s.get(0..l + 1) {
-                    left = part.to_string();
-                }
-                let r = v[v.len() - 6];
-                if let Some(part) = s.get(r + 1..s.len()) {
-                    right = part.to_string();
-                }
-                left.push_str(right.as_str());
-
-                for (_, &item) in left[1..left.len() - 3].as_bytes().iter().enumerate() {
-                    crc ^= item;
-                }
-
-                left.replace_range(
-                    left.len() - 2..left.len(),
-                    hex::encode_upper(vec![crc]).as_str(),
-                );
-
-                left
-            } else {
-                s
-            }
+            anemometer_core::nmea::fix_rmc_sentence(s)
         }
 
         pub fn process_gps_input(input_buffer: &mut [u8]) -> Option<String> {
@@ -0,0 +1,126 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+// Runs the statistics pipeline of the firmware on a Linux host against a
+// recorded CSV file:
+//
+//   cargo run --example replay -- GpsLog.txt          rotation rate in column 2
+//   cargo run --example replay -- log.csv 1           rotation rate in column 1
+//   cargo run --example replay -- --pulses edges.csv  pulse edges [µs]
+//
+// The recordings contain no wind direction, north is used for all samples.
+use anemometer_core::data_processing::{WindDataHistory, WindStatistics};
+use anemometer_core::plausibility::{PlausibilityFilter, PlausibilityLimits};
+use anemometer_core::pulse_source::{PulseSource, ReplaySource};
+use std::process::exit;
+
+// Same settings as the production firmware
+const MEASUREMENT_INTERVAL: u64 = 250;
+const REPORTING_INTERVAL: u64 = 120;
+const PULSES_PER_REVOLUTION: u8 = 2;
+const GPS_LOG_RPS_COLUMN: usize = 2;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (pulses, args) = match args.first().map(String::as_str) {
+        Some("--pulses") => (true, &args[1..]),
+        _ => (false, &args[..]),
+    };
+    let Some(path) = args.first() else {
+        eprintln!("usage: replay [--pulses] <file.csv> [rps column]");
+        exit(2);
+    };
+
+    let csv = std::fs::read_to_string(path).unwrap_or_else(|err| {
+        eprintln!("failed to read {path}: {err}");
+        exit(1);
+    });
+    let source = if pulses {
+        ReplaySource::from_pulse_csv(&csv, PULSES_PER_REVOLUTION, MEASUREMENT_INTERVAL)
+    } else {
+        let column = args
+            .get(1)
+            .map_or(Some(GPS_LOG_RPS_COLUMN), |column| column.parse().ok())
+            .unwrap_or_else(|| {
+                eprintln!("invalid rps column");
+                exit(2);
+            });
+        ReplaySource::from_rps_csv(&csv, column, MEASUREMENT_INTERVAL)
+    };
+    let mut source = source.unwrap_or_else(|err| {
+        eprintln!("{path}: {err}");
+        exit(1);
+    });
+
+    let mut plausibility_filter = PlausibilityFilter::new(PlausibilityLimits {
+        max_rps: 50.0,
+        max_step: 10.0,
+    });
+    let mut wind_historian = WindDataHistory::default();
+    let report_samples = REPORTING_INTERVAL * 1000 / MEASUREMENT_INTERVAL;
+    let mut samples = 0;
+
+    while let Some(reading) = source.read() {
+        plausibility_filter.pulses_bounced(reading.bounced);
+        let rps = plausibility_filter.filter(reading.rps);
+        wind_historian.store_measurement(rps, 0, samples * MEASUREMENT_INTERVAL / 1000);
+        samples += 1;
+
+        if samples % report_samples == 0 {
+            report(&mut wind_historian, samples);
+        }
+    }
+    if samples % report_samples != 0 {
+        report(&mut wind_historian, samples);
+    }
+
+    println!("{:?}", plausibility_filter.health());
+}
+
+fn report(wind_historian: &mut WindDataHistory, samples: u64) {
+    let time = samples * MEASUREMENT_INTERVAL / 1000;
+    println!(
+        "{:02}:{:02}:{:02} mean {:.2} km/h, max {:.2} km/h, gust {:.2} km/h",
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        wind_historian.avg_speed(),
+        wind_historian.max_speed(),
+        wind_historian.gust_speed()
+    );
+    for stats in wind_historian.statistics() {
+        println!(
+            "    {:4} s: {} samples, mean {:.2}, std dev {:.2}, gust factor {:.2}",
+            stats.window, stats.samples, stats.mean, stats.std_dev, stats.gust_factor
+        );
+    }
+    wind_historian.clear_wind_gust();
+}
@@ -0,0 +1,334 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use hmac::{Hmac, Mac};
+use log::*;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Maximum difference between the time stamp of a command and the device
+// clock [s]
+pub const DEFAULT_AUTH_WINDOW: u64 = 300;
+// Commands are rejected as long as the device clock hasn't been set by SNTP
+const MIN_VALID_TIME: u64 = 1_600_000_000;
+// Nonces of the accepted commands within the window, further commands are
+// rejected until the oldest nonce expires
+const NONCE_CAPACITY: usize = 32;
+const MAX_NONCE_LEN: usize = 64;
+
+// Envelope of an authenticated command. The signature is the hex encoded
+// HMAC-SHA256 over "<topic>\n<timestamp>\n<nonce>\n<payload>", which binds
+// the command to the device and topic it was sent to.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedCommand {
+    // original payload of the command topic
+    pub payload: String,
+    // unix time [s]
+    pub timestamp: u64,
+    pub nonce: String,
+    pub signature: String,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AuthError {
+    Malformed,
+    InvalidSignature,
+    ClockNotSet,
+    Expired,
+    Replayed,
+    NonceWindowFull,
+}
+
+// Verifies the signature, time stamp and nonce of the commands
+pub struct CommandAuth {
+    key: Vec<u8>,
+    window: u64,
+    clock: fn() -> u64,
+    // nonce and time stamp of the accepted commands, oldest first
+    nonces: VecDeque<(String, u64)>,
+    // seconds since the boot, commands signed before the boot are rejected
+    uptime: Option<fn() -> u64>,
+    // newest time stamp accepted before the boot, the nonces of that time
+    // are lost with the restart
+    replay_floor: u64,
+    // newest time stamp accepted so far and where it is persisted
+    last_accepted: u64,
+    store: Option<fn(u64)>,
+}
+
+impl SignedCommand {
+    pub fn sign(key: &[u8], topic: &str, payload: &str, timestamp: u64, nonce: &str) -> Self {
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+        mac.update(&Self::signed_data(topic, payload, timestamp, nonce));
+        SignedCommand {
+            payload: payload.into(),
+            timestamp,
+            nonce: nonce.into(),
+            signature: to_hex(&mac.finalize().into_bytes()),
+        }
+    }
+
+    fn signed_data(topic: &str, payload: &str, timestamp: u64, nonce: &str) -> Vec<u8> {
+        format!("{topic}\n{timestamp}\n{nonce}\n{payload}").into_bytes()
+    }
+}
+
+impl CommandAuth {
+    pub fn new(key: &[u8], window: u64, clock: fn() -> u64) -> Self {
+        CommandAuth {
+            key: key.into(),
+            window,
+            clock,
+            nonces: VecDeque::new(),
+            uptime: None,
+            replay_floor: 0,
+            last_accepted: 0,
+            store: None,
+        }
+    }
+
+    pub fn with_uptime(mut self, uptime: fn() -> u64) -> Self {
+        self.uptime = Some(uptime);
+        self
+    }
+
+    // last_accepted is the time stamp persisted by store before the restart
+    pub fn with_last_accepted(mut self, last_accepted: u64, store: fn(u64)) -> Self {
+        self.replay_floor = last_accepted;
+        self.last_accepted = last_accepted;
+        self.store = Some(store);
+        self
+    }
+
+    // Returns the payload of the command if the envelope is valid
+    pub fn verify(&mut self, topic: &str, data: &[u8]) -> Result<String, AuthError> {
+        let signed: SignedCommand =
+            serde_json::from_slice(data).map_err(|_| AuthError::Malformed)?;
+        if signed.nonce.is_empty() || signed.nonce.len() > MAX_NONCE_LEN {
+            return Err(AuthError::Malformed);
+        }
+        let signature = from_hex(&signed.signature).ok_or(AuthError::Malformed)?;
+
+        // the comparison of the MAC is done in constant time
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(&SignedCommand::signed_data(
+            topic,
+            &signed.payload,
+            signed.timestamp,
+            &signed.nonce,
+        ));
+        mac.verify_slice(&signature)
+            .map_err(|_| AuthError::InvalidSignature)?;
+
+        let now = (self.clock)();
+        if now < MIN_VALID_TIME {
+            return Err(AuthError::ClockNotSet);
+        }
+        if now.abs_diff(signed.timestamp) > self.window {
+            return Err(AuthError::Expired);
+        }
+
+        // a command captured before a restart would pass the nonce check
+        // after it, as long as it is within the window
+        if signed.timestamp <= self.replay_floor {
+            return Err(AuthError::Replayed);
+        }
+        if let Some(uptime) = self.uptime {
+            if signed.timestamp < now.saturating_sub(uptime()) {
+                return Err(AuthError::Replayed);
+            }
+        }
+
+        // a nonce only needs to be remembered as long as its time stamp is
+        // within the window
+        let window = self.window;
+        self.nonces
+            .retain(|(_, timestamp)| now.abs_diff(*timestamp) <= window);
+        if self.nonces.iter().any(|(nonce, _)| *nonce == signed.nonce) {
+            return Err(AuthError::Replayed);
+        }
+        if self.nonces.len() >= NONCE_CAPACITY {
+            return Err(AuthError::NonceWindowFull);
+        }
+        self.nonces.push_back((signed.nonce, signed.timestamp));
+
+        if signed.timestamp > self.last_accepted {
+            self.last_accepted = signed.timestamp;
+            if let Some(store) = self.store {
+                store(signed.timestamp);
+            }
+        }
+
+        debug!("Command on {} authenticated", topic);
+        Ok(signed.payload)
+    }
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 == 1 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+    const TOPIC: &str = "anemometer/device-1/command/system_restart";
+    const NOW: u64 = 1_700_000_000;
+
+    fn clock() -> u64 {
+        NOW
+    }
+
+    fn hmac_sha256(key: &[u8], data: &[u8]) -> String {
+        let mut mac = HmacSha256::new_from_slice(key).unwrap();
+        mac.update(data);
+        to_hex(&mac.finalize().into_bytes())
+    }
+
+    // FIPS 180-4 and RFC 4231 vectors, guard against a misconfigured digest
+    #[test]
+    fn hmac_sha256_test() {
+        use sha2::Digest;
+
+        assert_eq!(
+            to_hex(&Sha256::digest(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            to_hex(&Sha256::digest([b'a'; 1000])),
+            "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3"
+        );
+        // RFC 4231 test cases 2 and 6
+        assert_eq!(
+            hmac_sha256(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            ),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn verify_test() {
+        let mut auth = CommandAuth::new(KEY, DEFAULT_AUTH_WINDOW, clock);
+        let message = |timestamp, nonce: &str| {
+            serde_json::to_vec(&SignedCommand::sign(KEY, TOPIC, "", timestamp, nonce)).unwrap()
+        };
+
+        assert_eq!(
+            auth.verify(TOPIC, &message(NOW - 10, "n1")),
+            Ok(String::new())
+        );
+        // replayed within the window
+        assert_eq!(
+            auth.verify(TOPIC, &message(NOW - 10, "n1")),
+            Err(AuthError::Replayed)
+        );
+        assert_eq!(
+            auth.verify(TOPIC, &message(NOW - DEFAULT_AUTH_WINDOW - 1, "n2")),
+            Err(AuthError::Expired)
+        );
+        // signed for another device
+        assert_eq!(
+            auth.verify(
+                "anemometer/device-2/command/system_restart",
+                &message(NOW, "n3")
+            ),
+            Err(AuthError::InvalidSignature)
+        );
+        let mut forged = SignedCommand::sign(b"wrong key", TOPIC, "", NOW, "n4");
+        assert_eq!(
+            auth.verify(TOPIC, &serde_json::to_vec(&forged).unwrap()),
+            Err(AuthError::InvalidSignature)
+        );
+        forged.signature = String::from("xyz");
+        assert_eq!(
+            auth.verify(TOPIC, &serde_json::to_vec(&forged).unwrap()),
+            Err(AuthError::Malformed)
+        );
+        assert_eq!(auth.verify(TOPIC, b""), Err(AuthError::Malformed));
+
+        // accepted before the restart
+        static STORED: AtomicU64 = AtomicU64::new(0);
+        let mut auth = CommandAuth::new(KEY, DEFAULT_AUTH_WINDOW, clock)
+            .with_last_accepted(NOW - 10, |timestamp| {
+                STORED.store(timestamp, Ordering::Relaxed)
+            });
+        assert_eq!(
+            auth.verify(TOPIC, &message(NOW - 10, "n1")),
+            Err(AuthError::Replayed)
+        );
+        assert_eq!(
+            auth.verify(TOPIC, &message(NOW - 9, "n1")),
+            Ok(String::new())
+        );
+        assert_eq!(STORED.load(Ordering::Relaxed), NOW - 9);
+
+        // signed before the boot 60 s ago
+        let mut auth = CommandAuth::new(KEY, DEFAULT_AUTH_WINDOW, clock).with_uptime(|| 60);
+        assert_eq!(
+            auth.verify(TOPIC, &message(NOW - 61, "n1")),
+            Err(AuthError::Replayed)
+        );
+        assert_eq!(
+            auth.verify(TOPIC, &message(NOW - 60, "n1")),
+            Ok(String::new())
+        );
+
+        let mut auth = CommandAuth::new(KEY, DEFAULT_AUTH_WINDOW, || 0);
+        assert_eq!(
+            auth.verify(TOPIC, &message(NOW, "n5")),
+            Err(AuthError::ClockNotSet)
+        );
+    }
+}
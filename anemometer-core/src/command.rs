@@ -0,0 +1,323 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::live_stream::LiveStreamRequest;
+use crate::mqtt_msg::{CmdMqttMsg, OtaUrl, MQTT_TOPIC_POSTFIX_COMMAND_RESPONSE};
+use alloc::string::String;
+use log::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub const COMMAND_GET_STATUS: &str = "get_status";
+pub const COMMAND_GET_CONFIG: &str = "get_config";
+pub const COMMAND_SET_LOG_LEVEL: &str = "set_log_level";
+pub const COMMAND_REPORT_NOW: &str = "report_now";
+pub const COMMAND_FACTORY_RESET: &str = "factory_reset";
+pub const COMMAND_SYSTEM_RESTART: &str = "system_restart";
+pub const COMMAND_OTA_UPDATE: &str = "ota_update";
+pub const COMMAND_START_STREAM: &str = "start_stream";
+pub const COMMAND_STOP_STREAM: &str = "stop_stream";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Command {
+    GetStatus,
+    GetConfig,
+    // log level as accepted by log::LevelFilter
+    SetLogLevel(String),
+    ReportNow,
+    // resets the settings to the firmware defaults and restarts
+    FactoryReset,
+    SystemRestart,
+    OtaUpdate(OtaUrl),
+    // publishes the raw samples to the live topic for a limited time
+    StartStream(LiveStreamRequest),
+    StopStream,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CommandError {
+    UnknownCommand,
+    InvalidArgument,
+    PayloadTooLarge,
+    Failed,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ResponseStatus {
+    Ok,
+    Error,
+}
+
+// Command received on <topic_prefix>/<device_id>/command/request. Unknown
+// commands and invalid arguments are kept, so they can be answered with an
+// error response.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandRequest {
+    pub cmd: String,
+    pub correlation_id: Option<String>,
+    // the default response topic is used if not given
+    pub response_topic: Option<String>,
+    pub command: Result<Command, CommandError>,
+}
+
+// Published to the response topic of the request before any side effect
+// of the command, e.g. a restart, takes place
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandResponse {
+    pub cmd: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    pub status: ResponseStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<CommandError>,
+}
+
+impl CommandRequest {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        match serde_json::from_slice::<CmdMqttMsg>(data) {
+            Ok(msg) => Some(Self::from(msg)),
+            Err(err) => {
+                warn!("Invalid command request: {}", err);
+                None
+            }
+        }
+    }
+
+    // Request without correlation id, used for the commands received on
+    // the single purpose command topics
+    pub fn new(cmd: &str, command: Command) -> Self {
+        CommandRequest {
+            cmd: cmd.into(),
+            correlation_id: None,
+            response_topic: None,
+            command: Ok(command),
+        }
+    }
+
+    // Request which could not be parsed, it's only answered with the error
+    pub fn rejected(cmd: &str, error: CommandError) -> Self {
+        CommandRequest {
+            cmd: cmd.into(),
+            correlation_id: None,
+            response_topic: None,
+            command: Err(error),
+        }
+    }
+
+    // Response topic of the request if it's below the topics of the device,
+    // <topic_prefix>/<device_id>. The command topics are excluded, so a
+    // response can't trigger another command.
+    pub fn response_topic(&self, device_prefix: &str) -> Option<&str> {
+        let topic = self.response_topic.as_deref()?;
+        let valid = match topic.strip_prefix(device_prefix) {
+            Some(postfix) => {
+                postfix.len() > 1
+                    && postfix.starts_with('/')
+                    && !postfix.contains(['+', '#'])
+                    && (!postfix.starts_with("/command/")
+                        || matches!(
+                            postfix.strip_prefix(MQTT_TOPIC_POSTFIX_COMMAND_RESPONSE),
+                            Some(sub_topic) if sub_topic.is_empty() || sub_topic.starts_with('/')
+                        ))
+            }
+            None => false,
+        };
+
+        if valid {
+            Some(topic)
+        } else {
+            warn!("Response topic {} rejected, using the default", topic);
+            None
+        }
+    }
+
+    pub fn ok(&self, result: Option<Value>) -> CommandResponse {
+        CommandResponse {
+            cmd: self.cmd.clone(),
+            correlation_id: self.correlation_id.clone(),
+            status: ResponseStatus::Ok,
+            result,
+            error: None,
+        }
+    }
+
+    pub fn error(&self, error: CommandError) -> CommandResponse {
+        CommandResponse {
+            cmd: self.cmd.clone(),
+            correlation_id: self.correlation_id.clone(),
+            status: ResponseStatus::Error,
+            result: None,
+            error: Some(error),
+        }
+    }
+
+    fn parse_command(cmd: &str, arg: &str) -> Result<Command, CommandError> {
+        let no_arg = |command| {
+            if arg.is_empty() {
+                Ok(command)
+            } else {
+                Err(CommandError::InvalidArgument)
+            }
+        };
+
+        match cmd {
+            COMMAND_GET_STATUS => no_arg(Command::GetStatus),
+            COMMAND_GET_CONFIG => no_arg(Command::GetConfig),
+            COMMAND_REPORT_NOW => no_arg(Command::ReportNow),
+            COMMAND_FACTORY_RESET => no_arg(Command::FactoryReset),
+            COMMAND_SYSTEM_RESTART => no_arg(Command::SystemRestart),
+            COMMAND_STOP_STREAM => no_arg(Command::StopStream),
+            COMMAND_START_STREAM => LiveStreamRequest::parse(arg)
+                .map(Command::StartStream)
+                .ok_or(CommandError::InvalidArgument),
+            COMMAND_SET_LOG_LEVEL => match arg.parse::<LevelFilter>() {
+                Ok(_) => Ok(Command::SetLogLevel(arg.to_ascii_lowercase())),
+                Err(_) => Err(CommandError::InvalidArgument),
+            },
+            COMMAND_OTA_UPDATE => {
+                let mut url = OtaUrl::new();
+                if arg.is_empty() || url.push_str(arg).is_err() {
+                    Err(CommandError::InvalidArgument)
+                } else {
+                    Ok(Command::OtaUpdate(url))
+                }
+            }
+            _ => Err(CommandError::UnknownCommand),
+        }
+    }
+}
+
+impl From<CmdMqttMsg> for CommandRequest {
+    fn from(msg: CmdMqttMsg) -> Self {
+        CommandRequest {
+            command: Self::parse_command(&msg.cmd, &msg.arg),
+            cmd: msg.cmd,
+            correlation_id: msg.correlation_id,
+            response_topic: msg.response_topic,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn request_test() {
+        let request = CommandRequest::parse(
+            br#"{"cmd":"set_log_level","arg":"Debug","correlationId":"42","responseTopic":"app/replies"}"#,
+        )
+        .unwrap();
+        assert_eq!(request.command, Ok(Command::SetLogLevel("debug".into())));
+        assert_eq!(request.correlation_id.as_deref(), Some("42"));
+        assert_eq!(request.response_topic.as_deref(), Some("app/replies"));
+
+        let request = CommandRequest::parse(br#"{"cmd":"get_status"}"#).unwrap();
+        assert_eq!(request.command, Ok(Command::GetStatus));
+        assert_eq!(request.correlation_id, None);
+
+        let request = CommandRequest::parse(br#"{"cmd":"report_now","arg":"1"}"#).unwrap();
+        assert_eq!(request.command, Err(CommandError::InvalidArgument));
+        let request = CommandRequest::parse(br#"{"cmd":"set_log_level","arg":"loud"}"#).unwrap();
+        assert_eq!(request.command, Err(CommandError::InvalidArgument));
+        let request = CommandRequest::parse(br#"{"cmd":"start_stream","arg":"10,2"}"#).unwrap();
+        assert_eq!(
+            request.command,
+            Ok(Command::StartStream(LiveStreamRequest {
+                minutes: 10,
+                decimation: 2
+            }))
+        );
+        let request = CommandRequest::parse(br#"{"cmd":"start_stream","arg":"600"}"#).unwrap();
+        assert_eq!(request.command, Err(CommandError::InvalidArgument));
+        let request = CommandRequest::parse(br#"{"cmd":"self_destruct"}"#).unwrap();
+        assert_eq!(request.command, Err(CommandError::UnknownCommand));
+
+        assert_eq!(CommandRequest::parse(b"get_status"), None);
+        assert_eq!(CommandRequest::parse(br#"{"arg":"debug"}"#), None);
+    }
+
+    #[test]
+    fn response_topic_test() {
+        const PREFIX: &str = "anemometer/device-1";
+        let request = |topic: &str| CommandRequest {
+            response_topic: Some(topic.into()),
+            ..CommandRequest::new(COMMAND_GET_STATUS, Command::GetStatus)
+        };
+
+        for topic in [
+            "anemometer/device-1/command/response",
+            "anemometer/device-1/command/response/app-7",
+            "anemometer/device-1/replies",
+        ] {
+            assert_eq!(request(topic).response_topic(PREFIX), Some(topic));
+        }
+        for topic in [
+            "anemometer/device-2/replies",
+            "anemometer/device-10/replies",
+            "other/topic",
+            "anemometer/device-1",
+            "anemometer/device-1/",
+            "anemometer/device-1/command/request",
+            "anemometer/device-1/command/system_restart",
+            "anemometer/device-1/command/responses",
+            "anemometer/device-1/#",
+        ] {
+            assert_eq!(request(topic).response_topic(PREFIX), None, "{topic}");
+        }
+        assert_eq!(
+            CommandRequest::new(COMMAND_GET_STATUS, Command::GetStatus).response_topic(PREFIX),
+            None
+        );
+    }
+
+    #[test]
+    fn response_test() {
+        let request =
+            CommandRequest::parse(br#"{"cmd":"get_status","correlationId":"7"}"#).unwrap();
+        assert_eq!(
+            serde_json::to_value(request.ok(Some(json!({"rssi": -60})))).unwrap(),
+            json!({"cmd": "get_status", "correlationId": "7", "status": "ok", "result": {"rssi": -60}})
+        );
+
+        let request = CommandRequest::parse(br#"{"cmd":"reboot"}"#).unwrap();
+        assert_eq!(
+            serde_json::to_value(request.error(CommandError::UnknownCommand)).unwrap(),
+            json!({"cmd": "reboot", "status": "error", "error": "unknownCommand"})
+        );
+    }
+}
@@ -0,0 +1,680 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::units::{normalize_degrees, SpeedUnit};
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+// Pulses generated by the hall sensor per revolution of the cups rotor
+// for the 3D printed anemometer (two magnets)
+const DEFAULT_PULSES_PER_REVOLUTION: u8 = 2;
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CalibrationPoint {
+    // rotations per second
+    pub rps: f32,
+    // wind speed [km/h]
+    pub kmh: f32,
+}
+
+// Transfer function to convert the rotation speed of the cups rotor into
+// wind speed. The coefficients are determined for every anemometer by the
+// calibration firmware (GPS reference) and stored in the conf partition,
+// so no firmware rebuild is required for a new device.
+//
+// If the table contains at least two points the wind speed is interpolated
+// piecewise linear (and extrapolated using the first or last segment),
+// otherwise the linear model slope * rps + offset is used. The offset is
+// the starting threshold of the anemometer, a rotor which does not turn
+// reports 0 km/h.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Calibration {
+    pub id: String,
+    pub pulses_per_revolution: u8,
+    // [km/h per rps]
+    pub slope: f32,
+    // [km/h]
+    pub offset: f32,
+    pub table: Vec<CalibrationPoint>,
+}
+
+impl Calibration {
+    pub fn rps_to_kmh(&self, rps: f32) -> f32 {
+        if rps <= 0.0 {
+            return 0.0;
+        }
+
+        if self.table.len() < 2 {
+            return self.slope * rps + self.offset;
+        }
+
+        // find the segment containing rps, outside of the table range the
+        // first or last segment is used for extrapolation
+        let idx = self
+            .table
+            .iter()
+            .position(|point| point.rps > rps)
+            .unwrap_or(self.table.len())
+            .clamp(1, self.table.len() - 1);
+        let (p0, p1) = (self.table[idx - 1], self.table[idx]);
+        let kmh = p0.kmh + (rps - p0.rps) * (p1.kmh - p0.kmh) / (p1.rps - p0.rps);
+
+        kmh.max(0.0)
+    }
+
+    // Parses a calibration table in the format "rps:kmh;rps:kmh;...".
+    // The rps values need to be strictly increasing.
+    pub fn parse_table(table: &str) -> Option<Vec<CalibrationPoint>> {
+        let mut points: Vec<CalibrationPoint> = Vec::new();
+
+        for entry in table.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            let (rps, kmh) = entry.split_once(':')?;
+            let point = CalibrationPoint {
+                rps: rps.trim().parse().ok()?,
+                kmh: kmh.trim().parse().ok()?,
+            };
+
+            if let Some(last) = points.last() {
+                if point.rps <= last.rps {
+                    return None;
+                }
+            }
+            points.push(point);
+        }
+
+        Some(points)
+    }
+
+    // Formats a calibration table for the conf partition, inverse of
+    // parse_table
+    pub fn format_table(table: &[CalibrationPoint]) -> String {
+        table
+            .iter()
+            .map(|point| format!("{}:{}", point.rps, point.kmh))
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self {
+            id: String::from("default"),
+            pulses_per_revolution: DEFAULT_PULSES_PER_REVOLUTION,
+            slope: 1.0,
+            offset: 0.0,
+            table: Vec::new(),
+        }
+    }
+}
+
+// Default sampling interval [ms], WMO-No. 8 recommends 4 Hz sampling for
+// the measurement of wind gusts
+const DEFAULT_SAMPLE_INTERVAL: u64 = 250;
+// Default averaging windows [s], see WMO-No. 8 below
+const DEFAULT_WINDOWS: [u64; 2] = [120, 600];
+// Averaging time for the running mean of wind gusts [s]
+const GUST_AVERAGING_TIME: u64 = 3;
+
+// Statistics of the wind speed within one averaging window. All speeds are
+// in km/h, the calibration is applied to every sample before averaging.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WindowStatistics {
+    // length of the window [s]
+    pub window: u64,
+    // number of samples available within the window
+    pub samples: usize,
+    pub mean: f32,
+    pub min: f32,
+    pub max: f32,
+    pub std_dev: f32,
+    // standard deviation / mean
+    pub turbulence_intensity: f32,
+    // highest 3 sec running mean within the window / mean
+    pub gust_factor: f32,
+    // vector average of the wind direction [degree]
+    pub direction: f32,
+}
+
+impl WindowStatistics {
+    // Converts the wind speeds into the given unit. As Beaufort is a scale
+    // the statistics are converted to m/s in this case.
+    pub fn convert(&self, unit: SpeedUnit) -> WindowStatistics {
+        let unit = unit.linear_unit();
+
+        WindowStatistics {
+            mean: unit.convert(self.mean),
+            min: unit.convert(self.min),
+            max: unit.convert(self.max),
+            std_dev: unit.convert(self.std_dev),
+            ..*self
+        }
+    }
+}
+
+// Peak wind gust within a reporting period
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Gust {
+    // highest 3 sec running mean [km/h]
+    pub speed: f32,
+    // unix time of the last sample of the 3 sec interval [s]
+    pub time: u64,
+    // vector average of the wind direction during the 3 sec interval [degree]
+    pub direction: f32,
+}
+
+// WMO-No. 8 section 5.8.3 defines the peak gust as the maximum of the 3 sec
+// running mean of the wind speed. With the recommended sampling rate of
+// 4 Hz the running mean is calculated over 12 samples, for other sampling
+// rates the number of samples is adjusted accordingly. A gust is only
+// detected once a complete 3 sec interval has been sampled.
+pub struct GustTracker {
+    // number of samples within 3 sec
+    samples: usize,
+    // calibrated wind speed [km/h]
+    speed_buffer: VecDeque<f32>,
+    direction_buffer: VecDeque<u16>,
+    // running mean of the latest complete 3 sec interval
+    current: Option<Gust>,
+    // peak gust since the last reset
+    peak: Option<Gust>,
+}
+
+impl GustTracker {
+    pub fn new(sample_interval: u64) -> Self {
+        let samples = ((GUST_AVERAGING_TIME * 1000) / sample_interval.max(1)).max(1) as usize;
+
+        GustTracker {
+            samples,
+            speed_buffer: VecDeque::with_capacity(samples),
+            direction_buffer: VecDeque::with_capacity(samples),
+            current: None,
+            peak: None,
+        }
+    }
+
+    pub fn store(&mut self, speed: f32, direction: u16, time: u64) {
+        if self.speed_buffer.len() == self.samples {
+            self.speed_buffer.pop_front();
+            self.direction_buffer.pop_front();
+        }
+        self.speed_buffer.push_back(speed);
+        self.direction_buffer.push_back(direction);
+
+        if self.speed_buffer.len() < self.samples {
+            return;
+        }
+
+        let current = Gust {
+            speed: self.speed_buffer.iter().sum::<f32>() / self.samples as f32,
+            time,
+            direction: circular_mean(self.direction_buffer.iter()),
+        };
+        self.current = Some(current);
+        if !matches!(self.peak, Some(peak) if peak.speed >= current.speed) {
+            self.peak = Some(current);
+        }
+    }
+
+    pub fn current(&self) -> Option<Gust> {
+        self.current
+    }
+
+    pub fn peak(&self) -> Option<Gust> {
+        self.peak
+    }
+
+    // Starts a new reporting period, the running mean is kept
+    pub fn reset(&mut self) {
+        self.peak = None;
+    }
+}
+
+// This strucure is updated every sample interval (default 250ms) with
+// measurement data. The process of processing wind data follows the
+// guidance provided in the following document;
+//
+// Guide to Meteorological Instruments and
+// Methods of Observation WMO-No. 8
+// 2014 edition
+// Updated in 2017
+// According to section 1.3.2.4 Instantaneous meteorological values
+// "In order to standardize averaging algorithms it is recommended:
+//
+// (a) That atmospheric pressure, air temperature, air humidity, sea-surface temperature, visibility,
+// among others, be reported as 1 to 10 min averages, which are obtained after linearization
+// of the sensor output;
+//
+// (b) That wind, except wind gusts, be reported as 2 or 10 min averages, which are obtained after
+// linearization of the sensor output.
+//
+// These averaged values are to be considered as the “instantaneous” values of meteorological
+// variables"
+//
+// The data stored in the structure are raw data which are in case of wind speed
+// rotations per second. The samples of the longest configured window are kept,
+// statistics for any shorter window are calculated from the most recent samples.
+pub struct WindDataHistory {
+    // sampling interval [ms]
+    sample_interval: u64,
+    // configured averaging windows [s], the first one is the reporting window
+    windows: Vec<u64>,
+    // number of samples required for the longest window
+    capacity: usize,
+    // samples of the longest window, the most recent sample is at the back
+    wind_speed_buffer: VecDeque<f32>,
+    wind_direction_buffer: VecDeque<u16>,
+    // peak wind gust since the last clear_wind_gust
+    gust_tracker: GustTracker,
+    calibration: Calibration,
+}
+
+impl WindDataHistory {
+    pub fn new(sample_interval: u64, windows: &[u64]) -> Self {
+        let sample_interval = sample_interval.max(1);
+        let windows = if windows.is_empty() {
+            DEFAULT_WINDOWS.to_vec()
+        } else {
+            windows.to_vec()
+        };
+        let capacity =
+            Self::samples_for(sample_interval, windows.iter().max().copied().unwrap_or(0)).max(1);
+
+        WindDataHistory {
+            sample_interval,
+            windows,
+            capacity,
+            wind_speed_buffer: VecDeque::with_capacity(capacity),
+            wind_direction_buffer: VecDeque::with_capacity(capacity),
+            gust_tracker: GustTracker::new(sample_interval),
+            calibration: Calibration::default(),
+        }
+    }
+
+    pub fn set_calibration(&mut self, calibration: Calibration) {
+        self.calibration = calibration;
+    }
+
+    pub fn calibration(&self) -> &Calibration {
+        &self.calibration
+    }
+
+    pub fn windows(&self) -> &[u64] {
+        &self.windows
+    }
+
+    // speed is the rotation speed [rps], direction in degree and time is
+    // the unix time of the sample [s]
+    pub fn store_measurement(&mut self, speed: f32, direction: u16, time: u64) {
+        if self.wind_speed_buffer.len() == self.capacity {
+            self.wind_speed_buffer.pop_front();
+            self.wind_direction_buffer.pop_front();
+        }
+        self.wind_speed_buffer.push_back(speed);
+        self.wind_direction_buffer.push_back(direction);
+        self.gust_tracker
+            .store(self.calibration.rps_to_kmh(speed), direction, time);
+    }
+
+    // 3 sec running mean of the most recent samples, None if less than
+    // 3 sec have been sampled
+    pub fn current_gust(&self) -> Option<Gust> {
+        self.gust_tracker.current()
+    }
+
+    // Statistics for a window of the given length [s]. Any window up to the
+    // longest configured window can be requested, None is returned for
+    // longer windows or if no samples are available yet.
+    pub fn window_statistics(&self, window: u64) -> Option<WindowStatistics> {
+        let n = Self::samples_for(self.sample_interval, window);
+        if n == 0 || n > self.capacity || self.wind_speed_buffer.is_empty() {
+            return None;
+        }
+
+        let skip = self.wind_speed_buffer.len().saturating_sub(n);
+        let speeds: Vec<f32> = self
+            .wind_speed_buffer
+            .iter()
+            .skip(skip)
+            .map(|rps| self.calibration.rps_to_kmh(*rps))
+            .collect();
+        let samples = speeds.len() as f32;
+
+        let mean = speeds.iter().sum::<f32>() / samples;
+        let min = speeds.iter().copied().fold(f32::MAX, f32::min);
+        let max = speeds.iter().copied().fold(f32::MIN, f32::max);
+        let std_dev =
+            libm::sqrtf(speeds.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / samples);
+
+        let gust_samples =
+            Self::samples_for(self.sample_interval, GUST_AVERAGING_TIME).clamp(1, speeds.len());
+        let gust = speeds
+            .windows(gust_samples)
+            .map(|w| w.iter().sum::<f32>() / gust_samples as f32)
+            .fold(0.0, f32::max);
+
+        let (turbulence_intensity, gust_factor) = if mean > 0.0 {
+            (std_dev / mean, gust / mean)
+        } else {
+            (0.0, 0.0)
+        };
+
+        Some(WindowStatistics {
+            window,
+            samples: speeds.len(),
+            mean,
+            min,
+            max,
+            std_dev,
+            turbulence_intensity,
+            gust_factor,
+            direction: circular_mean(self.wind_direction_buffer.iter().skip(skip)),
+        })
+    }
+
+    // Statistics for all configured windows
+    pub fn statistics(&self) -> Vec<WindowStatistics> {
+        self.windows
+            .iter()
+            .filter_map(|window| self.window_statistics(*window))
+            .collect()
+    }
+
+    // longest window [s] which can be requested
+    pub fn max_window(&self) -> u64 {
+        self.capacity as u64 * self.sample_interval / 1000
+    }
+
+    // the window may be requested by a client, overlong windows saturate
+    fn samples_for(sample_interval: u64, window: u64) -> usize {
+        usize::try_from(window.saturating_mul(1000) / sample_interval).unwrap_or(usize::MAX)
+    }
+}
+
+impl Default for WindDataHistory {
+    fn default() -> Self {
+        Self::new(DEFAULT_SAMPLE_INTERVAL, &DEFAULT_WINDOWS)
+    }
+}
+
+// Wind direction is a circular quantity, the arithmetic mean of 350° and
+// 10° would be 180°. Therefore each sample is converted into a unit
+// vector and the direction of the vector sum is used as average.
+fn circular_mean<'a>(directions: impl Iterator<Item = &'a u16>) -> f32 {
+    let (sin_sum, cos_sum) =
+        directions.fold((0.0_f32, 0.0_f32), |(sin_sum, cos_sum), direction| {
+            let angle = (*direction as f32).to_radians();
+            (sin_sum + libm::sinf(angle), cos_sum + libm::cosf(angle))
+        });
+
+    let avg = normalize_degrees(libm::atan2f(sin_sum, cos_sum).to_degrees());
+    // normalizing may round up to 360.0 for tiny negative angles
+    if avg >= 360.0 {
+        0.0
+    } else {
+        avg
+    }
+}
+
+pub trait WindStatistics {
+    fn avg_speed(&self) -> f32;
+
+    fn avg_direction(&self) -> f32;
+
+    fn gust_speed(&self) -> f32;
+
+    fn peak_gust(&self) -> Option<Gust>;
+
+    fn max_speed(&self) -> f32;
+
+    fn clear_wind_gust(&mut self);
+}
+
+// The trait reports the statistics of the reporting window which is the
+// first configured window.
+impl WindStatistics for WindDataHistory {
+    fn avg_speed(&self) -> f32 {
+        self.window_statistics(self.windows[0])
+            .map_or(0.0, |stats| stats.mean)
+    }
+
+    fn avg_direction(&self) -> f32 {
+        self.window_statistics(self.windows[0])
+            .map_or(0.0, |stats| stats.direction)
+    }
+
+    fn gust_speed(&self) -> f32 {
+        self.gust_tracker.peak().map_or(0.0, |gust| gust.speed)
+    }
+
+    fn peak_gust(&self) -> Option<Gust> {
+        self.gust_tracker.peak()
+    }
+
+    fn max_speed(&self) -> f32 {
+        self.window_statistics(self.windows[0])
+            .map_or(0.0, |stats| stats.max)
+    }
+
+    fn clear_wind_gust(&mut self) {
+        self.gust_tracker.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn avg_speed_test() {
+        let mut wind_data = WindDataHistory::default();
+
+        for _ in 0..240 {
+            wind_data.store_measurement(1.0, 0, 0);
+        }
+        assert_eq!(wind_data.avg_speed(), 1.0);
+    }
+
+    #[test]
+    fn gust_speed_test() {
+        let mut wind_data = WindDataHistory::default();
+
+        for _ in 0..240 {
+            wind_data.store_measurement(1.0, 0, 0);
+        }
+        let gust = wind_data.gust_speed();
+        assert_eq!(gust, 1.0);
+    }
+
+    #[test]
+    fn calibration_linear_test() {
+        let calibration = Calibration {
+            slope: 2.5,
+            offset: 1.5,
+            ..Default::default()
+        };
+
+        assert_eq!(calibration.rps_to_kmh(0.0), 0.0);
+        assert_eq!(calibration.rps_to_kmh(2.0), 6.5);
+    }
+
+    #[test]
+    fn calibration_table_test() {
+        let calibration = Calibration {
+            table: Calibration::parse_table("1.0:5.0; 2.0:9.0;4.0:17.0").unwrap(),
+            ..Default::default()
+        };
+
+        assert_eq!(calibration.rps_to_kmh(0.0), 0.0);
+        assert_eq!(calibration.rps_to_kmh(1.5), 7.0);
+        assert_eq!(calibration.rps_to_kmh(3.0), 13.0);
+        // extrapolation beyond the table range
+        assert_eq!(calibration.rps_to_kmh(0.5), 3.0);
+        assert_eq!(calibration.rps_to_kmh(5.0), 21.0);
+    }
+
+    #[test]
+    fn calibration_parse_table_test() {
+        assert_eq!(Calibration::parse_table(""), Some(Vec::new()));
+        assert_eq!(Calibration::parse_table("1.0:5.0;1.0:6.0"), None);
+        assert_eq!(Calibration::parse_table("1.0;2.0"), None);
+        assert_eq!(Calibration::parse_table("a:5.0"), None);
+    }
+
+    #[test]
+    fn window_statistics_test() {
+        let mut wind_data = WindDataHistory::default();
+
+        for i in 0..240 {
+            wind_data.store_measurement(if i % 2 == 0 { 1.0 } else { 3.0 }, 0, 0);
+        }
+        let stats = wind_data.window_statistics(120).unwrap();
+        assert_eq!(stats.samples, 240);
+        assert_eq!(stats.mean, 2.0);
+        assert_eq!(stats.min, 1.0);
+        assert_eq!(stats.max, 3.0);
+        assert_eq!(stats.std_dev, 1.0);
+        assert_eq!(stats.turbulence_intensity, 0.5);
+        assert_eq!(stats.gust_factor, 1.0);
+    }
+
+    #[test]
+    fn multiple_windows_test() {
+        let mut wind_data = WindDataHistory::new(500, &[120, 600]);
+
+        for i in 0..1300 {
+            wind_data.store_measurement(if i < 1060 { 0.0 } else { 4.0 }, 0, 0);
+        }
+        let stats = wind_data.statistics();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].window, 120);
+        assert_eq!(stats[0].mean, 4.0);
+        assert_eq!(stats[1].window, 600);
+        assert_eq!(stats[1].samples, 1200);
+        assert_eq!(stats[1].mean, 0.8);
+        assert_eq!(stats[1].gust_factor, 5.0);
+
+        // any window up to the longest configured one can be requested
+        assert_eq!(wind_data.window_statistics(60).unwrap().samples, 120);
+        assert!(wind_data.window_statistics(601).is_none());
+        assert!(wind_data.window_statistics(u64::MAX).is_none());
+        assert_eq!(wind_data.max_window(), 600);
+        assert_eq!(wind_data.avg_speed(), 4.0);
+    }
+
+    #[test]
+    fn empty_history_test() {
+        let wind_data = WindDataHistory::default();
+
+        assert!(wind_data.window_statistics(120).is_none());
+        assert_eq!(wind_data.avg_speed(), 0.0);
+    }
+
+    #[test]
+    fn gust_tracker_test() {
+        let mut wind_data = WindDataHistory::default();
+
+        // 4 Hz sampling, 10 sec of 5 km/h, 3 sec of 20 km/h from east
+        for i in 0..40 {
+            wind_data.store_measurement(5.0, 0, 1000 + i / 4);
+        }
+        for i in 40..52 {
+            wind_data.store_measurement(20.0, 90, 1000 + i / 4);
+        }
+        for i in 52..100 {
+            wind_data.store_measurement(5.0, 0, 1000 + i / 4);
+        }
+
+        let gust = wind_data.peak_gust().unwrap();
+        assert_eq!(gust.speed, 20.0);
+        assert_eq!(gust.time, 1012);
+        assert!(angle_diff(gust.direction, 90.0) < 0.01);
+
+        wind_data.clear_wind_gust();
+        assert_eq!(wind_data.gust_speed(), 0.0);
+        wind_data.store_measurement(5.0, 0, 1025);
+        assert_eq!(wind_data.gust_speed(), 5.0);
+    }
+
+    #[test]
+    fn gust_running_mean_test() {
+        let mut tracker = GustTracker::new(250);
+
+        // a single spike is averaged over the 3 sec interval
+        tracker.store(24.0, 0, 0);
+        assert!(tracker.peak().is_none());
+        for _ in 0..11 {
+            tracker.store(0.0, 0, 0);
+        }
+        assert_eq!(tracker.peak().unwrap().speed, 2.0);
+
+        // 1 Hz sampling uses 3 samples
+        let mut tracker = GustTracker::new(1000);
+        for speed in [3.0, 6.0, 9.0, 0.0] {
+            tracker.store(speed, 0, 0);
+        }
+        assert_eq!(tracker.peak().unwrap().speed, 6.0);
+    }
+
+    // smallest angle between two directions in degree
+    fn angle_diff(a: f32, b: f32) -> f32 {
+        let diff = (a - b).rem_euclid(360.0);
+        diff.min(360.0 - diff)
+    }
+
+    #[test]
+    fn avg_direction_test() {
+        let mut wind_data = WindDataHistory::default();
+
+        for _ in 0..240 {
+            wind_data.store_measurement(1.0, 90, 0);
+        }
+        assert!(angle_diff(wind_data.avg_direction(), 90.0) < 0.01);
+    }
+
+    #[test]
+    fn avg_direction_wrap_around_test() {
+        let mut wind_data = WindDataHistory::default();
+
+        for i in 0..240 {
+            wind_data.store_measurement(1.0, if i % 2 == 0 { 350 } else { 10 }, 0);
+        }
+        let avg = wind_data.avg_direction();
+        assert!((0.0..360.0).contains(&avg));
+        assert!(angle_diff(avg, 0.0) < 0.01);
+    }
+}
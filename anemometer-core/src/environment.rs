@@ -0,0 +1,375 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use alloc::collections::VecDeque;
+use serde::Serialize;
+
+// Environmental data of the optional BME280 sensor (temperature, humidity
+// and air pressure) and values derived from it together with the wind.
+
+// Interval for reading the environmental sensor [ms]. The readings are
+// averaged over the reporting window by the EnvironmentHistory.
+pub const ENVIRONMENT_SAMPLE_INTERVAL: u64 = 5000;
+
+// Specific gas constants of dry air and water vapour [J/(kg K)]
+const GAS_CONSTANT_DRY_AIR: f32 = 287.058;
+const GAS_CONSTANT_WATER_VAPOUR: f32 = 461.495;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EnvironmentReading {
+    // [°C]
+    pub temperature: f32,
+    // relative humidity [%]
+    pub humidity: f32,
+    // station pressure [hPa]
+    pub pressure: f32,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EnvironmentReport {
+    // [°C]
+    pub temperature: f32,
+    // relative humidity [%]
+    pub humidity: f32,
+    // station pressure [hPa]
+    pub pressure: f32,
+    // [°C], only defined for cold and windy conditions
+    pub wind_chill: Option<f32>,
+    // [kg/m³]
+    pub air_density: f32,
+}
+
+impl EnvironmentReading {
+    // wind_speed is the mean wind speed [km/h]
+    pub fn report(&self, wind_speed: f32) -> EnvironmentReport {
+        EnvironmentReport {
+            temperature: self.temperature,
+            humidity: self.humidity,
+            pressure: self.pressure,
+            wind_chill: wind_chill(self.temperature, wind_speed),
+            air_density: air_density(self.pressure, self.temperature, self.humidity),
+        }
+    }
+}
+
+// Readings of the environmental sensor within the reporting window, the
+// most recent reading is at the back
+pub struct EnvironmentHistory {
+    capacity: usize,
+    readings: VecDeque<EnvironmentReading>,
+}
+
+impl EnvironmentHistory {
+    // window is the reporting window [s]
+    pub fn new(window: u64) -> Self {
+        EnvironmentHistory {
+            capacity: ((window * 1000) / ENVIRONMENT_SAMPLE_INTERVAL).max(1) as usize,
+            readings: VecDeque::new(),
+        }
+    }
+
+    // Stores a reading, which is expected every ENVIRONMENT_SAMPLE_INTERVAL
+    pub fn store(&mut self, reading: EnvironmentReading) {
+        while self.readings.len() >= self.capacity {
+            self.readings.pop_front();
+        }
+        self.readings.push_back(reading);
+    }
+
+    // Average of the readings within the reporting window and the values
+    // derived together with the mean wind speed [km/h]. None if no
+    // environmental sensor is available.
+    pub fn report(&self, wind_speed: f32) -> Option<EnvironmentReport> {
+        if self.readings.is_empty() {
+            return None;
+        }
+
+        let readings = self.readings.len() as f32;
+        let sum = self
+            .readings
+            .iter()
+            .fold(EnvironmentReading::default(), |sum, reading| {
+                EnvironmentReading {
+                    temperature: sum.temperature + reading.temperature,
+                    humidity: sum.humidity + reading.humidity,
+                    pressure: sum.pressure + reading.pressure,
+                }
+            });
+        let mean = EnvironmentReading {
+            temperature: sum.temperature / readings,
+            humidity: sum.humidity / readings,
+            pressure: sum.pressure / readings,
+        };
+
+        Some(mean.report(wind_speed))
+    }
+}
+
+// Wind chill index of the North American and UK standard (JAG/TI 2001),
+// defined for temperatures up to 10 °C and wind speeds above 4.8 km/h.
+// temperature [°C], wind_speed [km/h]
+pub fn wind_chill(temperature: f32, wind_speed: f32) -> Option<f32> {
+    if temperature > 10.0 || wind_speed <= 4.8 {
+        return None;
+    }
+
+    let v = libm::powf(wind_speed, 0.16);
+    Some(13.12 + 0.6215 * temperature - 11.37 * v + 0.3965 * temperature * v)
+}
+
+// Density of moist air [kg/m³] as sum of the partial densities of dry air
+// and water vapour. The saturation vapour pressure is approximated by the
+// Magnus formula. pressure [hPa], temperature [°C], humidity [%]
+pub fn air_density(pressure: f32, temperature: f32, humidity: f32) -> f32 {
+    let saturation_pressure = 6.1078 * libm::powf(10.0, 7.5 * temperature / (temperature + 237.3));
+    let vapour_pressure = humidity.clamp(0.0, 100.0) / 100.0 * saturation_pressure;
+    let dry_pressure = pressure - vapour_pressure;
+    let kelvin = temperature + 273.15;
+
+    (dry_pressure * 100.0 / (GAS_CONSTANT_DRY_AIR * kelvin))
+        + (vapour_pressure * 100.0 / (GAS_CONSTANT_WATER_VAPOUR * kelvin))
+}
+
+// Trimming parameters stored in the NVM of every BME280. The compensation
+// follows the integer reference implementation of the Bosch datasheet
+// (BST-BME280-DS002, section 4.2.3).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Bme280Calibration {
+    t1: u16,
+    t2: i16,
+    t3: i16,
+    p1: u16,
+    p2: i16,
+    p3: i16,
+    p4: i16,
+    p5: i16,
+    p6: i16,
+    p7: i16,
+    p8: i16,
+    p9: i16,
+    h1: u8,
+    h2: i16,
+    h3: u8,
+    h4: i16,
+    h5: i16,
+    h6: i8,
+}
+
+impl Bme280Calibration {
+    // tp holds the registers 0x88..0xA1, h the registers 0xE1..0xE7
+    pub fn from_registers(tp: &[u8; 26], h: &[u8; 7]) -> Self {
+        let u16_at = |idx: usize| u16::from_le_bytes([tp[idx], tp[idx + 1]]);
+        let i16_at = |idx: usize| u16_at(idx) as i16;
+
+        Bme280Calibration {
+            t1: u16_at(0),
+            t2: i16_at(2),
+            t3: i16_at(4),
+            p1: u16_at(6),
+            p2: i16_at(8),
+            p3: i16_at(10),
+            p4: i16_at(12),
+            p5: i16_at(14),
+            p6: i16_at(16),
+            p7: i16_at(18),
+            p8: i16_at(20),
+            p9: i16_at(22),
+            h1: tp[25],
+            h2: i16::from_le_bytes([h[0], h[1]]),
+            h3: h[2],
+            // 12 bit values sharing the register 0xE5
+            h4: ((h[3] as i8 as i16) << 4) | (h[4] & 0x0f) as i16,
+            h5: ((h[5] as i8 as i16) << 4) | (h[4] >> 4) as i16,
+            h6: h[6] as i8,
+        }
+    }
+
+    // data holds the registers 0xF7..0xFE (press, temp, hum)
+    pub fn compensate(&self, data: &[u8; 8]) -> EnvironmentReading {
+        let adc_p = ((data[0] as i32) << 12) | ((data[1] as i32) << 4) | (data[2] as i32 >> 4);
+        let adc_t = ((data[3] as i32) << 12) | ((data[4] as i32) << 4) | (data[5] as i32 >> 4);
+        let adc_h = ((data[6] as i32) << 8) | data[7] as i32;
+
+        let t_fine = self.t_fine(adc_t);
+
+        EnvironmentReading {
+            temperature: ((t_fine * 5 + 128) >> 8) as f32 / 100.0,
+            humidity: self.humidity(adc_h, t_fine) as f32 / 1024.0,
+            pressure: self.pressure(adc_p, t_fine) as f32 / 256.0 / 100.0,
+        }
+    }
+
+    fn t_fine(&self, adc_t: i32) -> i32 {
+        let t1 = self.t1 as i32;
+        let var1 = (((adc_t >> 3) - (t1 << 1)) * self.t2 as i32) >> 11;
+        let var2 = (((((adc_t >> 4) - t1) * ((adc_t >> 4) - t1)) >> 12) * self.t3 as i32) >> 14;
+
+        var1 + var2
+    }
+
+    // pressure [Pa] in Q24.8 format
+    fn pressure(&self, adc_p: i32, t_fine: i32) -> u32 {
+        let mut var1 = t_fine as i64 - 128000;
+        let mut var2 = var1 * var1 * self.p6 as i64;
+        var2 += (var1 * self.p5 as i64) << 17;
+        var2 += (self.p4 as i64) << 35;
+        var1 = ((var1 * var1 * self.p3 as i64) >> 8) + ((var1 * self.p2 as i64) << 12);
+        var1 = (((1_i64 << 47) + var1) * self.p1 as i64) >> 33;
+        if var1 == 0 {
+            // avoid a division by zero
+            return 0;
+        }
+
+        let mut p = 1_048_576 - adc_p as i64;
+        p = (((p << 31) - var2) * 3125) / var1;
+        let var1 = (self.p9 as i64 * (p >> 13) * (p >> 13)) >> 25;
+        let var2 = (self.p8 as i64 * p) >> 19;
+
+        (((p + var1 + var2) >> 8) + ((self.p7 as i64) << 4)) as u32
+    }
+
+    // relative humidity [%] in Q22.10 format
+    fn humidity(&self, adc_h: i32, t_fine: i32) -> u32 {
+        let mut v = t_fine - 76800;
+        v = ((((adc_h << 14) - ((self.h4 as i32) << 20) - (self.h5 as i32 * v)) + 16384) >> 15)
+            * (((((((v * self.h6 as i32) >> 10) * (((v * self.h3 as i32) >> 11) + 32768)) >> 10)
+                + 2_097_152)
+                * self.h2 as i32
+                + 8192)
+                >> 14);
+        v -= ((((v >> 15) * (v >> 15)) >> 7) * self.h1 as i32) >> 4;
+
+        (v.clamp(0, 419_430_400) >> 12) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Example trimming values of the BMP280 datasheet, which shares the
+    // temperature and pressure compensation with the BME280
+    fn datasheet_calibration() -> Bme280Calibration {
+        let mut tp = [0_u8; 26];
+        let words: [u16; 12] = [
+            27504,
+            26435,
+            -1000_i16 as u16,
+            36477,
+            -10685_i16 as u16,
+            3024,
+            2855,
+            140,
+            -7_i16 as u16,
+            15500,
+            -14600_i16 as u16,
+            6000,
+        ];
+        for (idx, word) in words.iter().enumerate() {
+            tp[idx * 2..idx * 2 + 2].copy_from_slice(&word.to_le_bytes());
+        }
+        tp[25] = 75;
+
+        // typical humidity trimming: H2 = 362, H3 = 0, H4 = 313, H5 = 50, H6 = 30
+        let h = [0x6a, 0x01, 0x00, 0x13, 0x29, 0x03, 0x1e];
+
+        Bme280Calibration::from_registers(&tp, &h)
+    }
+
+    fn raw_data(adc_p: u32, adc_t: u32, adc_h: u16) -> [u8; 8] {
+        let p = (adc_p << 4).to_be_bytes();
+        let t = (adc_t << 4).to_be_bytes();
+        let h = adc_h.to_be_bytes();
+        [p[1], p[2], p[3], t[1], t[2], t[3], h[0], h[1]]
+    }
+
+    #[test]
+    fn bme280_compensation_test() {
+        let calibration = datasheet_calibration();
+        assert_eq!(calibration.h4, 313);
+        assert_eq!(calibration.h5, 50);
+
+        // datasheet example: 25.08 °C, 100653 Pa
+        let reading = calibration.compensate(&raw_data(415148, 519888, 30000));
+        assert_eq!(reading.temperature, 25.08);
+        assert!((reading.pressure - 1006.53).abs() < 0.01, "{reading:?}");
+
+        // the humidity increases with the raw value and stays within 0..100 %
+        let mut last = 0.0;
+        for adc_h in (20000..40000).step_by(1000) {
+            let humidity = calibration
+                .compensate(&raw_data(415148, 519888, adc_h))
+                .humidity;
+            assert!(humidity >= last && humidity <= 100.0, "{humidity}");
+            last = humidity;
+        }
+        assert!(last > 0.0);
+    }
+
+    #[test]
+    fn environment_history_test() {
+        let mut history = EnvironmentHistory::new(120);
+        assert_eq!(history.report(20.0), None);
+
+        // only the readings of the 120 s reporting window are averaged
+        for temperature in [100.0, -5.0, -15.0] {
+            for _ in 0..12 {
+                history.store(EnvironmentReading {
+                    temperature,
+                    humidity: 80.0,
+                    pressure: 1000.0,
+                });
+            }
+        }
+
+        let environment = history.report(20.0).unwrap();
+        assert_eq!(environment.temperature, -10.0);
+        assert_eq!(environment.pressure, 1000.0);
+        assert!(environment.wind_chill.unwrap() < -10.0);
+    }
+
+    #[test]
+    fn derived_values_test() {
+        // Environment Canada wind chill table: -10 °C at 30 km/h gives -19.5
+        let chill = wind_chill(-10.0, 30.0).unwrap();
+        assert!((chill + 19.5).abs() < 0.05, "{chill}");
+        assert_eq!(wind_chill(15.0, 30.0), None);
+        assert_eq!(wind_chill(-10.0, 3.0), None);
+
+        // ISA sea level density for dry air
+        let density = air_density(1013.25, 15.0, 0.0);
+        assert!((density - 1.225).abs() < 0.001, "{density}");
+        // humid air is lighter than dry air
+        assert!(air_density(1013.25, 15.0, 100.0) < density);
+    }
+}
@@ -0,0 +1,268 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::units::SpeedUnit;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use serde::Serialize;
+
+// Default topic prefix Home Assistant subscribes to for discovery
+pub const HOME_ASSISTANT_DISCOVERY_PREFIX: &str = "homeassistant";
+
+const HOME_ASSISTANT_MODEL: &str = "ESP32 Anemometer";
+
+// State of all entities, published to <topic_prefix>/<device_id>/state
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HomeAssistantState<'a> {
+    pub speed: f32,
+    pub gust: f32,
+    // [degree]
+    pub direction: f32,
+    pub direction_text: &'a str,
+    // signal strength of the wifi access point [dBm]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rssi: Option<i8>,
+    pub firmware: &'a str,
+}
+
+// Retained discovery config of one entity
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiscoveryMessage {
+    pub topic: String,
+    pub payload: String,
+}
+
+// The keys are defined by the Home Assistant MQTT sensor integration
+#[derive(Serialize)]
+struct SensorConfig<'a> {
+    name: &'a str,
+    unique_id: String,
+    object_id: String,
+    state_topic: &'a str,
+    value_template: &'a str,
+    // the retained presence of the device, unavailable after the last will
+    availability_topic: &'a str,
+    availability_template: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit_of_measurement: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state_class: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entity_category: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: Option<&'a str>,
+    device: &'a DeviceConfig<'a>,
+}
+
+#[derive(Serialize)]
+struct DeviceConfig<'a> {
+    identifiers: [&'a str; 1],
+    name: String,
+    model: &'a str,
+    sw_version: &'a str,
+}
+
+struct Entity {
+    key: &'static str,
+    name: &'static str,
+    value_template: &'static str,
+    unit: Option<&'static str>,
+    device_class: Option<&'static str>,
+    state_class: Option<&'static str>,
+    entity_category: Option<&'static str>,
+    icon: Option<&'static str>,
+}
+
+// Discovery configs of the wind speed, gust, direction, RSSI and firmware
+// version sensors. All of them read the state topic, the device is
+// identified by its device id.
+pub fn discovery_messages(
+    discovery_prefix: &str,
+    device_id: &str,
+    state_topic: &str,
+    availability_topic: &str,
+    speed_unit: SpeedUnit,
+    firmware_version: &str,
+) -> Vec<DiscoveryMessage> {
+    // Home Assistant doesn't know the Beaufort symbol for wind speeds
+    let speed_class = match speed_unit {
+        SpeedUnit::Beaufort => None,
+        _ => Some("wind_speed"),
+    };
+    let entities = [
+        Entity {
+            key: "wind_speed",
+            name: "Wind speed",
+            value_template: "{{ value_json.speed }}",
+            unit: Some(speed_unit.symbol()),
+            device_class: speed_class,
+            state_class: Some("measurement"),
+            entity_category: None,
+            icon: None,
+        },
+        Entity {
+            key: "wind_gust",
+            name: "Wind gust",
+            value_template: "{{ value_json.gust }}",
+            unit: Some(speed_unit.symbol()),
+            device_class: speed_class,
+            state_class: Some("measurement"),
+            entity_category: None,
+            icon: Some("mdi:weather-windy"),
+        },
+        Entity {
+            key: "wind_direction",
+            name: "Wind direction",
+            value_template: "{{ value_json.direction }}",
+            unit: Some("°"),
+            device_class: None,
+            state_class: Some("measurement"),
+            entity_category: None,
+            icon: Some("mdi:compass-outline"),
+        },
+        Entity {
+            key: "rssi",
+            name: "RSSI",
+            value_template: "{{ value_json.rssi }}",
+            unit: Some("dBm"),
+            device_class: Some("signal_strength"),
+            state_class: Some("measurement"),
+            entity_category: Some("diagnostic"),
+            icon: None,
+        },
+        Entity {
+            key: "firmware",
+            name: "Firmware version",
+            value_template: "{{ value_json.firmware }}",
+            unit: None,
+            device_class: None,
+            state_class: None,
+            entity_category: Some("diagnostic"),
+            icon: Some("mdi:chip"),
+        },
+    ];
+
+    let device = DeviceConfig {
+        identifiers: [device_id],
+        name: format!("Anemometer {device_id}"),
+        model: HOME_ASSISTANT_MODEL,
+        sw_version: firmware_version,
+    };
+
+    entities
+        .iter()
+        .map(|entity| {
+            let config = SensorConfig {
+                name: entity.name,
+                unique_id: format!("{device_id}_{}", entity.key),
+                object_id: format!("{device_id}_{}", entity.key),
+                state_topic,
+                value_template: entity.value_template,
+                availability_topic,
+                availability_template: "{{ value_json.state }}",
+                unit_of_measurement: entity.unit,
+                device_class: entity.device_class,
+                state_class: entity.state_class,
+                entity_category: entity.entity_category,
+                icon: entity.icon,
+                device: &device,
+            };
+            DiscoveryMessage {
+                topic: format!(
+                    "{discovery_prefix}/sensor/{device_id}/{}/config",
+                    entity.key
+                ),
+                payload: serde_json::to_string(&config).unwrap(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn discovery_test() {
+        let messages = discovery_messages(
+            HOME_ASSISTANT_DISCOVERY_PREFIX,
+            "anemometer-1",
+            "weather/anemometer-1/state",
+            "weather/anemometer-1/status",
+            SpeedUnit::Knots,
+            "0.2.0",
+        );
+        assert_eq!(messages.len(), 5);
+        assert_eq!(
+            messages[0].topic,
+            "homeassistant/sensor/anemometer-1/wind_speed/config"
+        );
+
+        let config: Value = serde_json::from_str(&messages[0].payload).unwrap();
+        assert_eq!(config["unique_id"], "anemometer-1_wind_speed");
+        assert_eq!(config["state_topic"], "weather/anemometer-1/state");
+        assert_eq!(config["availability_topic"], "weather/anemometer-1/status");
+        assert_eq!(config["unit_of_measurement"], "kn");
+        assert_eq!(config["device_class"], "wind_speed");
+        assert_eq!(config["device"]["identifiers"][0], "anemometer-1");
+        assert_eq!(config["device"]["sw_version"], "0.2.0");
+
+        let config: Value = serde_json::from_str(&messages[4].payload).unwrap();
+        assert_eq!(config["entity_category"], "diagnostic");
+        assert!(config.get("unit_of_measurement").is_none());
+
+        let messages = discovery_messages("ha", "a", "s", "p", SpeedUnit::Beaufort, "0.2.0");
+        let config: Value = serde_json::from_str(&messages[1].payload).unwrap();
+        assert_eq!(config["unit_of_measurement"], "Bft");
+        assert!(config.get("device_class").is_none());
+    }
+
+    #[test]
+    fn state_test() {
+        let state = HomeAssistantState {
+            speed: 12.5,
+            gust: 20.0,
+            direction: 270.0,
+            direction_text: "W",
+            rssi: Some(-67),
+            firmware: "0.2.0",
+        };
+        assert_eq!(
+            serde_json::to_string(&state).unwrap(),
+            r#"{"speed":12.5,"gust":20.0,"direction":270.0,"directionText":"W","rssi":-67,"firmware":"0.2.0"}"#
+        );
+    }
+}
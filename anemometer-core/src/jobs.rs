@@ -0,0 +1,309 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+// Operation of the job document which triggers a firmware update
+pub const JOB_OPERATION_OTA_UPDATE: &str = "ota_update";
+
+// Status of a job execution of the AWS IoT Jobs service
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum JobStatus {
+    Queued,
+    InProgress,
+    Succeeded,
+    Failed,
+    TimedOut,
+    Rejected,
+    Removed,
+    Canceled,
+}
+
+// Job document as created with the AWS IoT Jobs service:
+//
+// { "operation": "ota_update", "firmware": "fw-0.1.34.bin", "version": "0.1.34" }
+//
+// firmware is the file name in the firmware bucket. The version is
+// optional, if given the update only succeeds if the device runs this
+// version after the restart.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobDocument {
+    pub operation: String,
+    pub firmware: Option<String>,
+    pub version: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobExecution {
+    pub job_id: String,
+    pub status: JobStatus,
+    pub job_document: Option<JobDocument>,
+}
+
+// Payload of .../jobs/notify-next and .../jobs/$next/get/accepted, the
+// execution is missing if no job is pending
+#[derive(Debug, Deserialize)]
+pub struct NextJobExecution {
+    pub execution: Option<JobExecution>,
+}
+
+// Payload published to .../jobs/<job id>/update
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobStatusUpdate {
+    pub status: JobStatus,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub status_details: BTreeMap<String, String>,
+}
+
+// Outcome of a firmware update, persisted so that the job can be
+// completed after the restart into the new firmware
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobOutcome {
+    // update started, the result is known after the restart
+    Pending,
+    // update failed with the given reason
+    Failed(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobRecord {
+    pub job_id: String,
+    // firmware version running when the update was started
+    pub previous_version: String,
+    // expected firmware version from the job document
+    pub target_version: Option<String>,
+    pub outcome: JobOutcome,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JobAction {
+    // report IN_PROGRESS, persist the record and download the firmware
+    StartUpdate { firmware: String, record: JobRecord },
+    // report the final status of the job, the record can be removed
+    Complete(JobStatusUpdate),
+    Ignore,
+}
+
+impl NextJobExecution {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        match serde_json::from_slice(data) {
+            Ok(next) => Some(next),
+            Err(err) => {
+                warn!("Invalid job execution: {}", err);
+                None
+            }
+        }
+    }
+}
+
+impl JobStatusUpdate {
+    pub fn new(status: JobStatus) -> Self {
+        JobStatusUpdate {
+            status,
+            status_details: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_detail(mut self, key: &str, value: &str) -> Self {
+        self.status_details.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn failed(reason: &str) -> Self {
+        Self::new(JobStatus::Failed).with_detail("reason", reason)
+    }
+
+    pub fn format_msg(&self, msg: &mut String) {
+        *msg = serde_json::to_string(self).unwrap();
+    }
+}
+
+// Decides what to do with the next pending job execution. The record of
+// the update started before the last restart (if any) takes precedence,
+// because the job is still reported as IN_PROGRESS (or even QUEUED if the
+// IN_PROGRESS update got lost) until the result is reported.
+pub fn next_action(
+    execution: &JobExecution,
+    record: Option<&JobRecord>,
+    running_version: &str,
+) -> JobAction {
+    if let Some(record) = record.filter(|record| record.job_id == execution.job_id) {
+        return JobAction::Complete(match &record.outcome {
+            JobOutcome::Failed(reason) => JobStatusUpdate::failed(reason),
+            JobOutcome::Pending => {
+                let updated = match &record.target_version {
+                    Some(version) => version == running_version,
+                    None => record.previous_version != running_version,
+                };
+                if updated {
+                    JobStatusUpdate::new(JobStatus::Succeeded)
+                        .with_detail("version", running_version)
+                } else {
+                    // the bootloader rolled back to the previous firmware
+                    JobStatusUpdate::failed("RolledBack").with_detail("version", running_version)
+                }
+            }
+        });
+    }
+
+    match execution.status {
+        JobStatus::Queued => {}
+        // the device restarted during the update before the record was
+        // written, the job can't be resumed
+        JobStatus::InProgress => {
+            return JobAction::Complete(JobStatusUpdate::failed("Interrupted"))
+        }
+        _ => return JobAction::Ignore,
+    }
+
+    let document = execution.job_document.clone().unwrap_or_default();
+    match document.firmware {
+        Some(firmware)
+            if document.operation == JOB_OPERATION_OTA_UPDATE && !firmware.is_empty() =>
+        {
+            JobAction::StartUpdate {
+                firmware,
+                record: JobRecord {
+                    job_id: execution.job_id.clone(),
+                    previous_version: running_version.to_string(),
+                    target_version: document.version,
+                    outcome: JobOutcome::Pending,
+                },
+            }
+        }
+        _ => {
+            warn!("Unsupported job document {:?}", execution.job_document);
+            JobAction::Complete(JobStatusUpdate::failed("InvalidJobDocument"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTIFY_NEXT: &[u8] = br#"{"timestamp":1680000000,"execution":{"jobId":"ota-42",
+        "status":"QUEUED","queuedAt":1680000000,"versionNumber":1,"executionNumber":1,
+        "jobDocument":{"operation":"ota_update","firmware":"fw-0.1.34.bin","version":"0.1.34"}}}"#;
+
+    fn record(outcome: JobOutcome) -> JobRecord {
+        JobRecord {
+            job_id: String::from("ota-42"),
+            previous_version: String::from("0.1.33"),
+            target_version: Some(String::from("0.1.34")),
+            outcome,
+        }
+    }
+
+    #[test]
+    fn start_update_test() {
+        let execution = NextJobExecution::parse(NOTIFY_NEXT)
+            .unwrap()
+            .execution
+            .unwrap();
+
+        assert_eq!(
+            next_action(&execution, None, "0.1.33"),
+            JobAction::StartUpdate {
+                firmware: String::from("fw-0.1.34.bin"),
+                record: record(JobOutcome::Pending)
+            }
+        );
+        assert!(NextJobExecution::parse(br#"{"timestamp":1680000000}"#)
+            .unwrap()
+            .execution
+            .is_none());
+
+        let mut execution = execution;
+        execution.job_document = Some(JobDocument {
+            operation: String::from("reboot"),
+            ..Default::default()
+        });
+        let JobAction::Complete(update) = next_action(&execution, None, "0.1.33") else {
+            panic!("invalid job document not rejected");
+        };
+        assert_eq!(update.status, JobStatus::Failed);
+
+        let mut msg = String::new();
+        update.format_msg(&mut msg);
+        assert_eq!(
+            msg,
+            r#"{"status":"FAILED","statusDetails":{"reason":"InvalidJobDocument"}}"#
+        );
+    }
+
+    #[test]
+    fn resume_after_restart_test() {
+        let mut execution = NextJobExecution::parse(NOTIFY_NEXT)
+            .unwrap()
+            .execution
+            .unwrap();
+        execution.status = JobStatus::InProgress;
+
+        let pending = record(JobOutcome::Pending);
+        assert_eq!(
+            next_action(&execution, Some(&pending), "0.1.34"),
+            JobAction::Complete(
+                JobStatusUpdate::new(JobStatus::Succeeded).with_detail("version", "0.1.34")
+            )
+        );
+        assert_eq!(
+            next_action(&execution, Some(&pending), "0.1.33"),
+            JobAction::Complete(
+                JobStatusUpdate::failed("RolledBack").with_detail("version", "0.1.33")
+            )
+        );
+
+        let failed = record(JobOutcome::Failed(String::from("FwImageNotFound")));
+        assert_eq!(
+            next_action(&execution, Some(&failed), "0.1.33"),
+            JobAction::Complete(JobStatusUpdate::failed("FwImageNotFound"))
+        );
+
+        // record of another job
+        execution.job_id = String::from("ota-43");
+        assert_eq!(
+            next_action(&execution, Some(&pending), "0.1.34"),
+            JobAction::Complete(JobStatusUpdate::failed("Interrupted"))
+        );
+        execution.status = JobStatus::Canceled;
+        assert_eq!(
+            next_action(&execution, Some(&pending), "0.1.34"),
+            JobAction::Ignore
+        );
+    }
+}
@@ -0,0 +1,267 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+// Limits of the live stream [min], the stream ends automatically to keep
+// the power consumption and the traffic bounded
+pub const LIVE_STREAM_DEFAULT_DURATION: u16 = 5;
+pub const LIVE_STREAM_MAX_DURATION: u16 = 30;
+pub const LIVE_STREAM_MAX_DECIMATION: u16 = 240;
+// samples waiting to be published, older samples are dropped if the
+// connection can't keep up
+pub const LIVE_STREAM_BUFFER_SIZE: usize = 16;
+
+// Argument of the start_stream command: "[minutes[,decimation]]", with a
+// decimation of n every n-th sample is published
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LiveStreamRequest {
+    pub minutes: u16,
+    pub decimation: u16,
+}
+
+impl LiveStreamRequest {
+    pub fn parse(arg: &str) -> Option<Self> {
+        let mut values = arg.split(',').map(str::trim);
+        let minutes = match values.next() {
+            Some("") | None => LIVE_STREAM_DEFAULT_DURATION,
+            Some(minutes) => minutes.parse().ok()?,
+        };
+        let decimation = match values.next() {
+            Some(decimation) => decimation.parse().ok()?,
+            None => 1,
+        };
+
+        if values.next().is_some()
+            || !(1..=LIVE_STREAM_MAX_DURATION).contains(&minutes)
+            || !(1..=LIVE_STREAM_MAX_DECIMATION).contains(&decimation)
+        {
+            return None;
+        }
+
+        Some(LiveStreamRequest {
+            minutes,
+            decimation,
+        })
+    }
+}
+
+// Raw sample published to <topic_prefix>/<device_id>/wind/live
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+pub struct LiveSample {
+    // unix time [ms]
+    pub time: u64,
+    // rotation rate [rps] and calibrated speed [km/h]
+    pub rps: f32,
+    pub speed: f32,
+    // [deg]
+    pub direction: u16,
+}
+
+impl LiveSample {
+    pub fn format_msg(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+// Selects the samples of a running live stream. The duration is counted in
+// samples, so the stream also ends if the system time isn't valid.
+pub struct LiveStream {
+    remaining: u64,
+    decimation: u16,
+    skipped: u16,
+    samples: VecDeque<LiveSample>,
+}
+
+impl LiveStream {
+    pub fn new() -> Self {
+        LiveStream {
+            remaining: 0,
+            decimation: 1,
+            skipped: 0,
+            samples: VecDeque::new(),
+        }
+    }
+
+    // sample_interval is the interval of the measurements [ms], a running
+    // stream is restarted with the new settings
+    pub fn start(&mut self, request: LiveStreamRequest, sample_interval: u64) {
+        self.remaining = request.minutes as u64 * 60_000 / sample_interval.max(1);
+        self.decimation = request.decimation.max(1);
+        // the first sample after the start is published
+        self.skipped = self.decimation - 1;
+        self.samples.clear();
+    }
+
+    pub fn stop(&mut self) {
+        self.remaining = 0;
+        self.samples.clear();
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.remaining > 0
+    }
+
+    // remaining duration of the stream [s]
+    pub fn remaining(&self, sample_interval: u64) -> u64 {
+        self.remaining * sample_interval / 1000
+    }
+
+    // Called for every measurement, returns true if the sample has been
+    // selected for publishing
+    pub fn record(&mut self, sample: LiveSample) -> bool {
+        if self.remaining == 0 {
+            return false;
+        }
+        self.remaining -= 1;
+
+        self.skipped += 1;
+        if self.skipped < self.decimation {
+            return false;
+        }
+        self.skipped = 0;
+
+        if self.samples.len() == LIVE_STREAM_BUFFER_SIZE {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+        true
+    }
+
+    pub fn take_samples(&mut self) -> Vec<LiveSample> {
+        self.samples.drain(..).collect()
+    }
+}
+
+impl Default for LiveStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(time: u64) -> LiveSample {
+        LiveSample {
+            time,
+            rps: 1.5,
+            speed: 10.25,
+            direction: 270,
+        }
+    }
+
+    #[test]
+    fn request_test() {
+        assert_eq!(
+            LiveStreamRequest::parse("10"),
+            Some(LiveStreamRequest {
+                minutes: 10,
+                decimation: 1
+            })
+        );
+        assert_eq!(
+            LiveStreamRequest::parse("2, 4"),
+            Some(LiveStreamRequest {
+                minutes: 2,
+                decimation: 4
+            })
+        );
+        assert_eq!(
+            LiveStreamRequest::parse(""),
+            Some(LiveStreamRequest {
+                minutes: LIVE_STREAM_DEFAULT_DURATION,
+                decimation: 1
+            })
+        );
+        assert_eq!(LiveStreamRequest::parse("0"), None);
+        assert_eq!(LiveStreamRequest::parse("31"), None);
+        assert_eq!(LiveStreamRequest::parse("5,0"), None);
+        assert_eq!(LiveStreamRequest::parse("5,2,1"), None);
+        assert_eq!(LiveStreamRequest::parse("five"), None);
+    }
+
+    #[test]
+    fn live_stream_test() {
+        let mut stream = LiveStream::new();
+        assert!(!stream.is_active());
+        assert!(!stream.record(sample(0)));
+
+        // 1 min with a sample every 20 s, every 2nd sample is published
+        stream.start(
+            LiveStreamRequest {
+                minutes: 1,
+                decimation: 2,
+            },
+            20_000,
+        );
+        assert!(stream.is_active());
+        assert_eq!(stream.remaining(20_000), 60);
+        assert!(stream.record(sample(0)));
+        assert!(!stream.record(sample(20_000)));
+        assert!(stream.record(sample(40_000)));
+        assert!(!stream.is_active());
+        assert!(!stream.record(sample(60_000)));
+        assert_eq!(stream.take_samples(), vec![sample(0), sample(40_000)]);
+        assert!(stream.take_samples().is_empty());
+
+        assert_eq!(
+            sample(1_700_000_000_250).format_msg(),
+            r#"{"time":1700000000250,"rps":1.5,"speed":10.25,"direction":270}"#
+        );
+    }
+
+    #[test]
+    fn live_stream_buffer_test() {
+        let mut stream = LiveStream::new();
+        stream.start(
+            LiveStreamRequest {
+                minutes: 1,
+                decimation: 1,
+            },
+            250,
+        );
+        for time in 0..LIVE_STREAM_BUFFER_SIZE as u64 + 2 {
+            assert!(stream.record(sample(time)));
+        }
+        let samples = stream.take_samples();
+        assert_eq!(samples.len(), LIVE_STREAM_BUFFER_SIZE);
+        assert_eq!(samples[0], sample(2));
+
+        stream.stop();
+        assert!(!stream.is_active());
+        assert!(!stream.record(sample(100)));
+    }
+}
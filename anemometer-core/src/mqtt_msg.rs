@@ -0,0 +1,722 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::auth::CommandAuth;
+use crate::command::{CommandError, CommandRequest, COMMAND_OTA_UPDATE, COMMAND_SYSTEM_RESTART};
+use crate::jobs::{JobExecution, NextJobExecution};
+use crate::shadow::{ShadowConfig, ShadowDelta};
+use alloc::vec::Vec;
+use core::str;
+use heapless::String;
+use log::*;
+use serde::{Deserialize, Serialize};
+
+pub type OtaUrl = String<128>;
+
+#[allow(dead_code)]
+pub const MQTT_TOPIC_POSTFIX_COMMAND: &str = "/command/#";
+pub const MQTT_TOPIC_POSTFIX_COMMAND_OTA_UPDATE: &str = "/command/ota_update";
+pub const MQTT_TOPIC_POSTFIX_COMMAND_SYSTEM_RESTART: &str = "/command/system_restart";
+pub const MQTT_TOPIC_POSTFIX_COMMAND_REQUEST: &str = "/command/request";
+pub const MQTT_TOPIC_POSTFIX_COMMAND_RESPONSE: &str = "/command/response";
+pub const MQTT_TOPIC_POSTFIX_WIND_SPEED: &str = "/wind/speed";
+pub const MQTT_TOPIC_POSTFIX_WIND_DIRECTION: &str = "/wind/direction";
+pub const MQTT_TOPIC_POSTFIX_WIND_ROSE: &str = "/wind/rose";
+pub const MQTT_TOPIC_POSTFIX_WIND_LIVE: &str = "/wind/live";
+pub const MQTT_TOPIC_POSTFIX_SUMMARY_HOURLY: &str = "/summary/hourly";
+pub const MQTT_TOPIC_POSTFIX_SUMMARY_DAILY: &str = "/summary/daily";
+pub const MQTT_TOPIC_POSTFIX_OTA_STATUS: &str = "/ota/status";
+pub const MQTT_TOPIC_POSTFIX_STATE: &str = "/state";
+pub const MQTT_TOPIC_POSTFIX_PRESENCE: &str = "/status";
+// AWS IoT Jobs, below <things_prefix>/<device_id>
+pub const MQTT_TOPIC_POSTFIX_JOBS_NOTIFY_NEXT: &str = "/jobs/notify-next";
+pub const MQTT_TOPIC_POSTFIX_JOBS_GET_NEXT: &str = "/jobs/$next/get";
+pub const MQTT_TOPIC_POSTFIX_JOBS_GET_NEXT_ACCEPTED: &str = "/jobs/$next/get/accepted";
+// the status of a job is updated on .../jobs/<job id>/update
+pub const MQTT_TOPIC_POSTFIX_JOBS: &str = "/jobs/";
+pub const MQTT_TOPIC_POSTFIX_JOB_UPDATE: &str = "/update";
+
+// Larger payloads are rejected, the chunks of a message are assembled in
+// RAM
+pub const DEFAULT_MAX_PAYLOAD_SIZE: usize = 4096;
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CmdMqttMsg {
+    pub cmd: alloc::string::String,
+    #[serde(default)]
+    pub arg: alloc::string::String,
+    // returned unchanged in the response to match it with the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<alloc::string::String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_topic: Option<alloc::string::String>,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum MqttCommand {
+    ExecOTAUpdate(OtaUrl),
+    SystemRestart,
+    // desired state of the device shadow which differs from the reported state
+    ShadowDelta(ShadowConfig),
+    // next pending execution of the AWS IoT Jobs service
+    JobExecution(JobExecution),
+    // command of the request/response protocol
+    Request(CommandRequest),
+}
+
+// Transport independent description of a received MQTT payload. Large
+// messages are delivered by the MQTT client in several chunks which need
+// to be assembled before the command can be parsed.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Chunk {
+    Complete,
+    Initial {
+        total_data_size: usize,
+    },
+    Subsequent {
+        current_data_offset: usize,
+        total_data_size: usize,
+    },
+}
+
+pub struct MessageParser {
+    #[allow(clippy::type_complexity)]
+    command_parser: Option<fn(&[u8]) -> Option<MqttCommand>>,
+    // assembles the chunks of a message
+    payload_buf: Vec<u8>,
+    max_payload_size: usize,
+    // the topic is configured in the conf partition, therefore it can't
+    // be matched by a fixed postfix like the commands
+    shadow_delta_topic: Option<alloc::string::String>,
+    // $aws/things/<device_id>, the jobs topics are only accepted if set.
+    // Shadow and jobs are AWS IoT services, their topics are protected by
+    // the policy of the broker and the messages can't be signed.
+    jobs_topic_prefix: Option<alloc::string::String>,
+    // commands are only accepted with a valid signature if set
+    auth: Option<CommandAuth>,
+    // topic of the chunked message, which is only given with the first chunk
+    chunk_topic: Option<alloc::string::String>,
+}
+
+impl MessageParser {
+    pub fn new() -> Self {
+        MessageParser {
+            command_parser: None,
+            payload_buf: Vec::new(),
+            max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
+            shadow_delta_topic: None,
+            jobs_topic_prefix: None,
+            auth: None,
+            chunk_topic: None,
+        }
+    }
+
+    pub fn with_shadow_delta_topic(mut self, topic: &str) -> Self {
+        self.shadow_delta_topic = Some(topic.into());
+        self
+    }
+
+    pub fn with_jobs_topic_prefix(mut self, prefix: &str) -> Self {
+        self.jobs_topic_prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn with_max_payload_size(mut self, size: usize) -> Self {
+        self.max_payload_size = size;
+        self
+    }
+
+    pub fn with_authentication(mut self, auth: CommandAuth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    pub fn process(
+        &mut self,
+        topic: Option<&str>,
+        data: &[u8],
+        chunk: Chunk,
+    ) -> Option<MqttCommand> {
+        info!("Message = {:?} {} bytes {:?}", topic, data.len(), chunk);
+
+        match chunk {
+            Chunk::Complete => {
+                let topic = topic?;
+                let command_parser = self.parse_command(topic)?;
+                if data.len() > self.max_payload_size {
+                    return self.payload_too_large(topic, data.len());
+                }
+                Self::dispatch(self.auth.as_mut(), command_parser, topic, data)
+            }
+            Chunk::Initial { total_data_size } => {
+                self.command_parser = None;
+                self.payload_buf.clear();
+
+                let topic = topic?;
+                let command_parser = self.parse_command(topic)?;
+                if total_data_size > self.max_payload_size {
+                    return self.payload_too_large(topic, total_data_size);
+                }
+                if data.len() > total_data_size {
+                    warn!("Dropped inconsistent initial chunk on {}", topic);
+                    return None;
+                }
+
+                self.payload_buf.reserve_exact(total_data_size);
+                self.payload_buf.extend_from_slice(data);
+                self.command_parser = Some(command_parser);
+                self.chunk_topic = Some(topic.into());
+
+                self.complete_chunked(total_data_size)
+            }
+            Chunk::Subsequent {
+                current_data_offset,
+                total_data_size,
+            } => {
+                // chunks of an unknown topic or a dropped message
+                self.command_parser?;
+
+                // the chunks are delivered in order, anything else means a
+                // chunk has been lost
+                if current_data_offset != self.payload_buf.len()
+                    || current_data_offset + data.len() > total_data_size
+                    || total_data_size > self.max_payload_size
+                {
+                    warn!(
+                        "Dropped inconsistent chunk at offset {} of {} bytes",
+                        current_data_offset, total_data_size
+                    );
+                    self.command_parser = None;
+                    self.payload_buf = Vec::new();
+                    return None;
+                }
+
+                self.payload_buf.extend_from_slice(data);
+
+                self.complete_chunked(total_data_size)
+            }
+        }
+    }
+
+    fn complete_chunked(&mut self, total_data_size: usize) -> Option<MqttCommand> {
+        if self.payload_buf.len() < total_data_size {
+            return None;
+        }
+
+        let command_parser = self.command_parser.take()?;
+        let topic = self.chunk_topic.take().unwrap_or_default();
+        let command = Self::dispatch(
+            self.auth.as_mut(),
+            command_parser,
+            &topic,
+            &self.payload_buf,
+        );
+        // the buffer of a large message is not kept
+        self.payload_buf = Vec::new();
+
+        command
+    }
+
+    // Commands are answered with an error, other payloads like a shadow
+    // delta are dropped
+    fn payload_too_large(&self, topic: &str, size: usize) -> Option<MqttCommand> {
+        error!(
+            "Payload of {} bytes on {} exceeds the limit of {} bytes",
+            size, topic, self.max_payload_size
+        );
+
+        let cmd = if topic.ends_with(MQTT_TOPIC_POSTFIX_COMMAND_REQUEST) {
+            ""
+        } else if topic.ends_with(MQTT_TOPIC_POSTFIX_COMMAND_OTA_UPDATE) {
+            COMMAND_OTA_UPDATE
+        } else if topic.ends_with(MQTT_TOPIC_POSTFIX_COMMAND_SYSTEM_RESTART) {
+            COMMAND_SYSTEM_RESTART
+        } else {
+            return None;
+        };
+
+        Some(MqttCommand::Request(CommandRequest::rejected(
+            cmd,
+            CommandError::PayloadTooLarge,
+        )))
+    }
+
+    // Commands need to be authenticated if a key is configured, the payload
+    // of the command is taken from the verified envelope
+    fn dispatch(
+        auth: Option<&mut CommandAuth>,
+        command_parser: fn(&[u8]) -> Option<MqttCommand>,
+        topic: &str,
+        data: &[u8],
+    ) -> Option<MqttCommand> {
+        match auth {
+            Some(auth) if Self::is_command_topic(topic) => match auth.verify(topic, data) {
+                Ok(payload) => command_parser(payload.as_bytes()),
+                Err(err) => {
+                    warn!("Rejected unauthenticated command on {}: {:?}", topic, err);
+                    None
+                }
+            },
+            _ => command_parser(data),
+        }
+    }
+
+    fn is_command_topic(topic: &str) -> bool {
+        topic.ends_with(MQTT_TOPIC_POSTFIX_COMMAND_REQUEST)
+            || topic.ends_with(MQTT_TOPIC_POSTFIX_COMMAND_OTA_UPDATE)
+            || topic.ends_with(MQTT_TOPIC_POSTFIX_COMMAND_SYSTEM_RESTART)
+    }
+
+    fn is_jobs_topic(&self, topic: &str) -> bool {
+        match self
+            .jobs_topic_prefix
+            .as_deref()
+            .and_then(|prefix| topic.strip_prefix(prefix))
+        {
+            Some(postfix) => {
+                postfix == MQTT_TOPIC_POSTFIX_JOBS_NOTIFY_NEXT
+                    || postfix == MQTT_TOPIC_POSTFIX_JOBS_GET_NEXT_ACCEPTED
+            }
+            None => false,
+        }
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn parse_command(&self, topic: &str) -> Option<fn(&[u8]) -> Option<MqttCommand>> {
+        info!("parse_command: {}", topic);
+        if self.shadow_delta_topic.as_deref() == Some(topic) {
+            Some(Self::parse_shadow_delta)
+        } else if self.is_jobs_topic(topic) {
+            Some(Self::parse_job_execution)
+        } else if topic.ends_with(MQTT_TOPIC_POSTFIX_COMMAND_REQUEST) {
+            Some(Self::parse_command_request)
+        } else if topic.ends_with(MQTT_TOPIC_POSTFIX_COMMAND_OTA_UPDATE) {
+            Some(Self::parse_ota_update_command)
+        } else if topic.ends_with(MQTT_TOPIC_POSTFIX_COMMAND_SYSTEM_RESTART) {
+            Some(Self::parse_system_restart_command)
+        } else {
+            None
+        }
+    }
+
+    fn parse_ota_update_command(data: &[u8]) -> Option<MqttCommand> {
+        info!("parse_ota_update_command: {:?}", data);
+        Self::parse::<OtaUrl>(data).map(MqttCommand::ExecOTAUpdate)
+    }
+
+    fn parse_system_restart_command(data: &[u8]) -> Option<MqttCommand> {
+        info!("parse_system_restart_command: {:?}", data);
+        Self::parse_empty(data).map(|_| MqttCommand::SystemRestart)
+    }
+
+    fn parse_shadow_delta(data: &[u8]) -> Option<MqttCommand> {
+        info!("parse_shadow_delta: {:?}", data);
+        ShadowDelta::parse(data).map(|delta| MqttCommand::ShadowDelta(delta.state))
+    }
+
+    fn parse_job_execution(data: &[u8]) -> Option<MqttCommand> {
+        info!("parse_job_execution: {:?}", data);
+        NextJobExecution::parse(data)
+            .and_then(|next| next.execution)
+            .map(MqttCommand::JobExecution)
+    }
+
+    fn parse_command_request(data: &[u8]) -> Option<MqttCommand> {
+        info!("parse_command_request: {:?}", data);
+        CommandRequest::parse(data).map(MqttCommand::Request)
+    }
+
+    fn parse<T>(data: &[u8]) -> Option<T>
+    where
+        T: str::FromStr,
+    {
+        str::from_utf8(data)
+            .ok()
+            .and_then(|s| str::parse::<T>(s).ok())
+    }
+
+    fn parse_empty(data: &[u8]) -> Option<()> {
+        if data.is_empty() {
+            Some(())
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for MessageParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{SignedCommand, DEFAULT_AUTH_WINDOW};
+    use crate::command::{Command, CommandError, CommandRequest, COMMAND_OTA_UPDATE};
+
+    const OTA_TOPIC: &str = "anemometer/device-1/command/ota_update";
+    const RESTART_TOPIC: &str = "anemometer/device-1/command/system_restart";
+    const DELTA_TOPIC: &str = "$aws/things/device-1/shadow/update/delta";
+    const JOBS_TOPIC_PREFIX: &str = "$aws/things/device-1";
+    const JOBS_TOPIC: &str = "$aws/things/device-1/jobs/$next/get/accepted";
+    const REQUEST_TOPIC: &str = "anemometer/device-1/command/request";
+
+    #[test]
+    fn complete_message_test() {
+        let mut parser = MessageParser::new();
+
+        assert_eq!(
+            parser.process(Some(RESTART_TOPIC), &[], Chunk::Complete),
+            Some(MqttCommand::SystemRestart)
+        );
+        assert_eq!(
+            parser.process(Some(OTA_TOPIC), b"fw-0.1.33.bin", Chunk::Complete),
+            Some(MqttCommand::ExecOTAUpdate(OtaUrl::from("fw-0.1.33.bin")))
+        );
+        // restart does not accept a payload
+        assert_eq!(
+            parser.process(Some(RESTART_TOPIC), b"now", Chunk::Complete),
+            None
+        );
+        assert_eq!(
+            parser.process(
+                Some("anemometer/device-1/command/unknown"),
+                &[],
+                Chunk::Complete
+            ),
+            None
+        );
+        assert_eq!(parser.process(None, &[], Chunk::Complete), None);
+    }
+
+    #[test]
+    fn chunked_message_test() {
+        let mut parser = MessageParser::new();
+
+        assert_eq!(
+            parser.process(
+                Some(OTA_TOPIC),
+                b"fw-0.1",
+                Chunk::Initial {
+                    total_data_size: 13
+                }
+            ),
+            None
+        );
+        assert_eq!(
+            parser.process(
+                None,
+                b".33",
+                Chunk::Subsequent {
+                    current_data_offset: 6,
+                    total_data_size: 13
+                }
+            ),
+            None
+        );
+        assert_eq!(
+            parser.process(
+                None,
+                b".bin",
+                Chunk::Subsequent {
+                    current_data_offset: 9,
+                    total_data_size: 13
+                }
+            ),
+            Some(MqttCommand::ExecOTAUpdate(OtaUrl::from("fw-0.1.33.bin")))
+        );
+    }
+
+    #[test]
+    fn oversized_message_test() {
+        let mut parser = MessageParser::new();
+
+        assert_eq!(
+            parser.process(
+                Some(OTA_TOPIC),
+                &[b'a'; 64],
+                Chunk::Initial {
+                    total_data_size: DEFAULT_MAX_PAYLOAD_SIZE + 1
+                }
+            ),
+            Some(MqttCommand::Request(CommandRequest::rejected(
+                COMMAND_OTA_UPDATE,
+                CommandError::PayloadTooLarge
+            )))
+        );
+        assert_eq!(
+            parser.process(
+                None,
+                &[b'a'; 65],
+                Chunk::Subsequent {
+                    current_data_offset: 64,
+                    total_data_size: DEFAULT_MAX_PAYLOAD_SIZE + 1
+                }
+            ),
+            None
+        );
+
+        // a shadow delta can't be answered
+        let mut parser = MessageParser::new()
+            .with_shadow_delta_topic(DELTA_TOPIC)
+            .with_max_payload_size(16);
+        assert_eq!(
+            parser.process(Some(DELTA_TOPIC), &[b' '; 17], Chunk::Complete),
+            None
+        );
+    }
+
+    #[test]
+    fn large_chunked_message_test() {
+        let table: alloc::vec::Vec<_> = (1..=120)
+            .map(|i| alloc::format!(r#"{{"rps":{i}.0,"kmh":{}.5}}"#, i * 3))
+            .collect();
+        let delta = alloc::format!(
+            r#"{{"state":{{"calibration":{{"id":"gps-run","table":[{}]}}}},"version":7}}"#,
+            table.join(",")
+        );
+        let data = delta.as_bytes();
+        assert!(data.len() > 2048 && data.len() <= DEFAULT_MAX_PAYLOAD_SIZE);
+
+        let mut parser = MessageParser::new().with_shadow_delta_topic(DELTA_TOPIC);
+        let mut command = None;
+        for (i, chunk) in data.chunks(1024).enumerate() {
+            let offset = i * 1024;
+            command = parser.process(
+                (offset == 0).then_some(DELTA_TOPIC),
+                chunk,
+                if offset == 0 {
+                    Chunk::Initial {
+                        total_data_size: data.len(),
+                    }
+                } else {
+                    Chunk::Subsequent {
+                        current_data_offset: offset,
+                        total_data_size: data.len(),
+                    }
+                },
+            );
+        }
+        let Some(MqttCommand::ShadowDelta(config)) = command else {
+            panic!("chunked shadow delta not parsed");
+        };
+        assert_eq!(config.calibration.unwrap().table.unwrap().len(), 120);
+
+        // a lost chunk drops the message
+        let mut parser = MessageParser::new();
+        assert_eq!(
+            parser.process(
+                Some(OTA_TOPIC),
+                b"fw-0.1",
+                Chunk::Initial {
+                    total_data_size: 13
+                }
+            ),
+            None
+        );
+        assert_eq!(
+            parser.process(
+                None,
+                b".bin",
+                Chunk::Subsequent {
+                    current_data_offset: 9,
+                    total_data_size: 13
+                }
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn shadow_delta_test() {
+        let delta = br#"{"version":3,"state":{"reportingInterval":30}}"#;
+        let mut parser = MessageParser::new();
+
+        // the delta topic needs to be configured
+        assert_eq!(
+            parser.process(Some(DELTA_TOPIC), delta, Chunk::Complete),
+            None
+        );
+
+        let mut parser = MessageParser::new().with_shadow_delta_topic(DELTA_TOPIC);
+        assert_eq!(
+            parser.process(Some(DELTA_TOPIC), delta, Chunk::Complete),
+            Some(MqttCommand::ShadowDelta(ShadowConfig {
+                reporting_interval: Some(30),
+                ..Default::default()
+            }))
+        );
+        assert_eq!(
+            parser.process(Some(DELTA_TOPIC), b"not json", Chunk::Complete),
+            None
+        );
+    }
+
+    #[test]
+    fn job_execution_test() {
+        let mut parser = MessageParser::new().with_jobs_topic_prefix(JOBS_TOPIC_PREFIX);
+
+        let Some(MqttCommand::JobExecution(execution)) = parser.process(
+            Some(JOBS_TOPIC),
+            br#"{"execution":{"jobId":"ota-42","status":"IN_PROGRESS"}}"#,
+            Chunk::Complete,
+        ) else {
+            panic!("job execution not parsed");
+        };
+        assert_eq!(execution.job_id, "ota-42");
+        assert_eq!(execution.job_document, None);
+
+        // no pending job
+        assert_eq!(
+            parser.process(Some(JOBS_TOPIC), br#"{"timestamp":1}"#, Chunk::Complete),
+            None
+        );
+        assert_eq!(
+            parser.process(
+                Some("$aws/things/device-2/jobs/notify-next"),
+                br#"{"execution":{"jobId":"ota-42","status":"QUEUED"}}"#,
+                Chunk::Complete
+            ),
+            None
+        );
+    }
+
+    // Without AWS IoT neither shadow nor jobs messages are accepted
+    #[test]
+    fn generic_broker_test() {
+        let mut parser = MessageParser::new();
+
+        assert_eq!(
+            parser.process(
+                Some(JOBS_TOPIC),
+                br#"{"execution":{"jobId":"ota-42","status":"QUEUED"}}"#,
+                Chunk::Complete
+            ),
+            None
+        );
+        assert_eq!(
+            parser.process(
+                Some(DELTA_TOPIC),
+                br#"{"state":{"interval":10}}"#,
+                Chunk::Complete
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn command_request_test() {
+        let mut parser = MessageParser::new();
+
+        let Some(MqttCommand::Request(request)) = parser.process(
+            Some(REQUEST_TOPIC),
+            br#"{"cmd":"report_now","correlationId":"abc"}"#,
+            Chunk::Complete,
+        ) else {
+            panic!("command request not parsed");
+        };
+        assert_eq!(request.command, Ok(Command::ReportNow));
+        assert_eq!(request.correlation_id.as_deref(), Some("abc"));
+
+        assert_eq!(
+            parser.process(Some(REQUEST_TOPIC), b"report_now", Chunk::Complete),
+            None
+        );
+    }
+
+    #[test]
+    fn authenticated_command_test() {
+        const KEY: &[u8] = b"secret";
+        let mut parser = MessageParser::new()
+            .with_jobs_topic_prefix(JOBS_TOPIC_PREFIX)
+            .with_authentication(CommandAuth::new(KEY, DEFAULT_AUTH_WINDOW, || 1_700_000_000));
+        let signed = |topic, payload, nonce| {
+            serde_json::to_vec(&SignedCommand::sign(
+                KEY,
+                topic,
+                payload,
+                1_700_000_000,
+                nonce,
+            ))
+            .unwrap()
+        };
+
+        assert_eq!(
+            parser.process(
+                Some(RESTART_TOPIC),
+                &signed(RESTART_TOPIC, "", "1"),
+                Chunk::Complete
+            ),
+            Some(MqttCommand::SystemRestart)
+        );
+        assert_eq!(
+            parser.process(
+                Some(OTA_TOPIC),
+                &signed(OTA_TOPIC, "fw-0.1.33.bin", "2"),
+                Chunk::Complete
+            ),
+            Some(MqttCommand::ExecOTAUpdate(OtaUrl::from("fw-0.1.33.bin")))
+        );
+        // unsigned, replayed or signed for another topic
+        assert_eq!(
+            parser.process(Some(RESTART_TOPIC), &[], Chunk::Complete),
+            None
+        );
+        assert_eq!(
+            parser.process(
+                Some(RESTART_TOPIC),
+                &signed(RESTART_TOPIC, "", "1"),
+                Chunk::Complete
+            ),
+            None
+        );
+        assert_eq!(
+            parser.process(
+                Some(RESTART_TOPIC),
+                &signed(OTA_TOPIC, "", "3"),
+                Chunk::Complete
+            ),
+            None
+        );
+        // the job and shadow topics are protected by the broker
+        assert!(matches!(
+            parser.process(
+                Some(JOBS_TOPIC),
+                br#"{"execution":{"jobId":"ota-42","status":"QUEUED"}}"#,
+                Chunk::Complete
+            ),
+            Some(MqttCommand::JobExecution(_))
+        ));
+    }
+}
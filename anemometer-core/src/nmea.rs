@@ -0,0 +1,100 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+// The MTK3339 sends frequent RMC sentences which are not valid.
+// The contain unclear data between field 6 and field 7. So far
+// it was not possible to find documentation of this behavior.
+// As a hack, this function removes the data between field 6 und 7
+// and creates a valid RMC sentence
+pub fn fix_rmc_sentence(s: String) -> String {
+    let v: Vec<_> = s.match_indices(',').map(|(i, _)| i).collect();
+    if s.contains("RMC") && v.len() > 12 {
+        let mut left = String::new();
+        let mut right = String::new();
+        let mut crc = 0;
+
+        let l = v[6];
+        if let Some(part) = s.get(0..l + 1) {
+            left.push_str(part);
+        }
+        let r = v[v.len() - 6];
+        if let Some(part) = s.get(r + 1..s.len()) {
+            right.push_str(part);
+        }
+        left.push_str(right.as_str());
+
+        for &item in &left.as_bytes()[1..left.len() - 3] {
+            crc ^= item;
+        }
+
+        left.replace_range(left.len() - 2..left.len(), &format!("{crc:02X}"));
+
+        left
+    } else {
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // NMEA checksum: XOR of all characters between '$' and '*'
+    fn checksum(sentence: &str) -> String {
+        let data = &sentence[1..sentence.find('*').unwrap()];
+        format!("{:02X}", data.bytes().fold(0, |crc, b| crc ^ b))
+    }
+
+    #[test]
+    fn fix_rmc_sentence_test() {
+        let broken = "$GNRMC,123519.000,A,4807.038,N,01131.000,E,0.02,1.1,0.0,N,1.0,2.0,0.02,31.66,230394,,,A*00";
+        let fixed = fix_rmc_sentence(String::from(broken));
+
+        assert_eq!(
+            &fixed[..fixed.len() - 2],
+            "$GNRMC,123519.000,A,4807.038,N,01131.000,E,0.02,31.66,230394,,,A*"
+        );
+        assert_eq!(&fixed[fixed.len() - 2..], checksum(&fixed));
+    }
+
+    #[test]
+    fn valid_sentences_are_unchanged_test() {
+        let rmc = "$GNRMC,123519.000,A,4807.038,N,01131.000,E,0.02,31.66,230394,,,A*7C";
+        let gga = "$GNGGA,123519.000,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47";
+
+        assert_eq!(fix_rmc_sentence(String::from(rmc)), rmc);
+        assert_eq!(fix_rmc_sentence(String::from(gga)), gga);
+    }
+}
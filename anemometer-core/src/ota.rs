@@ -0,0 +1,278 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use alloc::string::String;
+use serde::{Deserialize, Serialize};
+
+// Progress of the firmware download is reported in steps of [%]
+pub const OTA_PROGRESS_STEP: u8 = 10;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OtaState {
+    Started,
+    Downloading,
+    Failed,
+    Succeeded,
+    // reported after the restart with the slot the device is running from
+    Running,
+}
+
+// Status of a firmware update, published to <topic_prefix>/<device_id>/ota/status
+// while the update is running and after the restart
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OtaStatus {
+    pub state: OtaState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub firmware: Option<String>,
+    // download progress [%]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<u8>,
+    // OtaError variant of a failed update
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slot: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+}
+
+impl OtaStatus {
+    fn new(state: OtaState, firmware: Option<&str>) -> Self {
+        OtaStatus {
+            state,
+            firmware: firmware.map(String::from),
+            progress: None,
+            reason: None,
+            slot: None,
+            version: None,
+        }
+    }
+
+    pub fn started(firmware: &str) -> Self {
+        Self::new(OtaState::Started, Some(firmware))
+    }
+
+    pub fn downloading(firmware: &str, progress: u8) -> Self {
+        OtaStatus {
+            progress: Some(progress),
+            ..Self::new(OtaState::Downloading, Some(firmware))
+        }
+    }
+
+    pub fn failed(firmware: &str, reason: &str) -> Self {
+        OtaStatus {
+            reason: Some(reason.into()),
+            ..Self::new(OtaState::Failed, Some(firmware))
+        }
+    }
+
+    pub fn succeeded(firmware: &str) -> Self {
+        Self::new(OtaState::Succeeded, Some(firmware))
+    }
+
+    pub fn running(slot: &str, version: Option<&str>) -> Self {
+        OtaStatus {
+            slot: Some(slot.into()),
+            version: version.map(String::from),
+            ..Self::new(OtaState::Running, None)
+        }
+    }
+}
+
+// Converts the number of downloaded bytes into a percentage, which is only
+// returned when the next OTA_PROGRESS_STEP is reached, so the broker isn't
+// flooded with messages
+pub struct OtaProgress {
+    total: usize,
+    reported: u8,
+}
+
+impl OtaProgress {
+    pub fn new(total: usize) -> Self {
+        OtaProgress { total, reported: 0 }
+    }
+
+    pub fn update(&mut self, downloaded: usize) -> Option<u8> {
+        if self.total == 0 {
+            return None;
+        }
+
+        let percent = (downloaded.min(self.total) * 100 / self.total) as u8;
+        let step = percent - percent % OTA_PROGRESS_STEP;
+        if step > self.reported {
+            self.reported = step;
+            Some(step)
+        } else {
+            None
+        }
+    }
+}
+
+// Hardware independent view of an OTA slot, filled by the firmware from
+// the ESP-IDF OTA API
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SlotSummary<'a> {
+    pub label: &'a str,
+    pub firmware: Option<FirmwareSummary<'a>>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FirmwareSummary<'a> {
+    pub version: &'a str,
+    pub released: &'a str,
+    pub description: Option<&'a str>,
+}
+
+pub fn format_update_summary<const N: usize>(
+    update_summary: &mut heapless::String<N>,
+    boot_slot: &SlotSummary,
+    run_slot: &SlotSummary,
+    update_slot: &SlotSummary,
+    ota_image_info: &FirmwareSummary,
+) {
+    update_summary.push_str("OTA Update Summary\n").unwrap();
+    update_summary.push_str("==================\n").unwrap();
+    update_summary.push_str("Boot   partition: ").unwrap();
+    add_slot_info(update_summary, boot_slot);
+
+    update_summary.push_str("\nRun    partition: ").unwrap();
+    add_slot_info(update_summary, run_slot);
+
+    update_summary.push_str("\nUpdate partition: ").unwrap();
+    add_slot_info(update_summary, update_slot);
+    update_summary.push_str("\n").unwrap();
+
+    update_summary.push_str("\nDownloaded FW  : ").unwrap();
+    add_firmware_info(update_summary, Some(ota_image_info));
+    update_summary.push_str("\n").unwrap();
+}
+
+fn add_slot_info<const N: usize>(update_summary: &mut heapless::String<N>, slot: &SlotSummary) {
+    let mut label: heapless::String<10> = heapless::String::new();
+
+    copy_truncated_string(&mut label, slot.label);
+    update_summary.push_str(label.as_str()).unwrap();
+    update_summary.push_str(", ").unwrap();
+    add_firmware_info(update_summary, slot.firmware.as_ref());
+}
+
+fn add_firmware_info<const N: usize>(
+    update_summary: &mut heapless::String<N>,
+    firmware: Option<&FirmwareSummary>,
+) {
+    let mut version: heapless::String<10> = heapless::String::new();
+    let mut released: heapless::String<19> = heapless::String::new();
+    let mut description: heapless::String<32> = heapless::String::new();
+
+    if let Some(fw) = firmware {
+        copy_truncated_string(&mut version, fw.version);
+        update_summary.push_str(version.as_str()).unwrap();
+        update_summary.push_str(", ").unwrap();
+        copy_truncated_string(&mut released, fw.released);
+        update_summary.push_str(released.as_str()).unwrap();
+        if let Some(desc) = fw.description {
+            update_summary.push_str(", ").unwrap();
+            copy_truncated_string(&mut description, desc);
+            update_summary.push_str(description.as_str()).unwrap();
+        }
+    }
+}
+
+fn copy_truncated_string<const N: usize>(dest: &mut heapless::String<N>, src: &str) {
+    for c in src.chars() {
+        if dest.push(c).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_summary_test() {
+        let firmware = FirmwareSummary {
+            version: "0.1.33",
+            released: "Jan 20 2023 10:00:00",
+            description: Some("ESP32-Anemometer"),
+        };
+        let boot_slot = SlotSummary {
+            label: "ota_0",
+            firmware: Some(firmware.clone()),
+        };
+        let update_slot = SlotSummary {
+            label: "ota_1",
+            firmware: None,
+        };
+        let downloaded = FirmwareSummary {
+            version: "0.1.34-beta-build",
+            released: "Feb 01 2023 12:00:00",
+            description: None,
+        };
+        let mut summary: heapless::String<410> = heapless::String::new();
+
+        format_update_summary(
+            &mut summary,
+            &boot_slot,
+            &boot_slot,
+            &update_slot,
+            &downloaded,
+        );
+
+        assert_eq!(
+            summary.as_str(),
+            "OTA Update Summary\n\
+             ==================\n\
+             Boot   partition: ota_0, 0.1.33, Jan 20 2023 10:00:0, ESP32-Anemometer\n\
+             Run    partition: ota_0, 0.1.33, Jan 20 2023 10:00:0, ESP32-Anemometer\n\
+             Update partition: ota_1, \n\
+             \n\
+             Downloaded FW  : 0.1.34-bet, Feb 01 2023 12:00:0\n"
+        );
+    }
+
+    #[test]
+    fn progress_test() {
+        let mut progress = OtaProgress::new(1_000);
+
+        assert_eq!(progress.update(50), None);
+        assert_eq!(progress.update(100), Some(10));
+        assert_eq!(progress.update(150), None);
+        // steps which are skipped by a large chunk are not reported
+        assert_eq!(progress.update(420), Some(40));
+        assert_eq!(progress.update(1_000), Some(100));
+        assert_eq!(progress.update(1_200), None);
+        assert_eq!(OtaProgress::new(0).update(0), None);
+    }
+}
@@ -0,0 +1,241 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use serde::Serialize;
+
+// Plausibility check of the rotation samples before they are stored in
+// the wind historian. A bouncing hall sensor or an electrical spike must
+// not show up as a gust, therefore samples above the physical maximum
+// and samples with an implausible jump from the previous sample are
+// replaced by the last valid sample. Rejections are counted and a sensor
+// health status is derived from the recent rejection rate.
+
+// After this number of consecutive jump rejections the new level is
+// accepted, otherwise a real and sustained change would be rejected forever
+const MAX_CONSECUTIVE_JUMPS: u32 = 4;
+// Smoothing factor of the rejection rate, ~1 min at 4 Hz sampling
+const REJECTION_RATE_SMOOTHING: f32 = 1.0 / 240.0;
+// Rejection rate from which the sensor is reported as degraded
+const DEGRADED_REJECTION_RATE: f32 = 0.01;
+// Rejection rate from which the sensor is reported as faulty
+const FAULTY_REJECTION_RATE: f32 = 0.25;
+// Consecutive failed wind vane readings after which the vane is faulty
+const MAX_VANE_ERRORS: u32 = 20;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PlausibilityLimits {
+    // physical maximum of the rotation rate [rps]
+    pub max_rps: f32,
+    // maximum change of the rotation rate between two samples [rps]
+    pub max_step: f32,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SensorStatus {
+    #[default]
+    Ok,
+    Degraded,
+    Faulty,
+}
+
+// Counters are accumulated since boot
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SensorHealth {
+    pub status: SensorStatus,
+    pub samples: u32,
+    pub rejected_max: u32,
+    pub rejected_jump: u32,
+    pub rejected_debounce: u32,
+    pub vane_errors: u32,
+    pub rejection_rate: f32,
+}
+
+pub struct PlausibilityFilter {
+    limits: PlausibilityLimits,
+    last_valid: Option<f32>,
+    consecutive_jumps: u32,
+    consecutive_vane_errors: u32,
+    health: SensorHealth,
+}
+
+impl PlausibilityFilter {
+    pub fn new(limits: PlausibilityLimits) -> Self {
+        PlausibilityFilter {
+            limits,
+            last_valid: None,
+            consecutive_jumps: 0,
+            consecutive_vane_errors: 0,
+            health: SensorHealth::default(),
+        }
+    }
+
+    // Checks a rotation sample [rps] and returns the value to be stored,
+    // which is the last valid sample if the sample got rejected
+    pub fn filter(&mut self, rps: f32) -> f32 {
+        self.health.samples = self.health.samples.saturating_add(1);
+
+        let jump = matches!(self.last_valid,
+            Some(last) if libm::fabsf(rps - last) > self.limits.max_step);
+
+        let rejected = if !rps.is_finite() || rps < 0.0 || rps > self.limits.max_rps {
+            self.health.rejected_max = self.health.rejected_max.saturating_add(1);
+            true
+        } else if jump && self.consecutive_jumps < MAX_CONSECUTIVE_JUMPS {
+            self.health.rejected_jump = self.health.rejected_jump.saturating_add(1);
+            self.consecutive_jumps += 1;
+            true
+        } else {
+            self.consecutive_jumps = 0;
+            self.last_valid = Some(rps);
+            false
+        };
+
+        self.update_rejection_rate(rejected);
+
+        self.last_valid.unwrap_or(0.0)
+    }
+
+    // Pulse edges rejected by the debounce check of the pulse capture
+    pub fn pulses_bounced(&mut self, count: u32) {
+        self.health.rejected_debounce = self.health.rejected_debounce.saturating_add(count);
+    }
+
+    // Result of a wind vane reading
+    pub fn vane_reading(&mut self, ok: bool) {
+        if ok {
+            self.consecutive_vane_errors = 0;
+        } else {
+            self.health.vane_errors = self.health.vane_errors.saturating_add(1);
+            self.consecutive_vane_errors = self.consecutive_vane_errors.saturating_add(1);
+        }
+        self.update_status();
+    }
+
+    pub fn health(&self) -> SensorHealth {
+        self.health
+    }
+
+    fn update_rejection_rate(&mut self, rejected: bool) {
+        let sample = if rejected { 1.0 } else { 0.0 };
+        self.health.rejection_rate +=
+            (sample - self.health.rejection_rate) * REJECTION_RATE_SMOOTHING;
+        self.update_status();
+    }
+
+    fn update_status(&mut self) {
+        self.health.status = if self.health.rejection_rate >= FAULTY_REJECTION_RATE
+            || self.consecutive_vane_errors >= MAX_VANE_ERRORS
+        {
+            SensorStatus::Faulty
+        } else if self.health.rejection_rate >= DEGRADED_REJECTION_RATE
+            || self.consecutive_vane_errors > 0
+        {
+            SensorStatus::Degraded
+        } else {
+            SensorStatus::Ok
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: PlausibilityLimits = PlausibilityLimits {
+        max_rps: 50.0,
+        max_step: 5.0,
+    };
+
+    #[test]
+    fn max_rps_test() {
+        let mut filter = PlausibilityFilter::new(LIMITS);
+
+        assert_eq!(filter.filter(10.0), 10.0);
+        assert_eq!(filter.filter(80.0), 10.0);
+        assert_eq!(filter.filter(f32::NAN), 10.0);
+        assert_eq!(filter.filter(11.0), 11.0);
+        assert_eq!(filter.health().rejected_max, 2);
+        assert_eq!(filter.health().samples, 4);
+    }
+
+    #[test]
+    fn spike_test() {
+        let mut filter = PlausibilityFilter::new(LIMITS);
+
+        assert_eq!(filter.filter(3.0), 3.0);
+        // single spike
+        assert_eq!(filter.filter(30.0), 3.0);
+        assert_eq!(filter.filter(4.0), 4.0);
+        assert_eq!(filter.health().rejected_jump, 1);
+
+        // a sustained change gets accepted
+        for _ in 0..MAX_CONSECUTIVE_JUMPS {
+            assert_eq!(filter.filter(20.0), 4.0);
+        }
+        assert_eq!(filter.filter(20.0), 20.0);
+        assert_eq!(filter.filter(21.0), 21.0);
+    }
+
+    #[test]
+    fn sensor_status_test() {
+        let mut filter = PlausibilityFilter::new(LIMITS);
+        for _ in 0..1000 {
+            filter.filter(5.0);
+        }
+        assert_eq!(filter.health().status, SensorStatus::Ok);
+
+        // every 10th sample is garbage
+        for i in 0..1000 {
+            filter.filter(if i % 10 == 0 { 100.0 } else { 5.0 });
+        }
+        assert_eq!(filter.health().status, SensorStatus::Degraded);
+
+        for _ in 0..1000 {
+            filter.filter(100.0);
+        }
+        assert_eq!(filter.health().status, SensorStatus::Faulty);
+
+        for _ in 0..2000 {
+            filter.filter(5.0);
+        }
+        assert_eq!(filter.health().status, SensorStatus::Ok);
+
+        for _ in 0..MAX_VANE_ERRORS {
+            filter.vane_reading(false);
+        }
+        assert_eq!(filter.health().status, SensorStatus::Faulty);
+        assert_eq!(filter.health().vane_errors, MAX_VANE_ERRORS);
+        filter.vane_reading(true);
+        assert_eq!(filter.health().status, SensorStatus::Ok);
+    }
+}
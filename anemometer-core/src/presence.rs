@@ -0,0 +1,104 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::telemetry::format_rfc3339;
+use alloc::string::String;
+use serde::Serialize;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PresenceState {
+    Online,
+    Offline,
+}
+
+// Retained presence of the device, published to
+// <topic_prefix>/<device_id>/status. The offline message is registered as
+// last will with the broker, the online (birth) message is published after
+// every connect.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Presence<'a> {
+    pub state: PresenceState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub firmware_version: Option<&'a str>,
+    // RFC 3339 UTC, only known once the time has been set by SNTP
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boot_timestamp: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reset_reason: Option<&'a str>,
+}
+
+impl<'a> Presence<'a> {
+    pub fn offline() -> Self {
+        Presence {
+            state: PresenceState::Offline,
+            firmware_version: None,
+            boot_timestamp: None,
+            reset_reason: None,
+        }
+    }
+
+    pub fn online(
+        firmware_version: &'a str,
+        boot_time: Option<u64>,
+        reset_reason: &'a str,
+    ) -> Self {
+        Presence {
+            state: PresenceState::Online,
+            firmware_version: Some(firmware_version),
+            boot_timestamp: boot_time.map(format_rfc3339),
+            reset_reason: Some(reset_reason),
+        }
+    }
+
+    pub fn format_msg(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presence_test() {
+        assert_eq!(Presence::offline().format_msg(), r#"{"state":"offline"}"#);
+        assert_eq!(
+            Presence::online("0.2.0", Some(1_700_000_000), "powerOn").format_msg(),
+            r#"{"state":"online","firmwareVersion":"0.2.0","bootTimestamp":"2023-11-14T22:13:20Z","resetReason":"powerOn"}"#
+        );
+        assert_eq!(
+            Presence::online("0.2.0", None, "panic").format_msg(),
+            r#"{"state":"online","firmwareVersion":"0.2.0","resetReason":"panic"}"#
+        );
+    }
+}
@@ -0,0 +1,317 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+// Rotation rate estimation from timestamped anemometer pulses.
+//
+// Counting pulses per measurement interval gives a resolution of one
+// pulse per interval, at light wind that is only a few pulses. Measuring
+// the time of the latest pulse period gives a much higher resolution at
+// low speed, while at high speed the ISR latency makes single periods
+// noisy. The estimator therefore blends both methods depending on the
+// number of pulses seen in an interval. Pulses are counted between pulse
+// edges instead of the interval boundaries, which removes the +/- one
+// pulse quantization of plain counting.
+//
+// All timestamps are free running µs counters which may wrap around.
+
+// Up to this number of pulses per interval only the period method is used
+const PERIOD_ONLY_PULSES: u32 = 2;
+// From this number of pulses per interval only the count method is used
+const COUNT_ONLY_PULSES: u32 = 10;
+// Without a pulse for this time [µs] the cups are considered standing still
+const STANDSTILL_TIMEOUT: u32 = 4_000_000;
+
+// Pulses captured by the ISR since the last measurement
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PulseCapture {
+    pub count: u32,
+    pub first_edge: u32,
+    pub penultimate_edge: u32,
+    pub last_edge: u32,
+    // edges rejected because they followed the previous edge closer than
+    // the debounce interval
+    pub bounced: u32,
+    debounce: u32,
+    accepted_edge: Option<u32>,
+}
+
+impl PulseCapture {
+    // debounce is the minimal interval between two pulse edges [µs]
+    pub const fn new(debounce: u32) -> Self {
+        PulseCapture {
+            count: 0,
+            first_edge: 0,
+            penultimate_edge: 0,
+            last_edge: 0,
+            bounced: 0,
+            debounce,
+            accepted_edge: None,
+        }
+    }
+
+    // Called from the ISR with the timestamp [µs] of the pulse edge
+    pub fn record(&mut self, timestamp: u32) {
+        if let Some(accepted_edge) = self.accepted_edge {
+            if timestamp.wrapping_sub(accepted_edge) < self.debounce {
+                self.bounced = self.bounced.saturating_add(1);
+                return;
+            }
+        }
+        self.accepted_edge = Some(timestamp);
+
+        if self.count == 0 {
+            self.first_edge = timestamp;
+        } else {
+            self.penultimate_edge = self.last_edge;
+        }
+        self.last_edge = timestamp;
+        self.count = self.count.saturating_add(1);
+    }
+
+    // Returns the captured pulses and starts a new capture. The last
+    // accepted edge is kept for debouncing the next edge.
+    pub fn take(&mut self) -> Self {
+        let capture = *self;
+        *self = PulseCapture {
+            accepted_edge: self.accepted_edge,
+            ..Self::new(self.debounce)
+        };
+
+        capture
+    }
+}
+
+pub struct RotationEstimator {
+    pulses_per_revolution: f32,
+    // last pulse edge of the previous intervals
+    previous_edge: Option<u32>,
+    rps: f32,
+}
+
+impl RotationEstimator {
+    pub fn new(pulses_per_revolution: u8) -> Self {
+        RotationEstimator {
+            pulses_per_revolution: pulses_per_revolution.max(1) as f32,
+            previous_edge: None,
+            rps: 0.0,
+        }
+    }
+
+    // Updates the estimate with the pulses captured during the last
+    // measurement interval. now is the timestamp [µs] at the end of the
+    // interval. Returns the rotation rate [rps].
+    pub fn update(&mut self, capture: &PulseCapture, now: u32) -> f32 {
+        if let Some(previous_edge) = self.previous_edge {
+            let reference = if capture.count > 0 {
+                capture.first_edge
+            } else {
+                now
+            };
+            // a pulse after standstill doesn't tell anything about the period
+            if reference.wrapping_sub(previous_edge) > STANDSTILL_TIMEOUT {
+                self.previous_edge = None;
+            }
+        }
+
+        if capture.count == 0 {
+            // The next pulse is not there yet, so the rotation is at most
+            // one pulse per time since the last pulse. This lets the
+            // estimate decay smoothly when the wind calms down.
+            self.rps = match self.previous_edge {
+                Some(previous_edge) => {
+                    let elapsed = now.wrapping_sub(previous_edge) as f32 / 1_000_000.0;
+                    self.rps.min(1.0 / (self.pulses_per_revolution * elapsed))
+                }
+                None => 0.0,
+            };
+            return self.rps;
+        }
+
+        let (reference, periods, penultimate_edge) = match self.previous_edge {
+            Some(previous_edge) if capture.count == 1 => (previous_edge, 1, previous_edge),
+            Some(previous_edge) => (previous_edge, capture.count, capture.penultimate_edge),
+            None => (
+                capture.first_edge,
+                capture.count - 1,
+                capture.penultimate_edge,
+            ),
+        };
+        self.previous_edge = Some(capture.last_edge);
+
+        // a single pulse after standstill carries no timing information
+        if periods == 0 {
+            return self.rps;
+        }
+
+        let span = capture.last_edge.wrapping_sub(reference).max(1) as f32 / 1_000_000.0;
+        let count_rps = periods as f32 / (self.pulses_per_revolution * span);
+        let period = capture.last_edge.wrapping_sub(penultimate_edge).max(1) as f32 / 1_000_000.0;
+        let period_rps = 1.0 / (self.pulses_per_revolution * period);
+
+        let weight = (capture.count.saturating_sub(PERIOD_ONLY_PULSES) as f32
+            / (COUNT_ONLY_PULSES - PERIOD_ONLY_PULSES) as f32)
+            .min(1.0);
+        self.rps = (1.0 - weight) * period_rps + weight * count_rps;
+
+        self.rps
+    }
+
+    // Estimate for pulse sources which only provide a pulse count like the
+    // PCNT hardware counter. interval is the measurement interval [µs].
+    pub fn update_count(&mut self, count: u32, interval: u32) -> f32 {
+        self.previous_edge = None;
+        self.rps = count as f32 / (self.pulses_per_revolution * interval as f32 / 1_000_000.0);
+
+        self.rps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: u32 = 250_000;
+
+    // Feeds the estimator with pulses at a constant rotation rate and
+    // returns the estimates of all intervals
+    fn simulate(
+        estimator: &mut RotationEstimator,
+        start: u32,
+        rps: f32,
+        pulses_per_revolution: u8,
+        intervals: u32,
+    ) -> Vec<f32> {
+        let period = (1_000_000.0 / (rps * pulses_per_revolution as f32)) as u64;
+        let mut edge = start as u64 + period / 3;
+        let mut estimates = Vec::new();
+
+        for i in 1..=intervals as u64 {
+            let now = start as u64 + i * INTERVAL as u64;
+            let mut capture = PulseCapture::new(0);
+            while edge <= now {
+                capture.record(edge as u32);
+                edge += period;
+            }
+            estimates.push(estimator.update(&capture, now as u32));
+        }
+        estimates
+    }
+
+    #[test]
+    fn pulse_capture_test() {
+        let mut capture = PulseCapture::new(50);
+        capture.record(100);
+        capture.record(200);
+        // contact bounce
+        capture.record(220);
+        capture.record(300);
+
+        let pulses = capture.take();
+        assert_eq!(pulses.count, 3);
+        assert_eq!(pulses.first_edge, 100);
+        assert_eq!(pulses.penultimate_edge, 200);
+        assert_eq!(pulses.last_edge, 300);
+        assert_eq!(pulses.bounced, 1);
+
+        // debouncing continues across captures
+        capture.record(320);
+        capture.record(400);
+        let pulses = capture.take();
+        assert_eq!(pulses.count, 1);
+        assert_eq!(pulses.first_edge, 400);
+        assert_eq!(pulses.bounced, 1);
+    }
+
+    #[test]
+    fn low_speed_resolution_test() {
+        // 0.35 rps gives less than one pulse per interval, the count method
+        // would report either 0 or 2 rps
+        let mut estimator = RotationEstimator::new(2);
+        let estimates = simulate(&mut estimator, 0, 0.35, 2, 80);
+
+        for rps in &estimates[20..] {
+            assert!((rps - 0.35).abs() < 0.01, "{rps}");
+        }
+
+        let mut estimator = RotationEstimator::new(2);
+        let estimates = simulate(&mut estimator, 0, 0.42, 2, 80);
+        for rps in &estimates[20..] {
+            assert!((rps - 0.42).abs() < 0.01, "{rps}");
+        }
+    }
+
+    #[test]
+    fn high_speed_test() {
+        let mut estimator = RotationEstimator::new(2);
+        let estimates = simulate(&mut estimator, 0, 25.0, 2, 40);
+
+        for rps in &estimates[4..] {
+            assert!((rps - 25.0).abs() < 0.01, "{rps}");
+        }
+    }
+
+    #[test]
+    fn timer_wrap_around_test() {
+        let mut estimator = RotationEstimator::new(2);
+        let estimates = simulate(&mut estimator, u32::MAX - 5_000_000, 1.5, 2, 80);
+
+        for rps in &estimates[8..] {
+            assert!((rps - 1.5).abs() < 0.01, "{rps}");
+        }
+    }
+
+    #[test]
+    fn standstill_test() {
+        let mut estimator = RotationEstimator::new(2);
+        let estimates = simulate(&mut estimator, 0, 2.0, 2, 40);
+        assert!((estimates[39] - 2.0).abs() < 0.01);
+
+        // no more pulses, the estimate decays and drops to zero
+        let mut last = estimates[39];
+        let mut now = 40 * INTERVAL;
+        for _ in 0..20 {
+            now += INTERVAL;
+            let rps = estimator.update(&PulseCapture::new(0), now);
+            assert!(rps <= last);
+            last = rps;
+        }
+        assert_eq!(last, 0.0);
+    }
+
+    #[test]
+    fn pulse_count_test() {
+        let mut estimator = RotationEstimator::new(2);
+
+        assert_eq!(estimator.update_count(0, INTERVAL), 0.0);
+        assert_eq!(estimator.update_count(5, INTERVAL), 10.0);
+        assert_eq!(estimator.update_count(1, INTERVAL), 2.0);
+    }
+}
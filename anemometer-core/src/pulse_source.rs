@@ -0,0 +1,351 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::pulse::{PulseCapture, RotationEstimator};
+use alloc::vec::Vec;
+use core::fmt;
+
+// Sources of the anemometer rotation which feed the wind historian. The
+// firmware reads the hall sensor (GPIO ISR or PCNT), while the synthetic
+// and the replay source allow running the statistics pipeline without an
+// anemometer, e.g. on the bench or on a Linux host against recorded wind.
+
+// Reading of one measurement interval
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct PulseReading {
+    // rotation rate [rps]
+    pub rps: f32,
+    // pulse edges rejected as contact bounce during the interval
+    pub bounced: u32,
+}
+
+pub trait PulseSource {
+    // Called once per measurement interval. Finite sources like a replay
+    // return None at the end of the recording.
+    fn read(&mut self) -> Option<PulseReading>;
+}
+
+// Deterministic wind for bench tests: a mean rotation rate modulated by a
+// sine wave plus pseudo random turbulence. The same seed always gives the
+// same sequence.
+pub struct SyntheticSource {
+    // sampling interval [ms]
+    sample_interval: u64,
+    mean_rps: f32,
+    amplitude: f32,
+    // period of the sine wave [s]
+    period: f32,
+    // max. deviation of the turbulence [rps]
+    turbulence: f32,
+    samples: u64,
+    random: u32,
+}
+
+impl SyntheticSource {
+    pub fn new(sample_interval: u64, mean_rps: f32, amplitude: f32, period: f32) -> Self {
+        SyntheticSource {
+            sample_interval: sample_interval.max(1),
+            mean_rps,
+            amplitude,
+            period: period.max(1.0),
+            turbulence: 0.0,
+            samples: 0,
+            random: 1,
+        }
+    }
+
+    pub fn with_turbulence(mut self, turbulence: f32, seed: u32) -> Self {
+        self.turbulence = turbulence;
+        // xorshift needs a non zero state
+        self.random = seed.max(1);
+        self
+    }
+
+    // xorshift32, good enough for turbulence and identical on all targets
+    fn next_random(&mut self) -> f32 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+
+        // -1.0 .. 1.0
+        (self.random as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+}
+
+impl PulseSource for SyntheticSource {
+    fn read(&mut self) -> Option<PulseReading> {
+        let time = (self.samples * self.sample_interval) as f32 / 1000.0;
+        self.samples += 1;
+
+        let phase = 2.0 * core::f32::consts::PI * time / self.period;
+        let rps = self.mean_rps
+            + self.amplitude * libm::sinf(phase)
+            + self.turbulence * self.next_random();
+
+        Some(PulseReading {
+            rps: rps.max(0.0),
+            bounced: 0,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ReplayError {
+    // line of the CSV file which could not be parsed, starting at 1
+    pub line: usize,
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid replay data in line {}", self.line)
+    }
+}
+
+enum Recording {
+    // time [s] and rotation rate [rps] per row
+    Rps(Vec<(f64, f32)>),
+    // pulse edges [µs]
+    Pulses {
+        edges: Vec<u64>,
+        estimator: RotationEstimator,
+    },
+}
+
+// Replays a recorded CSV file. The first column of every row is a time
+// stamp. Lines starting with '#' are comments, a first line which can't
+// be parsed is treated as header.
+//
+// Rotation rate files contain the rate [rps] in a given column, rows may
+// be recorded at any interval and the rate is held until the next row.
+// This includes the GpsLog.txt files of the calibration firmware
+// ("hh:mm:ss,gps km/h,rps", column 2). Time stamps are either seconds or
+// a time of day hh:mm:ss[.fff] which may wrap around at midnight.
+//
+// Pulse files contain one pulse edge per row as time stamp [µs], they
+// are replayed through the RotationEstimator like the GPIO ISR capture.
+pub struct ReplaySource {
+    // sampling interval [ms]
+    sample_interval: u64,
+    recording: Recording,
+    // replay time, same unit as the recording
+    time: f64,
+    next: usize,
+    rps: f32,
+}
+
+impl ReplaySource {
+    pub fn from_rps_csv(
+        csv: &str,
+        rps_column: usize,
+        sample_interval: u64,
+    ) -> Result<Self, ReplayError> {
+        let mut rows: Vec<(f64, f32)> = Vec::new();
+        // added to the time of day after midnight
+        let mut day_offset = 0.0;
+
+        for (line, fields) in Self::rows(csv) {
+            let row = fields
+                .get(rps_column)
+                .and_then(|rps| Some((parse_time(fields[0])?, rps.trim().parse::<f32>().ok()?)));
+            let (mut time, rps) = match row {
+                Some(row) => row,
+                None if line == 1 => continue,
+                None => return Err(ReplayError { line }),
+            };
+
+            time += day_offset;
+            if let Some((last, _)) = rows.last() {
+                if time < *last {
+                    day_offset += 24.0 * 60.0 * 60.0;
+                    time += 24.0 * 60.0 * 60.0;
+                }
+            }
+            rows.push((time, rps));
+        }
+
+        Ok(ReplaySource {
+            sample_interval: sample_interval.max(1),
+            time: rows.first().map_or(0.0, |row| row.0),
+            recording: Recording::Rps(rows),
+            next: 0,
+            rps: 0.0,
+        })
+    }
+
+    pub fn from_pulse_csv(
+        csv: &str,
+        pulses_per_revolution: u8,
+        sample_interval: u64,
+    ) -> Result<Self, ReplayError> {
+        let mut edges: Vec<u64> = Vec::new();
+
+        for (line, fields) in Self::rows(csv) {
+            match fields[0].trim().parse::<u64>() {
+                Ok(edge) => edges.push(edge),
+                Err(_) if line == 1 => continue,
+                Err(_) => return Err(ReplayError { line }),
+            }
+        }
+
+        Ok(ReplaySource {
+            sample_interval: sample_interval.max(1),
+            time: edges.first().map_or(0.0, |edge| *edge as f64),
+            recording: Recording::Pulses {
+                edges,
+                estimator: RotationEstimator::new(pulses_per_revolution),
+            },
+            next: 0,
+            rps: 0.0,
+        })
+    }
+
+    // Number of the non empty, non comment lines with their fields
+    fn rows(csv: &str) -> Vec<(usize, Vec<&str>)> {
+        csv.lines()
+            .enumerate()
+            .map(|(idx, line)| (idx + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(line, fields)| (line, fields.split(',').collect()))
+            .collect()
+    }
+}
+
+impl PulseSource for ReplaySource {
+    fn read(&mut self) -> Option<PulseReading> {
+        match &mut self.recording {
+            Recording::Rps(rows) => {
+                let (last_time, _) = *rows.last()?;
+                if self.time > last_time {
+                    return None;
+                }
+                while self.next < rows.len() && rows[self.next].0 <= self.time {
+                    self.rps = rows[self.next].1;
+                    self.next += 1;
+                }
+                self.time += self.sample_interval as f64 / 1000.0;
+            }
+            Recording::Pulses { edges, estimator } => {
+                if self.next >= edges.len() {
+                    return None;
+                }
+                self.time += self.sample_interval as f64 * 1000.0;
+
+                let mut capture = PulseCapture::new(0);
+                while self.next < edges.len() && edges[self.next] as f64 <= self.time {
+                    capture.record(edges[self.next] as u32);
+                    self.next += 1;
+                }
+                self.rps = estimator.update(&capture, self.time as u64 as u32);
+            }
+        }
+
+        Some(PulseReading {
+            rps: self.rps,
+            bounced: 0,
+        })
+    }
+}
+
+// Parses seconds "12.5" or a time of day "hh:mm:ss[.fff]" into seconds
+fn parse_time(time: &str) -> Option<f64> {
+    let time = time.trim();
+    if !time.contains(':') {
+        return time.parse().ok();
+    }
+
+    let mut seconds = 0.0;
+    for field in time.split(':') {
+        seconds = seconds * 60.0 + field.parse::<f64>().ok()?;
+    }
+    Some(seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn synthetic_source_test() {
+        let mut source = SyntheticSource::new(250, 5.0, 2.0, 60.0).with_turbulence(0.5, 42);
+        let samples: Vec<f32> = (0..240).map(|_| source.read().unwrap().rps).collect();
+
+        // deterministic for a given seed
+        let mut source = SyntheticSource::new(250, 5.0, 2.0, 60.0).with_turbulence(0.5, 42);
+        for rps in &samples {
+            assert_eq!(source.read().unwrap().rps, *rps);
+        }
+
+        // peak of the sine wave after 15 s
+        assert!(samples.iter().all(|rps| *rps >= 2.5 && *rps <= 7.5));
+        assert!((samples[60] - 7.0).abs() <= 0.5);
+    }
+
+    #[test]
+    fn replay_gps_log_test() {
+        let csv = "23:59:58, 10.00, 1.50\n\
+                   23:59:59, 12.00, 2.00\n\
+                   \n\
+                   00:00:00.500, 11.00, 3.00\n";
+        let mut source = ReplaySource::from_rps_csv(csv, 2, 250).unwrap();
+        let samples: Vec<f32> = core::iter::from_fn(|| source.read())
+            .map(|reading| reading.rps)
+            .collect();
+
+        // 2.5 s recording held at 4 Hz, wrapping around midnight
+        assert_eq!(samples.len(), 11);
+        assert_eq!(samples[..4], [1.5; 4]);
+        assert_eq!(samples[4..10], [2.0; 6]);
+        assert_eq!(samples[10], 3.0);
+
+        assert_eq!(
+            ReplaySource::from_rps_csv("time,rps\n1.0,2.0\n2.0,x\n", 1, 250).err(),
+            Some(ReplayError { line: 3 })
+        );
+    }
+
+    #[test]
+    fn replay_pulses_test() {
+        // 40 pulses at 2 rps with 2 pulses per revolution, 9.75 s between the
+        // first and the last pulse
+        let csv: String = (0..40)
+            .map(|i| format!("{}\n", 1_000 + i * 250_000))
+            .collect();
+        let mut source = ReplaySource::from_pulse_csv(&csv, 2, 250).unwrap();
+        let samples: Vec<f32> = core::iter::from_fn(|| source.read())
+            .map(|reading| reading.rps)
+            .collect();
+
+        assert_eq!(samples.len(), 39);
+        for rps in &samples[2..] {
+            assert!((rps - 2.0).abs() < 0.01, "{rps}");
+        }
+    }
+}
@@ -0,0 +1,236 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use serde::{Deserialize, Serialize};
+
+// Store and forward queue for MQTT telemetry. Reports which can't be
+// published while the connection is down are kept in RAM, with an optional
+// spill into flash once the RAM part is full. The reports are replayed in
+// the order they were measured, when the queue is full the oldest reports
+// are dropped.
+//
+// Order of the reports: flash (oldest) -> RAM (newest). Reports are only
+// moved from the front of the RAM part to the back of the flash part, so
+// the order is kept.
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueuedReport {
+    // unix time of the measurement [s]
+    pub epoch: u64,
+    pub topic: String,
+    pub payload: String,
+}
+
+// Persistent FIFO used for spilling reports into flash
+pub trait SpillStorage {
+    fn len(&self) -> usize;
+
+    fn capacity(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Returns false if the report could not be written
+    fn push_back(&mut self, report: &QueuedReport) -> bool;
+
+    fn front(&mut self) -> Option<QueuedReport>;
+
+    fn pop_front(&mut self);
+}
+
+pub struct ReportQueue<S: SpillStorage> {
+    ram: VecDeque<QueuedReport>,
+    ram_capacity: usize,
+    spill: Option<S>,
+    // reports dropped because the queue was full
+    dropped: u32,
+}
+
+impl<S: SpillStorage> ReportQueue<S> {
+    pub fn new(ram_capacity: usize, spill: Option<S>) -> Self {
+        ReportQueue {
+            ram: VecDeque::with_capacity(ram_capacity),
+            ram_capacity: ram_capacity.max(1),
+            spill,
+            dropped: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ram.len() + self.spill.as_ref().map_or(0, |spill| spill.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    pub fn push(&mut self, report: QueuedReport) {
+        if self.ram.len() >= self.ram_capacity {
+            if let Some(oldest) = self.ram.pop_front() {
+                if !self.spill_report(&oldest) {
+                    self.dropped = self.dropped.saturating_add(1);
+                }
+            }
+        }
+        self.ram.push_back(report);
+    }
+
+    // Oldest report, which is the next one to be published
+    pub fn front(&mut self) -> Option<QueuedReport> {
+        if let Some(spill) = self.spill.as_mut() {
+            if !spill.is_empty() {
+                return spill.front();
+            }
+        }
+        self.ram.front().cloned()
+    }
+
+    // Removes the oldest report after it has been published
+    pub fn pop_front(&mut self) {
+        if let Some(spill) = self.spill.as_mut() {
+            if !spill.is_empty() {
+                spill.pop_front();
+                return;
+            }
+        }
+        self.ram.pop_front();
+    }
+
+    fn spill_report(&mut self, report: &QueuedReport) -> bool {
+        let Some(spill) = self.spill.as_mut() else {
+            return false;
+        };
+        if spill.capacity() == 0 {
+            return false;
+        }
+
+        if spill.len() >= spill.capacity() {
+            spill.pop_front();
+            self.dropped = self.dropped.saturating_add(1);
+        }
+        spill.push_back(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct VecSpill {
+        reports: VecDeque<QueuedReport>,
+        capacity: usize,
+    }
+
+    impl SpillStorage for VecSpill {
+        fn len(&self) -> usize {
+            self.reports.len()
+        }
+
+        fn capacity(&self) -> usize {
+            self.capacity
+        }
+
+        fn push_back(&mut self, report: &QueuedReport) -> bool {
+            self.reports.push_back(report.clone());
+            true
+        }
+
+        fn front(&mut self) -> Option<QueuedReport> {
+            self.reports.front().cloned()
+        }
+
+        fn pop_front(&mut self) {
+            self.reports.pop_front();
+        }
+    }
+
+    fn report(epoch: u64) -> QueuedReport {
+        QueuedReport {
+            epoch,
+            topic: String::from("anemometer/device-1/wind"),
+            payload: format!("{{\"epochTime\":{epoch}}}"),
+        }
+    }
+
+    fn drain(queue: &mut ReportQueue<VecSpill>) -> Vec<u64> {
+        core::iter::from_fn(|| {
+            let report = queue.front()?;
+            queue.pop_front();
+            Some(report.epoch)
+        })
+        .collect()
+    }
+
+    #[test]
+    fn ram_queue_test() {
+        let mut queue: ReportQueue<VecSpill> = ReportQueue::new(3, None);
+        for epoch in 1..=5 {
+            queue.push(report(epoch));
+        }
+
+        // the oldest reports are dropped
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(drain(&mut queue), [3, 4, 5]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn flash_spill_test() {
+        let spill = VecSpill {
+            reports: VecDeque::new(),
+            capacity: 4,
+        };
+        let mut queue = ReportQueue::new(2, Some(spill));
+        for epoch in 1..=5 {
+            queue.push(report(epoch));
+        }
+        assert_eq!(queue.len(), 5);
+        assert_eq!(queue.dropped(), 0);
+
+        // replay starts with the spilled reports
+        assert_eq!(queue.front(), Some(report(1)));
+        queue.pop_front();
+
+        for epoch in 6..=9 {
+            queue.push(report(epoch));
+        }
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(drain(&mut queue), [4, 5, 6, 7, 8, 9]);
+    }
+}
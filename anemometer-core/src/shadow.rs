@@ -0,0 +1,430 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::data_processing::{Calibration, CalibrationPoint};
+use crate::units::{CompassPoints, SpeedUnit};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::str::FromStr;
+use log::*;
+use serde::{Deserialize, Serialize};
+
+// Limits of the data reporting interval [s]
+pub const MIN_REPORTING_INTERVAL: u32 = 10;
+pub const MAX_REPORTING_INTERVAL: u32 = 3600;
+// Longest POSIX TZ string which can be stored in the conf partition
+pub const MAX_TIMEZONE_LEN: usize = 64;
+// Longest calibration id and formatted calibration table which can be
+// read back from the conf partition, the string buffer holds 180 bytes
+// including the terminating zero
+pub const MAX_CALIBRATION_ID_LEN: usize = 64;
+pub const MAX_CALIBRATION_TABLE_LEN: usize = 179;
+
+// Message published by AWS IoT to <things_prefix>/<device_id>/shadow/update/delta
+// if the desired state of the device shadow differs from the reported state.
+// Only the state is of interest, version and metadata are ignored.
+#[derive(Debug, Deserialize)]
+pub struct ShadowDelta {
+    pub state: ShadowConfig,
+}
+
+// Settings which can be changed remotely through the desired state of the
+// device shadow. The same structure is echoed back as reported state once
+// the settings are applied, which clears the delta. Unknown keys are
+// ignored.
+//
+// {
+//   "reportingInterval": 60,
+//   "units": { "speedUnit": "kn", "compassPoints": 8 },
+//   "calibration": { "id": "cal-2023-04", "pulsesPerRevolution": 2,
+//                    "slope": 2.4, "offset": 0.3,
+//                    "table": [{ "rps": 0.5, "kmh": 1.8 }, ...] },
+//   "logLevel": "info",
+//   "timezone": "CET-1CEST-2,M3.5.0/02:00:00,M10.5.0/03:00:00"
+// }
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShadowConfig {
+    // [s]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reporting_interval: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub units: Option<UnitsConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub calibration: Option<CalibrationConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnitsConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed_unit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compass_points: Option<u8>,
+}
+
+// Uses the key names of the reported Calibration, otherwise the delta
+// would never be cleared
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CalibrationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pulses_per_revolution: Option<u8>,
+    // [km/h per rps]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slope: Option<f32>,
+    // [km/h]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub table: Option<Vec<CalibrationPoint>>,
+}
+
+impl ShadowDelta {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        match serde_json::from_slice(data) {
+            Ok(delta) => Some(delta),
+            Err(err) => {
+                warn!("Invalid shadow delta: {}", err);
+                None
+            }
+        }
+    }
+}
+
+impl ShadowConfig {
+    pub fn is_empty(&self) -> bool {
+        self.reporting_interval.is_none()
+            && self.units.is_none()
+            && self.calibration.is_none()
+            && self.log_level.is_none()
+            && self.timezone.is_none()
+    }
+
+    // Returns the valid part of the desired state. Invalid values are
+    // dropped, they are not reported and therefore stay in the delta.
+    pub fn validate(self) -> ShadowConfig {
+        let reporting_interval = self.reporting_interval.filter(|interval| {
+            let valid = (MIN_REPORTING_INTERVAL..=MAX_REPORTING_INTERVAL).contains(interval);
+            if !valid {
+                warn!("Invalid reporting interval {}", interval);
+            }
+            valid
+        });
+        let log_level = self.log_level.filter(|level| {
+            let valid = LevelFilter::from_str(level).is_ok();
+            if !valid {
+                warn!("Invalid log level \"{}\"", level);
+            }
+            valid
+        });
+        let timezone = self.timezone.filter(|timezone| {
+            let valid = !timezone.is_empty()
+                && timezone.len() <= MAX_TIMEZONE_LEN
+                && timezone.chars().all(|c| c.is_ascii_graphic());
+            if !valid {
+                warn!("Invalid timezone \"{}\"", timezone);
+            }
+            valid
+        });
+
+        ShadowConfig {
+            reporting_interval,
+            units: self.units.and_then(UnitsConfig::validate),
+            calibration: self.calibration.and_then(CalibrationConfig::validate),
+            log_level,
+            timezone,
+        }
+    }
+
+    pub fn log_level_filter(&self) -> Option<LevelFilter> {
+        self.log_level
+            .as_deref()
+            .and_then(|level| LevelFilter::from_str(level).ok())
+    }
+}
+
+impl UnitsConfig {
+    fn validate(self) -> Option<Self> {
+        let speed_unit = self.speed_unit.filter(|unit| {
+            let valid = SpeedUnit::from_str(unit).is_ok();
+            if !valid {
+                warn!("Invalid speed unit \"{}\"", unit);
+            }
+            valid
+        });
+        let compass_points = self.compass_points.filter(|count| {
+            let valid = CompassPoints::from_count(*count).is_some();
+            if !valid {
+                warn!("Invalid number of compass points {}", count);
+            }
+            valid
+        });
+
+        if speed_unit.is_none() && compass_points.is_none() {
+            None
+        } else {
+            Some(UnitsConfig {
+                speed_unit,
+                compass_points,
+            })
+        }
+    }
+}
+
+// Complete configuration of a calibration, e.g. to report the calibration
+// in use
+impl From<&Calibration> for CalibrationConfig {
+    fn from(calibration: &Calibration) -> Self {
+        CalibrationConfig {
+            id: Some(calibration.id.clone()),
+            pulses_per_revolution: Some(calibration.pulses_per_revolution),
+            slope: Some(calibration.slope),
+            offset: Some(calibration.offset),
+            table: Some(calibration.table.clone()),
+        }
+    }
+}
+
+impl CalibrationConfig {
+    fn validate(self) -> Option<Self> {
+        let id = self.id.filter(|id| {
+            if id.len() > MAX_CALIBRATION_ID_LEN {
+                warn!(
+                    "Invalid calibration id, longer than {}",
+                    MAX_CALIBRATION_ID_LEN
+                );
+            }
+            !id.is_empty() && id.len() <= MAX_CALIBRATION_ID_LEN
+        });
+        let pulses_per_revolution = self.pulses_per_revolution.filter(|ppr| {
+            if *ppr == 0 {
+                warn!("Invalid calibration pulses per revolution {}", ppr);
+            }
+            *ppr > 0
+        });
+        let slope = self.slope.filter(|slope| {
+            let valid = slope.is_finite() && *slope > 0.0;
+            if !valid {
+                warn!("Invalid calibration slope {}", slope);
+            }
+            valid
+        });
+        let offset = self.offset.filter(|offset| {
+            if !offset.is_finite() {
+                warn!("Invalid calibration offset {}", offset);
+            }
+            offset.is_finite()
+        });
+        // same rules as for the table in the conf partition
+        let table = self.table.filter(|table| {
+            let valid = table.windows(2).all(|points| points[0].rps < points[1].rps)
+                && table.iter().all(|point| {
+                    point.rps.is_finite()
+                        && point.rps >= 0.0
+                        && point.kmh.is_finite()
+                        && point.kmh >= 0.0
+                });
+            if !valid {
+                warn!("Invalid calibration table, rps needs to be strictly increasing and all values finite and non-negative");
+                return false;
+            }
+            let len = Calibration::format_table(table).len();
+            if len > MAX_CALIBRATION_TABLE_LEN {
+                warn!(
+                    "Invalid calibration table, {} characters exceed the limit of {}",
+                    len, MAX_CALIBRATION_TABLE_LEN
+                );
+                return false;
+            }
+            true
+        });
+
+        let calibration = CalibrationConfig {
+            id,
+            pulses_per_revolution,
+            slope,
+            offset,
+            table,
+        };
+        if calibration == CalibrationConfig::default() {
+            None
+        } else {
+            Some(calibration)
+        }
+    }
+
+    // Updates the given calibration with all values contained in the
+    // desired state
+    pub fn apply(&self, calibration: &mut Calibration) {
+        if let Some(id) = &self.id {
+            calibration.id = id.clone();
+        }
+        if let Some(ppr) = self.pulses_per_revolution {
+            calibration.pulses_per_revolution = ppr;
+        }
+        if let Some(slope) = self.slope {
+            calibration.slope = slope;
+        }
+        if let Some(offset) = self.offset {
+            calibration.offset = offset;
+        }
+        if let Some(table) = &self.table {
+            calibration.table = table.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_delta_test() {
+        let delta = br#"{"version":12,"timestamp":1680000000,
+            "state":{"reportingInterval":60,"units":{"speedUnit":"kn"},
+                "calibration":{"slope":2.5,"table":[{"rps":1.0,"kmh":3.0},{"rps":2.0,"kmh":7.0}]},
+                "logLevel":"debug","unknown":true},
+            "metadata":{"reportingInterval":{"timestamp":1680000000}}}"#;
+        let config = ShadowDelta::parse(delta).unwrap().state.validate();
+
+        assert_eq!(config.reporting_interval, Some(60));
+        assert_eq!(
+            config.units,
+            Some(UnitsConfig {
+                speed_unit: Some(String::from("kn")),
+                compass_points: None
+            })
+        );
+        assert_eq!(config.log_level_filter(), Some(LevelFilter::Debug));
+        assert_eq!(config.timezone, None);
+
+        let mut calibration = Calibration::default();
+        config.calibration.as_ref().unwrap().apply(&mut calibration);
+        assert_eq!(calibration.slope, 2.5);
+        assert_eq!(calibration.offset, 0.0);
+        assert_eq!(calibration.table.len(), 2);
+
+        // the accepted settings are echoed back without the unset keys
+        assert_eq!(
+            serde_json::to_string(&config.units).unwrap(),
+            r#"{"speedUnit":"kn"}"#
+        );
+        assert!(ShadowDelta::parse(b"{\"state\":").is_none());
+    }
+
+    #[test]
+    fn validate_test() {
+        let config = ShadowConfig {
+            reporting_interval: Some(1),
+            units: Some(UnitsConfig {
+                speed_unit: Some(String::from("furlong/fortnight")),
+                compass_points: Some(12),
+            }),
+            calibration: Some(CalibrationConfig {
+                pulses_per_revolution: Some(0),
+                slope: Some(f32::NAN),
+                table: Some(vec![
+                    CalibrationPoint { rps: 2.0, kmh: 7.0 },
+                    CalibrationPoint { rps: 1.0, kmh: 3.0 },
+                ]),
+                ..Default::default()
+            }),
+            log_level: Some(String::from("verbose")),
+            timezone: Some(String::from("CET -1")),
+        };
+        assert!(config.validate().is_empty());
+
+        let config = ShadowConfig {
+            units: Some(UnitsConfig {
+                speed_unit: Some(String::from("mph")),
+                compass_points: Some(12),
+            }),
+            timezone: Some(String::from("UTC0")),
+            ..Default::default()
+        }
+        .validate();
+        assert_eq!(config.units.unwrap().compass_points, None);
+        assert_eq!(config.timezone.as_deref(), Some("UTC0"));
+    }
+
+    #[test]
+    fn validate_calibration_table_test() {
+        let table = |points: Vec<CalibrationPoint>| ShadowConfig {
+            calibration: Some(CalibrationConfig {
+                table: Some(points),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        assert!(table(vec![
+            CalibrationPoint { rps: 1.0, kmh: 3.0 },
+            CalibrationPoint {
+                rps: 2.0,
+                kmh: -1.0
+            },
+        ])
+        .validate()
+        .is_empty());
+        assert!(table(vec![CalibrationPoint {
+            rps: 1.0,
+            kmh: f32::INFINITY
+        }])
+        .validate()
+        .is_empty());
+
+        // the formatted table wouldn't fit the buffer of the conf partition
+        let points: Vec<CalibrationPoint> = (0..20)
+            .map(|i| CalibrationPoint {
+                rps: i as f32 + 0.125,
+                kmh: i as f32 * 3.5 + 0.25,
+            })
+            .collect();
+        assert!(Calibration::format_table(&points).len() > MAX_CALIBRATION_TABLE_LEN);
+        assert!(table(points).validate().is_empty());
+
+        let points = vec![
+            CalibrationPoint { rps: 0.0, kmh: 0.0 },
+            CalibrationPoint { rps: 2.0, kmh: 7.0 },
+        ];
+        assert_eq!(
+            table(points.clone()).validate().calibration.unwrap().table,
+            Some(points)
+        );
+    }
+}
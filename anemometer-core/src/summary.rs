@@ -0,0 +1,329 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::data_processing::Gust;
+use crate::wind_rose::CALM_SPEED;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+// Samples are only summarized once the system time has been set by SNTP,
+// before the clock starts at the epoch
+const MIN_VALID_TIME: u64 = 1_577_836_800;
+// Completed summaries kept until they are published, e.g. while the
+// device is offline. Older summaries are dropped.
+pub const MAX_PENDING_SUMMARIES: usize = 48;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SummaryPeriod {
+    Hourly,
+    Daily,
+}
+
+impl SummaryPeriod {
+    // length of the period [s]
+    pub fn length(&self) -> i64 {
+        match self {
+            SummaryPeriod::Hourly => 60 * 60,
+            SummaryPeriod::Daily => 24 * 60 * 60,
+        }
+    }
+}
+
+// Accumulated samples of a running period
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SummaryRecord {
+    pub period: SummaryPeriod,
+    // number of the period since the epoch in local time
+    pub index: i64,
+    // unix time of the start of the period [s]
+    pub start_time: u64,
+    pub samples: u32,
+    pub calm: u32,
+    // sum of all wind speed samples [km/h]
+    pub speed_sum: f64,
+    // highest sample [km/h]
+    pub max_speed: f32,
+    pub peak_gust: Option<Gust>,
+}
+
+impl SummaryRecord {
+    fn new(period: SummaryPeriod, index: i64, utc_offset: i32) -> Self {
+        SummaryRecord {
+            period,
+            index,
+            start_time: (index * period.length() - utc_offset as i64).max(0) as u64,
+            samples: 0,
+            calm: 0,
+            speed_sum: 0.0,
+            max_speed: 0.0,
+            peak_gust: None,
+        }
+    }
+
+    fn add(&mut self, speed: f32, gust: Option<Gust>) {
+        self.samples = self.samples.saturating_add(1);
+        if speed < CALM_SPEED {
+            self.calm = self.calm.saturating_add(1);
+        }
+        self.speed_sum += speed as f64;
+        self.max_speed = self.max_speed.max(speed);
+
+        if let Some(gust) = gust {
+            if !matches!(self.peak_gust, Some(peak) if peak.speed >= gust.speed) {
+                self.peak_gust = Some(gust);
+            }
+        }
+    }
+
+    // sample_interval [ms] is required to integrate the wind run
+    fn report(&self, sample_interval: u64) -> SummaryReport {
+        let samples = self.samples.max(1) as f64;
+
+        SummaryReport {
+            period: self.period,
+            start_time: self.start_time,
+            end_time: self.start_time + self.period.length() as u64,
+            samples: self.samples,
+            mean: (self.speed_sum / samples) as f32,
+            max: self.max_speed,
+            peak_gust: self.peak_gust,
+            wind_run: (self.speed_sum * sample_interval as f64 / 3_600_000.0) as f32,
+            calm_percentage: (self.calm as f64 * 100.0 / samples) as f32,
+        }
+    }
+}
+
+// Summary of a completed period, all speeds are in km/h
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SummaryReport {
+    pub period: SummaryPeriod,
+    // unix time of the start and end of the period [s]
+    pub start_time: u64,
+    pub end_time: u64,
+    pub samples: u32,
+    pub mean: f32,
+    pub max: f32,
+    pub peak_gust: Option<Gust>,
+    // distance the air travelled during the period [km]
+    pub wind_run: f32,
+    // share of the samples below the calm threshold [%]
+    pub calm_percentage: f32,
+}
+
+// State of the summaries which is written to flash, so no data is lost
+// by a reboot (OTA update, watchdog, power loss)
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SummaryCheckpoint {
+    pub hourly: Option<SummaryRecord>,
+    pub daily: Option<SummaryRecord>,
+    pub completed: Vec<SummaryReport>,
+}
+
+// Accumulates hourly and daily summaries. The periods are aligned to the
+// local time, a day rolls over at local midnight. A summary is completed
+// with the first sample of the next period, which may be much later if the
+// device was switched off in between.
+pub struct SummaryTracker {
+    // sampling interval [ms]
+    sample_interval: u64,
+    hourly: Option<SummaryRecord>,
+    daily: Option<SummaryRecord>,
+    completed: VecDeque<SummaryReport>,
+}
+
+impl SummaryTracker {
+    pub fn new(sample_interval: u64) -> Self {
+        SummaryTracker {
+            sample_interval: sample_interval.max(1),
+            hourly: None,
+            daily: None,
+            completed: VecDeque::new(),
+        }
+    }
+
+    // speed [km/h], gust is the current 3 sec running mean, time is the
+    // unix time [s] and utc_offset the offset of the local time zone [s]
+    pub fn store(&mut self, speed: f32, gust: Option<Gust>, time: u64, utc_offset: i32) {
+        if time < MIN_VALID_TIME {
+            return;
+        }
+
+        for (record, period) in [
+            (&mut self.hourly, SummaryPeriod::Hourly),
+            (&mut self.daily, SummaryPeriod::Daily),
+        ] {
+            let index = (time as i64 + utc_offset as i64).div_euclid(period.length());
+
+            match record {
+                Some(current) if current.index == index => (),
+                _ => {
+                    if let Some(completed) = record.take() {
+                        if self.completed.len() == MAX_PENDING_SUMMARIES {
+                            self.completed.pop_front();
+                        }
+                        self.completed
+                            .push_back(completed.report(self.sample_interval));
+                    }
+                    *record = Some(SummaryRecord::new(period, index, utc_offset));
+                }
+            }
+
+            if let Some(record) = record {
+                record.add(speed, gust);
+            }
+        }
+    }
+
+    // Summary of the running period so far
+    pub fn current(&self, period: SummaryPeriod) -> Option<SummaryReport> {
+        let record = match period {
+            SummaryPeriod::Hourly => self.hourly,
+            SummaryPeriod::Daily => self.daily,
+        };
+        record.map(|record| record.report(self.sample_interval))
+    }
+
+    // Returns the summaries completed since the last call, oldest first
+    pub fn take_completed(&mut self) -> Vec<SummaryReport> {
+        self.completed.drain(..).collect()
+    }
+
+    pub fn checkpoint(&self) -> SummaryCheckpoint {
+        SummaryCheckpoint {
+            hourly: self.hourly,
+            daily: self.daily,
+            completed: self.completed.iter().copied().collect(),
+        }
+    }
+
+    pub fn restore(&mut self, checkpoint: SummaryCheckpoint) {
+        self.hourly = checkpoint.hourly;
+        self.daily = checkpoint.daily;
+        self.completed = checkpoint
+            .completed
+            .into_iter()
+            .rev()
+            .take(MAX_PENDING_SUMMARIES)
+            .rev()
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2023-06-01 00:00:00 UTC
+    const MIDNIGHT_UTC: u64 = 1_685_577_600;
+    const CEST: i32 = 2 * 60 * 60;
+
+    #[test]
+    fn hourly_summary_test() {
+        let mut tracker = SummaryTracker::new(250);
+        let gust = Gust {
+            speed: 30.0,
+            time: MIDNIGHT_UTC + 600,
+            direction: 90.0,
+        };
+
+        // one hour at 10 km/h with a single gust, followed by the first
+        // sample of the next hour
+        for i in 0..4 * 3600 {
+            let time = MIDNIGHT_UTC + i / 4;
+            let gust = if time == gust.time { Some(gust) } else { None };
+            tracker.store(10.0, gust, time, 0);
+        }
+        tracker.store(0.0, None, MIDNIGHT_UTC + 3600, 0);
+
+        let completed = tracker.take_completed();
+        assert_eq!(completed.len(), 1);
+        let summary = completed[0];
+        assert_eq!(summary.period, SummaryPeriod::Hourly);
+        assert_eq!(summary.start_time, MIDNIGHT_UTC);
+        assert_eq!(summary.end_time, MIDNIGHT_UTC + 3600);
+        assert_eq!(summary.mean, 10.0);
+        assert_eq!(summary.peak_gust, Some(gust));
+        assert!((summary.wind_run - 10.0).abs() < 0.001);
+        assert_eq!(summary.calm_percentage, 0.0);
+        assert!(tracker.take_completed().is_empty());
+
+        let current = tracker.current(SummaryPeriod::Hourly).unwrap();
+        assert_eq!(current.calm_percentage, 100.0);
+    }
+
+    #[test]
+    fn local_midnight_test() {
+        let mut tracker = SummaryTracker::new(1000);
+
+        // 23:59:59 and 00:00:00 local time (CEST)
+        let local_midnight = MIDNIGHT_UTC - CEST as u64;
+        tracker.store(5.0, None, local_midnight - 1, CEST);
+        tracker.store(5.0, None, local_midnight, CEST);
+
+        let completed = tracker.take_completed();
+        assert_eq!(completed.len(), 2);
+        let daily = completed
+            .iter()
+            .find(|s| s.period == SummaryPeriod::Daily)
+            .unwrap();
+        assert_eq!(daily.start_time, local_midnight - 24 * 3600);
+        assert_eq!(daily.end_time, local_midnight);
+
+        // samples before the clock is set are ignored
+        tracker.store(5.0, None, 100, CEST);
+        assert!(tracker.take_completed().is_empty());
+    }
+
+    #[test]
+    fn checkpoint_test() {
+        let mut tracker = SummaryTracker::new(1000);
+        tracker.store(4.0, None, MIDNIGHT_UTC, 0);
+        tracker.store(0.5, None, MIDNIGHT_UTC + 1, 0);
+        tracker.store(8.0, None, MIDNIGHT_UTC + 3600, 0);
+
+        // reboot
+        let mut restored = SummaryTracker::new(1000);
+        restored.restore(tracker.checkpoint());
+        assert_eq!(restored.checkpoint(), tracker.checkpoint());
+
+        restored.store(2.0, None, MIDNIGHT_UTC + 3601, 0);
+        let hourly = restored.current(SummaryPeriod::Hourly).unwrap();
+        assert_eq!(hourly.samples, 2);
+        assert_eq!(hourly.mean, 5.0);
+        let daily = restored.current(SummaryPeriod::Daily).unwrap();
+        assert_eq!(daily.samples, 4);
+        assert_eq!(daily.calm_percentage, 25.0);
+        assert_eq!(restored.take_completed().len(), 1);
+    }
+}
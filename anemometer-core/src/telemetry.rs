@@ -0,0 +1,354 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::data_processing::{Calibration, WindowStatistics};
+use crate::environment::EnvironmentReport;
+use crate::plausibility::SensorHealth;
+use crate::summary::{SummaryPeriod, SummaryReport};
+use crate::units::SpeedUnit;
+use crate::wind_rose::WindRose;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use serde::Serialize;
+
+// Version of the typed telemetry schema, reported as schemaVersion
+pub const TELEMETRY_SCHEMA_VERSION: u8 = 2;
+// Unit of all reported directions
+pub const DIRECTION_UNIT: &str = "deg";
+// Unit of the wind run of the summaries
+pub const DISTANCE_UNIT: &str = "km";
+
+// Format of the published wind data. Legacy is the original shadow update
+// with all values as strings (comma as decimal separator) and local time
+// stamps, which is still used by the existing web app.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TelemetrySchema {
+    Legacy,
+    #[default]
+    Typed,
+}
+
+impl TelemetrySchema {
+    pub fn from_version(version: u8) -> Option<Self> {
+        match version {
+            1 => Some(Self::Legacy),
+            TELEMETRY_SCHEMA_VERSION => Some(Self::Typed),
+            _ => None,
+        }
+    }
+
+    pub fn version(&self) -> u8 {
+        match self {
+            Self::Legacy => 1,
+            Self::Typed => TELEMETRY_SCHEMA_VERSION,
+        }
+    }
+}
+
+// Reported state of the device shadow in the typed schema. Speeds are given
+// in speedUnit, the window statistics in windowsUnit, directions in degree.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WindReport<'a> {
+    pub schema_version: u8,
+    pub device_id: &'a str,
+    // RFC 3339 UTC
+    pub timestamp: String,
+    pub epoch_time: u64,
+    pub boot_timestamp: String,
+    pub firmware_version: &'a str,
+    pub speed_unit: &'a str,
+    pub direction_unit: &'a str,
+    pub speed: f32,
+    pub direction: f32,
+    pub direction_text: &'a str,
+    pub gust: f32,
+    pub gust_direction: f32,
+    // no gust is available if less than 3 sec have been sampled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gust_timestamp: Option<String>,
+    pub windows_unit: &'a str,
+    pub windows: &'a [WindowStatistics],
+    pub calibration: &'a Calibration,
+    pub sensor_health: SensorHealth,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub environment: Option<EnvironmentReport>,
+}
+
+// Published to <topic_prefix>/<device_id>/wind/speed
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WindSpeedReport<'a> {
+    pub schema_version: u8,
+    pub device_id: &'a str,
+    pub timestamp: String,
+    pub speed: f32,
+    pub gust: f32,
+    pub unit: &'a str,
+}
+
+// Published to <topic_prefix>/<device_id>/wind/direction
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WindDirectionReport<'a> {
+    pub schema_version: u8,
+    pub device_id: &'a str,
+    pub timestamp: String,
+    pub direction: f32,
+    pub direction_text: &'a str,
+    pub unit: &'a str,
+}
+
+// Published to <topic_prefix>/<device_id>/wind/rose in the typed schema.
+// The speed bins are given in speedUnit, which is a linear unit.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WindRoseReport<'a> {
+    pub schema_version: u8,
+    pub device_id: &'a str,
+    pub start_timestamp: String,
+    // accumulation period [s]
+    pub period: u64,
+    pub speed_unit: &'a str,
+    pub bins: Vec<f32>,
+    pub samples: u32,
+    pub calm: u32,
+    pub counts: &'a [Vec<u32>],
+}
+
+impl<'a> WindRoseReport<'a> {
+    pub fn new(device_id: &'a str, wind_rose: &'a WindRose, speed_unit: SpeedUnit) -> Self {
+        let unit = speed_unit.linear_unit();
+        WindRoseReport {
+            schema_version: TELEMETRY_SCHEMA_VERSION,
+            device_id,
+            start_timestamp: format_rfc3339(wind_rose.start_time),
+            period: wind_rose.period,
+            speed_unit: unit.symbol(),
+            bins: wind_rose
+                .bins
+                .iter()
+                .map(|bin| round_decimals(unit.convert(*bin), unit.decimals()))
+                .collect(),
+            samples: wind_rose.samples,
+            calm: wind_rose.calm,
+            counts: &wind_rose.counts,
+        }
+    }
+}
+
+// Published to <topic_prefix>/<device_id>/summary/hourly or .../daily in
+// the typed schema. Speeds are given in speedUnit, which is a linear unit.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WindSummaryReport<'a> {
+    pub schema_version: u8,
+    pub device_id: &'a str,
+    pub period: SummaryPeriod,
+    pub start_timestamp: String,
+    pub end_timestamp: String,
+    pub samples: u32,
+    pub speed_unit: &'a str,
+    pub direction_unit: &'a str,
+    pub distance_unit: &'a str,
+    pub mean: f32,
+    pub max: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gust: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gust_direction: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gust_timestamp: Option<String>,
+    pub wind_run: f32,
+    pub calm_percentage: f32,
+}
+
+impl<'a> WindSummaryReport<'a> {
+    pub fn new(device_id: &'a str, summary: &SummaryReport, speed_unit: SpeedUnit) -> Self {
+        let unit = speed_unit.linear_unit();
+        let speed = |kmh| round_decimals(unit.convert(kmh), unit.decimals());
+        WindSummaryReport {
+            schema_version: TELEMETRY_SCHEMA_VERSION,
+            device_id,
+            period: summary.period,
+            start_timestamp: format_rfc3339(summary.start_time),
+            end_timestamp: format_rfc3339(summary.end_time),
+            samples: summary.samples,
+            speed_unit: unit.symbol(),
+            direction_unit: DIRECTION_UNIT,
+            distance_unit: DISTANCE_UNIT,
+            mean: speed(summary.mean),
+            max: speed(summary.max),
+            gust: summary.peak_gust.map(|gust| speed(gust.speed)),
+            gust_direction: summary
+                .peak_gust
+                .map(|gust| round_decimals(gust.direction, 1)),
+            gust_timestamp: summary.peak_gust.map(|gust| format_rfc3339(gust.time)),
+            wind_run: round_decimals(summary.wind_run, 2),
+            calm_percentage: round_decimals(summary.calm_percentage, 1),
+        }
+    }
+}
+
+// Rounds a value for publishing, avoids numbers like 12.300000190734863
+// in the JSON documents
+pub fn round_decimals(value: f32, decimals: usize) -> f32 {
+    let factor = libm::powf(10.0, decimals as f32);
+    libm::roundf(value * factor) / factor
+}
+
+// Formats a unix time [s] as RFC 3339 UTC time stamp, 2023-04-01T10:00:00Z
+pub fn format_rfc3339(unixtime: u64) -> String {
+    let days = (unixtime / 86_400) as i64;
+    let secs = unixtime % 86_400;
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+// Converts days since 1970-01-01 into year, month and day of the proleptic
+// Gregorian calendar (H. Hinnant, chrono-compatible low-level date algorithms)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_processing::Gust;
+
+    #[test]
+    fn rfc3339_test() {
+        assert_eq!(format_rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_rfc3339(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_rfc3339(1_680_343_199), "2023-04-01T09:59:59Z");
+        assert_eq!(format_rfc3339(1_704_067_199), "2023-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn wind_report_test() {
+        let calibration = Calibration::default();
+        let report = WindReport {
+            schema_version: TELEMETRY_SCHEMA_VERSION,
+            device_id: "device-1",
+            timestamp: format_rfc3339(1_680_343_200),
+            epoch_time: 1_680_343_200,
+            boot_timestamp: format_rfc3339(1_680_300_000),
+            firmware_version: "0.1.34",
+            speed_unit: "km/h",
+            direction_unit: DIRECTION_UNIT,
+            speed: round_decimals(12.345_678, 2),
+            direction: round_decimals(271.26, 1),
+            direction_text: "W",
+            gust: 20.5,
+            gust_direction: 280.0,
+            gust_timestamp: None,
+            windows_unit: "km/h",
+            windows: &[],
+            calibration: &calibration,
+            sensor_health: SensorHealth::default(),
+            environment: None,
+        };
+        let json = serde_json::to_value(&report).unwrap();
+
+        assert_eq!(json["schemaVersion"], 2);
+        assert_eq!(json["timestamp"], "2023-04-01T10:00:00Z");
+        assert_eq!(json["speed"].as_f64().unwrap() as f32, 12.35);
+        assert_eq!(json["direction"].as_f64().unwrap() as f32, 271.3);
+        assert!(json.get("gustTimestamp").is_none());
+        assert_eq!(
+            TelemetrySchema::from_version(1),
+            Some(TelemetrySchema::Legacy)
+        );
+        assert_eq!(TelemetrySchema::from_version(3), None);
+    }
+
+    #[test]
+    fn wind_rose_report_test() {
+        let mut wind_rose = WindRose::new(3600, &[36.0, 72.0]);
+        wind_rose.add(50.0, 90, 1_680_343_200);
+        let report = WindRoseReport::new("device-1", &wind_rose, SpeedUnit::Beaufort);
+        let json = serde_json::to_value(&report).unwrap();
+
+        assert_eq!(json["schemaVersion"], 2);
+        assert_eq!(json["startTimestamp"], "2023-04-01T10:00:00Z");
+        assert_eq!(json["speedUnit"], "m/s");
+        assert_eq!(json["bins"], serde_json::json!([10.0, 20.0]));
+        assert_eq!(json["samples"], 1);
+    }
+
+    #[test]
+    fn summary_report_test() {
+        let summary = SummaryReport {
+            period: SummaryPeriod::Hourly,
+            start_time: 1_680_343_200,
+            end_time: 1_680_346_800,
+            samples: 14_400,
+            mean: 18.0,
+            max: 36.0,
+            peak_gust: Some(Gust {
+                speed: 54.0,
+                time: 1_680_344_000,
+                direction: 271.26,
+            }),
+            wind_run: 18.0,
+            calm_percentage: 2.5,
+        };
+        let report = WindSummaryReport::new("device-1", &summary, SpeedUnit::MetersPerSecond);
+        let json = serde_json::to_value(&report).unwrap();
+
+        assert_eq!(json["schemaVersion"], 2);
+        assert_eq!(json["period"], "hourly");
+        assert_eq!(json["endTimestamp"], "2023-04-01T11:00:00Z");
+        assert_eq!(json["speedUnit"], "m/s");
+        assert_eq!(json["distanceUnit"], "km");
+        assert_eq!(json["mean"].as_f64().unwrap() as f32, 5.0);
+        assert_eq!(json["gust"].as_f64().unwrap() as f32, 15.0);
+        assert_eq!(json["gustDirection"].as_f64().unwrap() as f32, 271.3);
+        assert_eq!(json["gustTimestamp"], "2023-04-01T10:13:20Z");
+    }
+}
@@ -0,0 +1,232 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use core::str::FromStr;
+use serde::Serialize;
+
+// Beaufort scale lower limits of force 1 to 12 [m/s] as given in
+// WMO-No. 306 Manual on Codes
+const BEAUFORT_LIMITS: [f32; 12] = [
+    0.3, 1.6, 3.4, 5.5, 8.0, 10.8, 13.9, 17.2, 20.8, 24.5, 28.5, 32.7,
+];
+
+const COMPASS_POINTS_16: [&str; 16] = [
+    "N", "NNE", "NE", "ENE", "E", "ESE", "SE", "SSE", "S", "SSW", "SW", "WSW", "W", "WNW", "NW",
+    "NNW",
+];
+
+const COMPASS_POINTS_8: [&str; 8] = ["N", "NE", "E", "SE", "S", "SW", "W", "NW"];
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub enum SpeedUnit {
+    MetersPerSecond,
+    #[default]
+    KilometersPerHour,
+    Knots,
+    MilesPerHour,
+    Beaufort,
+}
+
+impl SpeedUnit {
+    // Converts a wind speed given in km/h into this unit
+    pub fn convert(&self, kmh: f32) -> f32 {
+        match self {
+            Self::Beaufort => beaufort(kmh) as f32,
+            _ => kmh * self.linear_unit().factor(),
+        }
+    }
+
+    // Beaufort is a scale and can't be used for values like the standard
+    // deviation, m/s is used instead in this case.
+    pub fn linear_unit(&self) -> SpeedUnit {
+        match self {
+            Self::Beaufort => Self::MetersPerSecond,
+            unit => *unit,
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Self::MetersPerSecond => "m/s",
+            Self::KilometersPerHour => "km/h",
+            Self::Knots => "kn",
+            Self::MilesPerHour => "mph",
+            Self::Beaufort => "Bft",
+        }
+    }
+
+    // Number of decimal places used for published values
+    pub fn decimals(&self) -> usize {
+        match self {
+            Self::Beaufort => 0,
+            _ => 2,
+        }
+    }
+
+    fn factor(&self) -> f32 {
+        match self {
+            Self::MetersPerSecond => 1.0 / 3.6,
+            Self::KilometersPerHour => 1.0,
+            Self::Knots => 1.0 / 1.852,
+            Self::MilesPerHour => 1.0 / 1.609_344,
+            Self::Beaufort => unreachable!(),
+        }
+    }
+}
+
+impl FromStr for SpeedUnit {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "m/s" | "ms" | "mps" => Ok(Self::MetersPerSecond),
+            "km/h" | "kmh" | "kph" => Ok(Self::KilometersPerHour),
+            "kn" | "kt" | "knots" => Ok(Self::Knots),
+            "mph" => Ok(Self::MilesPerHour),
+            "bft" | "beaufort" => Ok(Self::Beaufort),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum CompassPoints {
+    Eight,
+    #[default]
+    Sixteen,
+}
+
+impl CompassPoints {
+    pub fn from_count(count: u8) -> Option<Self> {
+        match count {
+            8 => Some(Self::Eight),
+            16 => Some(Self::Sixteen),
+            _ => None,
+        }
+    }
+
+    pub fn count(&self) -> u8 {
+        match self {
+            Self::Eight => 8,
+            Self::Sixteen => 16,
+        }
+    }
+
+    // Name of the compass point for a direction given in degree
+    pub fn name(&self, direction: f32) -> &'static str {
+        let names: &[&'static str] = match self {
+            Self::Eight => &COMPASS_POINTS_8,
+            Self::Sixteen => &COMPASS_POINTS_16,
+        };
+        let sector = 360.0 / names.len() as f32;
+        let idx = (normalize_degrees(direction) / sector + 0.5) as usize % names.len();
+
+        names[idx]
+    }
+}
+
+// Maps an angle given in degree into the range [0, 360)
+pub fn normalize_degrees(angle: f32) -> f32 {
+    let angle = angle % 360.0;
+    if angle < 0.0 {
+        angle + 360.0
+    } else {
+        angle
+    }
+}
+
+// Beaufort force for a wind speed given in km/h
+pub fn beaufort(kmh: f32) -> u8 {
+    let ms = kmh / 3.6;
+
+    BEAUFORT_LIMITS.iter().filter(|limit| ms >= **limit).count() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 0.001, "{a} != {b}");
+    }
+
+    #[test]
+    fn speed_conversion_test() {
+        assert_close(SpeedUnit::KilometersPerHour.convert(36.0), 36.0);
+        assert_close(SpeedUnit::MetersPerSecond.convert(36.0), 10.0);
+        assert_close(SpeedUnit::Knots.convert(18.52), 10.0);
+        assert_close(SpeedUnit::MilesPerHour.convert(16.09344), 10.0);
+        assert_close(SpeedUnit::Beaufort.convert(36.0), 5.0);
+    }
+
+    #[test]
+    fn beaufort_test() {
+        assert_eq!(beaufort(0.0), 0);
+        assert_eq!(beaufort(0.2 * 3.6), 0);
+        assert_eq!(beaufort(0.3 * 3.6), 1);
+        assert_eq!(beaufort(5.4 * 3.6), 3);
+        assert_eq!(beaufort(5.5 * 3.6), 4);
+        assert_eq!(beaufort(32.6 * 3.6), 11);
+        assert_eq!(beaufort(32.7 * 3.6), 12);
+        assert_eq!(beaufort(200.0), 12);
+    }
+
+    #[test]
+    fn speed_unit_from_str_test() {
+        assert_eq!("km/h".parse(), Ok(SpeedUnit::KilometersPerHour));
+        assert_eq!("M/S".parse(), Ok(SpeedUnit::MetersPerSecond));
+        assert_eq!(" knots".parse(), Ok(SpeedUnit::Knots));
+        assert_eq!("mph".parse(), Ok(SpeedUnit::MilesPerHour));
+        assert_eq!("Beaufort".parse(), Ok(SpeedUnit::Beaufort));
+        assert_eq!("furlongs".parse::<SpeedUnit>(), Err(()));
+    }
+
+    #[test]
+    fn compass_points_test() {
+        let points = CompassPoints::Sixteen;
+        assert_eq!(points.name(0.0), "N");
+        assert_eq!(points.name(11.0), "N");
+        assert_eq!(points.name(12.0), "NNE");
+        assert_eq!(points.name(90.0), "E");
+        assert_eq!(points.name(200.0), "SSW");
+        assert_eq!(points.name(348.0), "NNW");
+        assert_eq!(points.name(349.0), "N");
+        assert_eq!(points.name(355.0), "N");
+        assert_eq!(points.name(-10.0), "N");
+
+        let points = CompassPoints::Eight;
+        assert_eq!(points.name(22.0), "N");
+        assert_eq!(points.name(23.0), "NE");
+        assert_eq!(points.name(180.0), "S");
+        assert_eq!(points.name(338.0), "N");
+        assert_eq!(points.name(337.0), "NW");
+    }
+}
@@ -0,0 +1,262 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::units::normalize_degrees;
+use alloc::vec;
+use alloc::vec::Vec;
+use serde::Serialize;
+
+// Number of direction sectors of the wind rose (N, NNE, NE, ...)
+pub const WIND_ROSE_SECTORS: usize = 16;
+// Samples below this speed [km/h] are counted as calm and are not
+// assigned to a sector (Beaufort 0)
+pub const CALM_SPEED: f32 = 1.0;
+// Default accumulation period [s]
+pub const DEFAULT_WIND_ROSE_PERIOD: u64 = 24 * 60 * 60;
+// Default upper limits of the speed bins [km/h]
+pub const DEFAULT_WIND_ROSE_BINS: [f32; 5] = [5.0, 10.0, 20.0, 30.0, 40.0];
+
+// Histogram of the wind by direction sector and speed class. The speed
+// bins are given by their upper limits [km/h], the last bin is open ended.
+// counts[sector][bin] holds the number of samples, sector 0 is centered
+// around north.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WindRose {
+    // accumulation period [s]
+    pub period: u64,
+    // unix time of the first sample [s]
+    pub start_time: u64,
+    pub bins: Vec<f32>,
+    pub samples: u32,
+    pub calm: u32,
+    pub counts: Vec<Vec<u32>>,
+}
+
+impl WindRose {
+    pub fn new(period: u64, bins: &[f32]) -> Self {
+        WindRose {
+            period,
+            start_time: 0,
+            bins: bins.to_vec(),
+            samples: 0,
+            calm: 0,
+            counts: vec![vec![0; bins.len() + 1]; WIND_ROSE_SECTORS],
+        }
+    }
+
+    // speed [km/h], direction in degree, time is the unix time [s]
+    pub fn add(&mut self, speed: f32, direction: u16, time: u64) {
+        if self.samples == 0 {
+            self.start_time = time;
+        }
+        self.samples = self.samples.saturating_add(1);
+
+        if speed < CALM_SPEED {
+            self.calm = self.calm.saturating_add(1);
+            return;
+        }
+
+        let sector_size = 360.0 / WIND_ROSE_SECTORS as f32;
+        let sector =
+            (normalize_degrees(direction as f32) / sector_size + 0.5) as usize % WIND_ROSE_SECTORS;
+        let bin = self
+            .bins
+            .iter()
+            .position(|limit| speed < *limit)
+            .unwrap_or(self.bins.len());
+
+        self.counts[sector][bin] = self.counts[sector][bin].saturating_add(1);
+    }
+
+    // Starts a new accumulation period with the same settings
+    pub fn reset(&mut self) {
+        *self = WindRose::new(self.period, &self.bins);
+    }
+
+    // Share of the samples in the given sector and bin [%]
+    pub fn frequency(&self, sector: usize, bin: usize) -> f32 {
+        if self.samples == 0 {
+            return 0.0;
+        }
+        self.counts[sector][bin] as f32 * 100.0 / self.samples as f32
+    }
+
+    // Parses speed bins in the format "5;10;20;30". The limits need to be
+    // strictly increasing and positive.
+    pub fn parse_bins(bins: &str) -> Option<Vec<f32>> {
+        let mut limits: Vec<f32> = Vec::new();
+
+        for limit in bins.split(';').map(str::trim).filter(|l| !l.is_empty()) {
+            let limit: f32 = limit.parse().ok()?;
+            if limit <= 0.0 || matches!(limits.last(), Some(last) if limit <= *last) {
+                return None;
+            }
+            limits.push(limit);
+        }
+
+        if limits.is_empty() {
+            None
+        } else {
+            Some(limits)
+        }
+    }
+}
+
+impl Default for WindRose {
+    fn default() -> Self {
+        Self::new(DEFAULT_WIND_ROSE_PERIOD, &DEFAULT_WIND_ROSE_BINS)
+    }
+}
+
+// Accumulates the wind rose of the running period and keeps the last
+// completed one. The period is measured by the number of samples, as the
+// system time may not be valid yet.
+pub struct WindRoseTracker {
+    // sampling interval [ms]
+    sample_interval: u64,
+    current: WindRose,
+    last: Option<WindRose>,
+    pending: bool,
+}
+
+impl WindRoseTracker {
+    pub fn new(sample_interval: u64) -> Self {
+        WindRoseTracker {
+            sample_interval: sample_interval.max(1),
+            current: WindRose::default(),
+            last: None,
+            pending: false,
+        }
+    }
+
+    // Restarts the accumulation with a new period [s] and new speed bins
+    // [km/h]
+    pub fn configure(&mut self, period: u64, bins: &[f32]) {
+        self.current = WindRose::new(period, bins);
+        self.last = None;
+        self.pending = false;
+    }
+
+    // speed [km/h], direction in degree, time is the unix time [s]
+    pub fn add(&mut self, speed: f32, direction: u16, time: u64) {
+        self.current.add(speed, direction, time);
+        if self.current.samples as u64 >= (self.current.period * 1000 / self.sample_interval).max(1)
+        {
+            self.last = Some(self.current.clone());
+            self.current.reset();
+            self.pending = true;
+        }
+    }
+
+    // Wind rose of the running period
+    pub fn current(&self) -> &WindRose {
+        &self.current
+    }
+
+    // Wind rose of the last completed period
+    pub fn last(&self) -> Option<&WindRose> {
+        self.last.as_ref()
+    }
+
+    // Returns the last completed wind rose once after its period completed
+    pub fn take_completed(&mut self) -> Option<WindRose> {
+        if self.pending {
+            self.pending = false;
+            self.last.clone()
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wind_rose_test() {
+        let mut rose = WindRose::new(3600, &[10.0, 20.0]);
+
+        rose.add(5.0, 0, 1000);
+        rose.add(15.0, 350, 1001);
+        rose.add(25.0, 90, 1002);
+        rose.add(25.0, 100, 1003);
+        rose.add(0.5, 180, 1004);
+
+        assert_eq!(rose.start_time, 1000);
+        assert_eq!(rose.samples, 5);
+        assert_eq!(rose.calm, 1);
+        assert_eq!(rose.counts[0], vec![1, 1, 0]);
+        // 90° and 100° are both east (78.75° - 101.25°)
+        assert_eq!(rose.counts[4], vec![0, 0, 2]);
+        assert_eq!(rose.counts[8], vec![0, 0, 0]);
+        assert_eq!(rose.frequency(4, 2), 40.0);
+
+        rose.reset();
+        assert_eq!(rose, WindRose::new(3600, &[10.0, 20.0]));
+    }
+
+    #[test]
+    fn wind_rose_period_test() {
+        let mut tracker = WindRoseTracker::new(250);
+        tracker.configure(60, &[10.0]);
+
+        for i in 0..239 {
+            tracker.add(1.0, 90, 1000 + i / 4);
+        }
+        assert_eq!(tracker.current().samples, 239);
+        assert_eq!(tracker.take_completed(), None);
+
+        tracker.add(20.0, 90, 1060);
+        assert_eq!(tracker.current().samples, 0);
+
+        let rose = tracker.take_completed().unwrap();
+        assert_eq!(rose.samples, 240);
+        assert_eq!(rose.start_time, 1000);
+        assert_eq!(rose.counts[4], vec![239, 1]);
+        assert_eq!(rose.calm, 0);
+        assert_eq!(tracker.take_completed(), None);
+        assert_eq!(tracker.last(), Some(&rose));
+    }
+
+    #[test]
+    fn parse_bins_test() {
+        assert_eq!(
+            WindRose::parse_bins("5; 10;20.5;"),
+            Some(vec![5.0, 10.0, 20.5])
+        );
+        assert_eq!(WindRose::parse_bins(""), None);
+        assert_eq!(WindRose::parse_bins("10;5"), None);
+        assert_eq!(WindRose::parse_bins("0;5"), None);
+        assert_eq!(WindRose::parse_bins("5;x"), None);
+    }
+}
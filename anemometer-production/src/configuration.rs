@@ -29,13 +29,26 @@
  * See the License for the specific language governing permissions and
  * limitations under the License.
  */
+use crate::data_processing::Calibration;
+use crate::global_settings::{DATA_REPORTING_INTERVAL, DEFAULT_TIMEZONE};
+use crate::units::{CompassPoints, SpeedUnit};
 use crate::utils::nvs_ext::*;
+use anemometer_core::auth::{from_hex, DEFAULT_AUTH_WINDOW};
+use anemometer_core::homeassistant::HOME_ASSISTANT_DISCOVERY_PREFIX;
+use anemometer_core::shadow::ShadowConfig;
+use anemometer_core::telemetry::TelemetrySchema;
+use anemometer_core::wind_rose::{WindRose, DEFAULT_WIND_ROSE_BINS, DEFAULT_WIND_ROSE_PERIOD};
 use esp_idf_svc::nvs::*;
 use esp_idf_sys::*;
 use log::*;
 
 const NVS_STRING_READ_BUFFER_SIZE: usize = 180;
 
+// Namespaces of the conf partition with settings which can be changed at
+// runtime. The provisioning data (certificates, AWS and broker settings)
+// and the calibration of the anemometer survive a factory reset.
+const RUNTIME_SETTINGS_NAMESPACES: [&str; 5] = ["reporting", "units", "windrose", "log", "time"];
+
 #[derive(Debug)]
 pub struct AwsIoTSettings {
     pub things_prefix: String,
@@ -51,6 +64,76 @@ pub struct AwsIoTSettings {
     pub credential_provider_endpoint: String,
 }
 
+// Key and replay window [s] of the command authentication, commands are
+// accepted without signature if no key is configured
+#[derive(Clone)]
+pub struct CommandAuthSettings {
+    pub key: Vec<u8>,
+    pub window: u64,
+}
+
+// Units used for all published wind data (shadow update, web page)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UnitSettings {
+    pub speed_unit: SpeedUnit,
+    pub compass_points: CompassPoints,
+}
+
+// Accumulation period [s] and speed bins [km/h] of the wind rose
+#[derive(Debug, Clone)]
+pub struct WindRoseSettings {
+    pub period: u64,
+    pub bins: Vec<f32>,
+}
+
+// Local time zone as POSIX TZ string, used for the time stamps of the
+// published data and the rollover of the daily summaries
+#[derive(Debug, Clone)]
+pub struct TimeSettings {
+    pub timezone: String,
+}
+
+// Interval [s] in which the wind data is reported and the format of the
+// reported data
+#[derive(Debug, Clone, Copy)]
+pub struct ReportingSettings {
+    pub interval: u64,
+    pub schema: TelemetrySchema,
+}
+
+// Maximum level of the log output
+#[derive(Debug, Clone, Copy)]
+pub struct LogSettings {
+    pub level: LevelFilter,
+}
+
+// Transport, authentication and topic layout of the MQTT connection. The
+// defaults connect to AWS IoT Core, a generic broker like Mosquitto or EMQX
+// is selected by the url.
+pub struct BrokerSettings {
+    pub url: String,
+    pub client_id: String,
+    pub auth: BrokerAuth,
+    pub username: String,
+    pub password: String,
+    // nul terminated PEM, the ESP x509 certificate bundle is used if empty
+    pub ca_cert: Vec<u8>,
+    pub things_prefix: String,
+    pub topic_prefix: String,
+    // Home Assistant MQTT discovery, only used with a generic broker
+    pub home_assistant: bool,
+    pub discovery_prefix: String,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BrokerAuth {
+    // client certificate and private key of the certificates namespace
+    #[default]
+    Certificate,
+    Password,
+    None,
+}
+
 #[derive(Debug)]
 pub struct AwsIoTCertificates {
     pub device_cert: Vec<u8>,
@@ -175,6 +258,570 @@ impl AwsIoTCertificates {
     }
 }
 
+impl BrokerSettings {
+    // The broker settings are stored in the namespace "broker" of the conf
+    // partition, without the namespace the device connects to AWS IoT Core:
+    //
+    // url            string  mqtt://host:1883 for plain TCP or mqtts://host:8883
+    //                        (default AWS IoT endpoint of the certificates)
+    // client_id      string  MQTT client id (default device id)
+    // auth           string  mtls, password or none (default mtls)
+    // username       string  user name of the password authentication
+    // password       string  password of the password authentication
+    // ca_cert        string  PEM CA certificate of the broker (default ESP
+    //                        certificate bundle)
+    // things_prefix  string  prefix of the shadow and job topics (default
+    //                        aws_settings)
+    // topic_prefix   string  prefix of the data topics (default aws_settings)
+    // ha_discovery   u8      0 disables the Home Assistant discovery (default 1)
+    // ha_prefix      string  Home Assistant discovery prefix (default
+    //                        homeassistant)
+    pub fn new(partition: &str) -> Result<Self, EspError> {
+        let mut settings = BrokerSettings::default();
+        let part = EspCustomNvsPartition::take(partition)?;
+
+        let nvs = match EspCustomNvs::new(part, "broker", false) {
+            Ok(nvs) => nvs,
+            Err(err) => {
+                info!("No broker settings found, connecting to AWS IoT Core: {err}");
+                return Ok(settings);
+            }
+        };
+
+        settings.url = get_string_from_nvs(&nvs, "url")?;
+        settings.client_id = get_string_from_nvs(&nvs, "client_id")?;
+        settings.username = get_string_from_nvs(&nvs, "username")?;
+        settings.password = get_string_from_nvs(&nvs, "password")?;
+        settings.things_prefix = get_string_from_nvs(&nvs, "things_prefix")?;
+        settings.topic_prefix = get_string_from_nvs(&nvs, "topic_prefix")?;
+
+        let mut discovery: u8 = 0;
+        if let Some(discovery) = nvs.get_u8("ha_discovery", &mut discovery)? {
+            settings.home_assistant = *discovery != 0;
+        }
+        let discovery_prefix = get_string_from_nvs(&nvs, "ha_prefix")?;
+        if !discovery_prefix.is_empty() {
+            settings.discovery_prefix = discovery_prefix;
+        }
+
+        let auth = get_string_from_nvs(&nvs, "auth")?;
+        match auth.as_str() {
+            "" | "mtls" => settings.auth = BrokerAuth::Certificate,
+            "password" => settings.auth = BrokerAuth::Password,
+            "none" => settings.auth = BrokerAuth::None,
+            _ => warn!("Invalid broker authentication \"{auth}\", using mtls"),
+        }
+        if settings.auth == BrokerAuth::Password && settings.username.is_empty() {
+            warn!("Broker password authentication without user name");
+        }
+        if settings.url.starts_with("mqtt://") && settings.auth == BrokerAuth::Certificate {
+            warn!("Client certificate is not used on a plain TCP connection");
+        }
+
+        if let Some(l) = nvs.len_str("ca_cert")? {
+            match settings.ca_cert.try_reserve(l + 2) {
+                Ok(_) => {
+                    settings.ca_cert.resize(l + 1, 0);
+                    nvs.get_str("ca_cert", &mut settings.ca_cert[..])?;
+                }
+                Err(err) => {
+                    warn!("Failed to reserve memory for broker CA certificate: {err}");
+                }
+            }
+        }
+
+        info!(
+            "Broker settings: url = \"{}\", client id = \"{}\", auth = {:?}, custom CA = {}, Home Assistant = {}",
+            settings.url,
+            settings.client_id,
+            settings.auth,
+            !settings.ca_cert.is_empty(),
+            settings.home_assistant_discovery()
+        );
+
+        Ok(settings)
+    }
+
+    // Device shadow and jobs are only available with AWS IoT Core, their
+    // topics are not subscribed on a generic broker
+    pub fn is_aws_iot(&self) -> bool {
+        self.url.is_empty()
+    }
+
+    pub fn home_assistant_discovery(&self) -> bool {
+        !self.is_aws_iot() && self.home_assistant
+    }
+
+    // Replaces the topic prefixes of the AWS settings, all topics are
+    // derived from these
+    pub fn apply_topic_layout(&self, aws_config: &mut AwsIoTSettings) {
+        if !self.things_prefix.is_empty() {
+            aws_config.things_prefix = self.things_prefix.clone();
+        }
+        if !self.topic_prefix.is_empty() {
+            aws_config.topic_prefix = self.topic_prefix.clone();
+        }
+    }
+}
+
+impl Default for BrokerSettings {
+    fn default() -> Self {
+        BrokerSettings {
+            url: String::new(),
+            client_id: String::new(),
+            auth: BrokerAuth::default(),
+            username: String::new(),
+            password: String::new(),
+            ca_cert: Vec::new(),
+            things_prefix: String::new(),
+            topic_prefix: String::new(),
+            home_assistant: true,
+            discovery_prefix: String::from(HOME_ASSISTANT_DISCOVERY_PREFIX),
+        }
+    }
+}
+
+// the password must not end up in the log
+impl core::fmt::Debug for BrokerSettings {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("BrokerSettings")
+            .field("url", &self.url)
+            .field("client_id", &self.client_id)
+            .field("auth", &self.auth)
+            .field("username", &self.username)
+            .field("password", &(!self.password.is_empty()).then_some("***"))
+            .field("ca_cert", &format_args!("{} bytes", self.ca_cert.len()))
+            .field("things_prefix", &self.things_prefix)
+            .field("topic_prefix", &self.topic_prefix)
+            .field("home_assistant", &self.home_assistant)
+            .field("discovery_prefix", &self.discovery_prefix)
+            .finish()
+    }
+}
+
+impl CommandAuthSettings {
+    // The command authentication is stored in the namespace "cmd_auth" of
+    // the conf partition:
+    //
+    // hmac_key  string  hex encoded HMAC-SHA256 key, at least 16 bytes
+    // window    u32     maximum age of a command [s] (default 300 s)
+    pub fn new(partition: &str) -> Result<Self, EspError> {
+        let mut settings = CommandAuthSettings::default();
+        let part = EspCustomNvsPartition::take(partition)?;
+
+        let nvs = match EspCustomNvs::new(part, "cmd_auth", false) {
+            Ok(nvs) => nvs,
+            Err(err) => {
+                warn!("No command authentication key found, commands are not authenticated: {err}");
+                return Ok(settings);
+            }
+        };
+
+        let key = get_string_from_nvs(&nvs, "hmac_key")?;
+        match from_hex(&key) {
+            Some(key) if key.len() >= 16 => settings.key = key,
+            _ => warn!("Invalid command authentication key, commands are not authenticated"),
+        }
+
+        let mut window: u32 = 0;
+        if let Some(window) = nvs.get_u32("window", &mut window)? {
+            if *window > 0 {
+                settings.window = *window as u64;
+            } else {
+                warn!("Invalid command authentication window {window}, using default");
+            }
+        }
+
+        info!(
+            "Command authentication: {}, window = {} s",
+            settings.is_enabled(),
+            settings.window
+        );
+
+        Ok(settings)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.key.is_empty()
+    }
+}
+
+impl Default for CommandAuthSettings {
+    fn default() -> Self {
+        CommandAuthSettings {
+            key: Vec::new(),
+            window: DEFAULT_AUTH_WINDOW,
+        }
+    }
+}
+
+// the key must not end up in the log
+impl core::fmt::Debug for CommandAuthSettings {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("CommandAuthSettings")
+            .field("enabled", &self.is_enabled())
+            .field("window", &self.window)
+            .finish()
+    }
+}
+
+impl UnitSettings {
+    // The units are stored in the namespace "units" of the conf partition:
+    //
+    // speed_unit  string  m/s, km/h, kn, mph or bft (default km/h)
+    // compass     u8      number of compass points 8 or 16 (default 16)
+    pub fn new(partition: &str) -> Result<Self, EspError> {
+        let mut settings = UnitSettings::default();
+        let part = EspCustomNvsPartition::take(partition)?;
+
+        let nvs = match EspCustomNvs::new(part, "units", false) {
+            Ok(nvs) => nvs,
+            Err(err) => {
+                warn!("No unit settings found, using defaults: {err}");
+                return Ok(settings);
+            }
+        };
+
+        let speed_unit = get_string_from_nvs(&nvs, "speed_unit")?;
+        if !speed_unit.is_empty() {
+            match speed_unit.parse() {
+                Ok(unit) => settings.speed_unit = unit,
+                Err(_) => warn!("Invalid speed unit \"{speed_unit}\", using default"),
+            }
+        }
+
+        let mut compass: u8 = 0;
+        if let Some(compass) = nvs.get_u8("compass", &mut compass)? {
+            match CompassPoints::from_count(*compass) {
+                Some(points) => settings.compass_points = points,
+                None => warn!("Invalid number of compass points {compass}, using default"),
+            }
+        }
+
+        info!("Unit settings: {:?}", settings);
+
+        Ok(settings)
+    }
+}
+
+impl WindRoseSettings {
+    // The wind rose settings are stored in the namespace "windrose" of the
+    // conf partition:
+    //
+    // period  u32     accumulation period [s] (default 24 h)
+    // bins    string  upper limits of the speed bins [km/h] "5;10;20;30;40"
+    pub fn new(partition: &str) -> Result<Self, EspError> {
+        let mut settings = WindRoseSettings::default();
+        let part = EspCustomNvsPartition::take(partition)?;
+
+        let nvs = match EspCustomNvs::new(part, "windrose", false) {
+            Ok(nvs) => nvs,
+            Err(err) => {
+                warn!("No wind rose settings found, using defaults: {err}");
+                return Ok(settings);
+            }
+        };
+
+        let mut period: u32 = 0;
+        if let Some(period) = nvs.get_u32("period", &mut period)? {
+            if *period > 0 {
+                settings.period = *period as u64;
+            } else {
+                warn!("Invalid wind rose period {period}, using default");
+            }
+        }
+
+        let bins = get_string_from_nvs(&nvs, "bins")?;
+        if !bins.is_empty() {
+            match WindRose::parse_bins(&bins) {
+                Some(bins) => settings.bins = bins,
+                None => warn!("Invalid wind rose bins \"{bins}\", using default"),
+            }
+        }
+
+        info!("Wind rose settings: {:?}", settings);
+
+        Ok(settings)
+    }
+}
+
+impl Default for WindRoseSettings {
+    fn default() -> Self {
+        WindRoseSettings {
+            period: DEFAULT_WIND_ROSE_PERIOD,
+            bins: DEFAULT_WIND_ROSE_BINS.to_vec(),
+        }
+    }
+}
+
+impl TimeSettings {
+    // The time settings are stored in the namespace "time" of the conf
+    // partition:
+    //
+    // tz  string  POSIX TZ string (default Berlin/Germany)
+    pub fn new(partition: &str) -> Result<Self, EspError> {
+        let mut settings = TimeSettings::default();
+        let part = EspCustomNvsPartition::take(partition)?;
+
+        let nvs = match EspCustomNvs::new(part, "time", false) {
+            Ok(nvs) => nvs,
+            Err(err) => {
+                warn!("No time settings found, using defaults: {err}");
+                return Ok(settings);
+            }
+        };
+
+        let timezone = get_string_from_nvs(&nvs, "tz")?;
+        if !timezone.is_empty() {
+            settings.timezone = timezone;
+        }
+
+        info!("Time settings: {:?}", settings);
+
+        Ok(settings)
+    }
+}
+
+impl Default for TimeSettings {
+    fn default() -> Self {
+        TimeSettings {
+            timezone: String::from(DEFAULT_TIMEZONE),
+        }
+    }
+}
+
+impl ReportingSettings {
+    // The reporting settings are stored in the namespace "reporting" of the
+    // conf partition:
+    //
+    // interval  u32  data reporting interval [s] (default 120 s)
+    // schema    u8   telemetry schema version, 1 for the legacy format of
+    //                the existing web app (default 2)
+    pub fn new(partition: &str) -> Result<Self, EspError> {
+        let mut settings = ReportingSettings::default();
+        let part = EspCustomNvsPartition::take(partition)?;
+
+        let nvs = match EspCustomNvs::new(part, "reporting", false) {
+            Ok(nvs) => nvs,
+            Err(err) => {
+                warn!("No reporting settings found, using defaults: {err}");
+                return Ok(settings);
+            }
+        };
+
+        let mut interval: u32 = 0;
+        if let Some(interval) = nvs.get_u32("interval", &mut interval)? {
+            if *interval > 0 {
+                settings.interval = *interval as u64;
+            } else {
+                warn!("Invalid reporting interval {interval}, using default");
+            }
+        }
+
+        let mut schema: u8 = 0;
+        if let Some(schema) = nvs.get_u8("schema", &mut schema)? {
+            match TelemetrySchema::from_version(*schema) {
+                Some(version) => settings.schema = version,
+                None => warn!("Invalid telemetry schema version {schema}, using default"),
+            }
+        }
+
+        info!("Reporting settings: {:?}", settings);
+
+        Ok(settings)
+    }
+}
+
+impl Default for ReportingSettings {
+    fn default() -> Self {
+        ReportingSettings {
+            interval: DATA_REPORTING_INTERVAL,
+            schema: TelemetrySchema::default(),
+        }
+    }
+}
+
+impl LogSettings {
+    // The log settings are stored in the namespace "log" of the conf
+    // partition:
+    //
+    // level  string  off, error, warn, info, debug or trace (default info)
+    pub fn new(partition: &str) -> Result<Self, EspError> {
+        let mut settings = LogSettings::default();
+        let part = EspCustomNvsPartition::take(partition)?;
+
+        let nvs = match EspCustomNvs::new(part, "log", false) {
+            Ok(nvs) => nvs,
+            Err(err) => {
+                warn!("No log settings found, using defaults: {err}");
+                return Ok(settings);
+            }
+        };
+
+        let level = get_string_from_nvs(&nvs, "level")?;
+        if !level.is_empty() {
+            match level.parse() {
+                Ok(level) => settings.level = level,
+                Err(_) => warn!("Invalid log level \"{level}\", using default"),
+            }
+        }
+
+        info!("Log settings: {:?}", settings);
+
+        Ok(settings)
+    }
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        LogSettings {
+            level: LevelFilter::Info,
+        }
+    }
+}
+
+// Persists the settings received through the desired state of the device
+// shadow into the namespaces they are loaded from during startup. The
+// settings need to be validated before.
+pub fn save_shadow_config(partition: &str, config: &ShadowConfig) -> Result<(), EspError> {
+    let part = EspCustomNvsPartition::take(partition)?;
+
+    if let Some(interval) = config.reporting_interval {
+        let nvs = EspCustomNvs::new(part.clone(), "reporting", true)?;
+        nvs.set_u32("interval", interval)?;
+    }
+
+    if let Some(units) = &config.units {
+        let mut nvs = EspCustomNvs::new(part.clone(), "units", true)?;
+        if let Some(speed_unit) = &units.speed_unit {
+            nvs.set_str("speed_unit", speed_unit)?;
+        }
+        if let Some(compass) = units.compass_points {
+            nvs.set_u8("compass", compass)?;
+        }
+    }
+
+    if let Some(calibration) = &config.calibration {
+        let mut nvs = EspCustomNvs::new(part.clone(), "calibration", true)?;
+        if let Some(id) = &calibration.id {
+            nvs.set_str("cal_id", id)?;
+        }
+        if let Some(ppr) = calibration.pulses_per_revolution {
+            nvs.set_u8("ppr", ppr)?;
+        }
+        if let Some(slope) = calibration.slope {
+            nvs.set_str("slope", &slope.to_string())?;
+        }
+        if let Some(offset) = calibration.offset {
+            nvs.set_str("offset", &offset.to_string())?;
+        }
+        if let Some(table) = &calibration.table {
+            nvs.set_str("table", &Calibration::format_table(table))?;
+        }
+    }
+
+    if let Some(level) = &config.log_level {
+        let mut nvs = EspCustomNvs::new(part.clone(), "log", true)?;
+        nvs.set_str("level", level)?;
+    }
+
+    if let Some(timezone) = &config.timezone {
+        let mut nvs = EspCustomNvs::new(part, "time", true)?;
+        nvs.set_str("tz", timezone)?;
+    }
+
+    Ok(())
+}
+
+// Erases the runtime settings, the firmware defaults are used after the
+// next restart
+pub fn reset_settings(partition: &str) -> Result<(), EspError> {
+    let part = EspCustomNvsPartition::take(partition)?;
+
+    for namespace in RUNTIME_SETTINGS_NAMESPACES {
+        let mut nvs = EspCustomNvs::new(part.clone(), namespace, true)?;
+        nvs.erase_all()?;
+    }
+
+    Ok(())
+}
+
+// The calibration of the anemometer is stored in the namespace "calibration"
+// of the conf partition:
+//
+// cal_id  string  identifier of the calibration run, reported in the shadow
+// ppr     u8      pulses per revolution of the cups rotor
+// slope   string  linear model [km/h per rps]
+// offset  string  linear model, starting threshold [km/h]
+// table   string  optional piecewise linear table "rps:kmh;rps:kmh;..."
+//
+// Missing keys are replaced by the default calibration values.
+pub fn load_calibration(partition: &str) -> Result<Calibration, EspError> {
+    let mut calibration = Calibration::default();
+    let part = EspCustomNvsPartition::take(partition)?;
+
+    let nvs = match EspCustomNvs::new(part, "calibration", false) {
+        Ok(nvs) => nvs,
+        Err(err) => {
+            warn!("No calibration found, using default calibration: {err}");
+            return Ok(calibration);
+        }
+    };
+
+    // an overlong string in the nvs must not prevent the start
+    match get_string_from_nvs(&nvs, "cal_id") {
+        Ok(id) if !id.is_empty() => calibration.id = id,
+        Ok(_) => {}
+        Err(err) => warn!("Invalid calibration id, using default: {err}"),
+    }
+
+    let mut ppr: u8 = 0;
+    if let Some(ppr) = nvs.get_u8("ppr", &mut ppr)? {
+        if *ppr > 0 {
+            calibration.pulses_per_revolution = *ppr;
+        } else {
+            warn!("Invalid calibration pulses per revolution {ppr}, using default");
+        }
+    }
+
+    if let Some(slope) = get_f32_from_nvs(&nvs, "slope")? {
+        calibration.slope = slope;
+    }
+
+    if let Some(offset) = get_f32_from_nvs(&nvs, "offset")? {
+        calibration.offset = offset;
+    }
+
+    match get_string_from_nvs(&nvs, "table") {
+        Ok(table) => match Calibration::parse_table(&table) {
+            Some(table) => calibration.table = table,
+            None => warn!("Invalid calibration table \"{table}\", using linear model"),
+        },
+        Err(err) => warn!("Failed to read calibration table, using linear model: {err}"),
+    }
+
+    info!("Calibration: {:?}", calibration);
+
+    Ok(calibration)
+}
+
+// NVS has no floating point type, therefore floats are stored as strings
+fn get_f32_from_nvs(nvs: &EspCustomNvs, key: &str) -> Result<Option<f32>, EspError> {
+    let value = get_string_from_nvs(nvs, key)?;
+
+    if value.is_empty() {
+        return Ok(None);
+    }
+
+    match value.parse::<f32>() {
+        Ok(value) => Ok(Some(value)),
+        Err(err) => {
+            warn!("Invalid value \"{value}\" for {key}: {err}");
+            Ok(None)
+        }
+    }
+}
+
 fn get_string_from_nvs(nvs: &EspCustomNvs, key: &str) -> Result<String, EspError> {
     let mut nvm_str_buffer: [u8; NVS_STRING_READ_BUFFER_SIZE] = [0; NVS_STRING_READ_BUFFER_SIZE];
     nvs.get_str(key, &mut nvm_str_buffer)?;
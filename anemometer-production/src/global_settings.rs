@@ -31,14 +31,60 @@
  */
 // Global setting for data aquisition and reporting [sec]
 pub const DATA_REPORTING_INTERVAL: u64 = 120;
-// Interval for taking measurments from the anemometer [ms]
-pub const MEASUREMENT_INTERVAL: u64 = 500;
+// Interval for taking measurments from the anemometer [ms]. WMO-No. 8
+// recommends a 4 Hz sampling rate for the 3 sec gust detection
+pub const MEASUREMENT_INTERVAL: u64 = 250;
+// Averaging windows for wind statistics [sec], the first window is used
+// for the reported average wind speed and direction (WMO 2 and 10 min)
+pub const STATISTICS_WINDOWS: [u64; 2] = [120, 600];
+// Plausibility limits of the anemometer samples. Samples above the
+// physical maximum of the cups or with a larger change between two
+// samples than the inertia of the cups allows are rejected [rps]
+pub const MAX_PLAUSIBLE_RPS: f32 = 50.0;
+pub const MAX_PLAUSIBLE_RPS_STEP: f32 = 10.0;
+// Pulse edges closer than this interval to the previous edge are treated
+// as contact bounce [µs]. 50 rps with 2 pulses per revolution gives a
+// pulse every 10 ms.
+pub const PULSE_DEBOUNCE_INTERVAL: u32 = 2000;
+// Glitch filter of the PCNT pulse counter (feature "pcnt"). Pulses
+// shorter than this number of APB clock cycles (12.5 ns) are ignored,
+// the hardware supports up to 1023 cycles
+pub const PULSE_GLITCH_FILTER: u16 = 1000;
+// Interval for writing the hourly and daily summaries to flash [sec]. A
+// checkpoint is written in addition before a restart.
+pub const SUMMARY_CHECKPOINT_INTERVAL: u64 = 600;
+pub const SUMMARY_PARTITION: &str = "summary";
+// Time zone used if none is configured, POSIX TZ format for Berlin/Germany
+// taken from here https://sites.google.com/a/usapiens.com/opnode/time-zones
+pub const DEFAULT_TIMEZONE: &str = "CET-1CEST-2,M3.5.0/02:00:00,M10.5.0/03:00:00";
+// Reports which can't be published while the MQTT connection is down are
+// queued in RAM. If the "queue" partition exists, reports are spilled into
+// flash once the RAM queue is full. When full the oldest reports are dropped.
+// Each reporting interval queues up to 4 reports (wind report, speed,
+// direction and the Home Assistant state), plus the hourly and daily
+// summaries and the wind rose. At the default reporting interval that's
+// 120 reports per hour, the RAM holds 4 intervals and the flash about 2 h.
+// A wind report takes up to ~1.5 kB, 250 reports fit into the 256 kB
+// partition with room left for the NVS garbage collection.
+pub const REPORT_QUEUE_RAM_CAPACITY: usize = 16;
+pub const REPORT_QUEUE_FLASH_CAPACITY: usize = 250;
+pub const REPORT_QUEUE_PARTITION: &str = "queue";
+// Interval between two replayed reports after a reconnect [ms]
+pub const REPORT_REPLAY_INTERVAL: u64 = 500;
+// Maximum size of a received MQTT payload, larger messages are delivered
+// in chunks by the MQTT client and assembled in RAM [bytes]
+pub const MQTT_MAX_PAYLOAD_SIZE: usize = 4096;
+// Time for the command response to be sent before a restart [ms]
+pub const COMMAND_EFFECT_DELAY: u64 = 1000;
 // light sleep mode max cpu frequency
 pub const MAX_CPU_FREQ: i32 = 160;
 // light sleep mode min cpu frequency
 pub const MIN_CPU_FREQ: i32 = 40;
-// light sleep mode enabled flag
-pub const LIGHT_SLEEP_MODE_ENABLED: bool = true;
+// light sleep mode enabled flag. The PCNT (feature "pcnt") isn't clocked
+// in light sleep, this backend counts the pulses without an interrupt per
+// pulse but keeps the chip out of light sleep. The ISR backend wakes up on
+// every pulse and has the lower power consumption at low wind speeds.
+pub const LIGHT_SLEEP_MODE_ENABLED: bool = !cfg!(feature = "pcnt");
 // All user task will run on core 1
 // core 0 will be used by wifi and network stack
 pub const TASK_HIGH_PRIORITY: u8 = 30;
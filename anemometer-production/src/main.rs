@@ -31,13 +31,18 @@
  */
 #![allow(incomplete_features)]
 #![feature(generic_const_exprs)]
-use crate::configuration::AwsIoTSettings;
+use crate::configuration::{
+    load_calibration, AwsIoTSettings, BrokerSettings, CommandAuthSettings, LogSettings,
+    ReportingSettings, TimeSettings, UnitSettings, WindRoseSettings,
+};
 use crate::global_settings::*;
 use crate::services::*;
 use crate::state::*;
-use crate::task::{httpd, mqtt, ota::*, publisher};
+use crate::task::{mqtt, ota::*, publisher, summary};
 use crate::utils::nvs_ext::*;
 use crate::utils::{datetime, errors::*};
+// the hardware independent modules live in anemometer-core
+use anemometer_core::{data_processing, units};
 use channel_bridge::{asynch::pubsub, asynch::*};
 use configuration::AwsIoTCertificates;
 use edge_executor::*;
@@ -58,10 +63,10 @@ use esp_idf_sys::esp_ota_mark_app_valid_cancel_rollback;
 use esp_idf_sys::{self as sys, esp, esp_wifi_set_ps, wifi_ps_type_t_WIFI_PS_MIN_MODEM};
 use log::*;
 use once_cell::sync::Lazy;
+use std::sync::atomic::Ordering;
 use std::sync::Mutex;
 
 mod configuration;
-mod data_processing;
 mod global_settings;
 mod mqtt_msg;
 mod peripherals;
@@ -75,6 +80,8 @@ mod utils;
 static AWSCERTIFICATES: static_cell::StaticCell<AwsIoTCertificates> =
     static_cell::StaticCell::new();
 
+static BROKERSETTINGS: static_cell::StaticCell<BrokerSettings> = static_cell::StaticCell::new();
+
 pub static AWSCONFIG: Lazy<Mutex<AwsIoTSettings>> = Lazy::new(|| {
     Mutex::new(match AwsIoTSettings::new("conf") {
         Ok(settings) => settings,
@@ -85,6 +92,16 @@ pub static AWSCONFIG: Lazy<Mutex<AwsIoTSettings>> = Lazy::new(|| {
     })
 });
 
+pub static UNITS: Lazy<Mutex<UnitSettings>> = Lazy::new(|| {
+    Mutex::new(match UnitSettings::new("conf") {
+        Ok(settings) => settings,
+        Err(err) => {
+            error!("Failed to load unit settings: {err}");
+            panic!();
+        }
+    })
+});
+
 fn main() -> core::result::Result<(), InitError> {
     esp_idf_hal::task::critical_section::link();
     esp_idf_svc::timer::embassy_time::driver::link();
@@ -114,13 +131,79 @@ fn main() -> core::result::Result<(), InitError> {
 
     let peripherals = peripherals::SystemPeripherals::take();
     let anemometer_peripherals = peripherals.pulse_counter;
+    let i2c_bus_peripherals = peripherals.i2c_bus;
     let nvs_default_partition = EspDefaultNvsPartition::take()?;
     let sysloop = EspSystemEventLoop::take()?;
 
     // Initialize data capture from anemometer
     let mut anemometer = anemometer::AnemometerDriver::new(anemometer_peripherals.pulse).unwrap();
-
-    let _anemometer_timer = anemometer.set_measurement_timer().unwrap();
+    let i2c_bus = i2c_bus::new(
+        i2c_bus_peripherals.i2c,
+        i2c_bus_peripherals.sda,
+        i2c_bus_peripherals.scl,
+    )
+    .unwrap();
+    #[cfg(feature = "bme280")]
+    let environment_sensor = bme280::Bme280::new(i2c_bus.clone());
+    let wind_vane = wind_vane::As5600::new(i2c_bus);
+
+    let calibration = match load_calibration("conf") {
+        Ok(calibration) => calibration,
+        Err(err) => {
+            error!("Failed to load calibration: {err}");
+            panic!();
+        }
+    };
+    #[cfg_attr(feature = "synthetic", allow(unused_variables))]
+    let pulses_per_revolution = calibration.pulses_per_revolution;
+    let wind_rose_settings = match WindRoseSettings::new("conf") {
+        Ok(settings) => settings,
+        Err(err) => {
+            error!("Failed to load wind rose settings: {err}");
+            panic!();
+        }
+    };
+    match LogSettings::new("conf") {
+        Ok(settings) => set_log_level(settings.level),
+        Err(err) => error!("Failed to load log settings: {err}"),
+    }
+    match ReportingSettings::new("conf") {
+        Ok(settings) => {
+            REPORTING_INTERVAL.store(settings.interval, Ordering::Relaxed);
+            TELEMETRY_SCHEMA.store(settings.schema.version(), Ordering::Relaxed);
+        }
+        Err(err) => error!("Failed to load reporting settings: {err}"),
+    }
+    WIND_DATA_HISTORY
+        .lock()
+        .unwrap()
+        .set_calibration(calibration);
+    WIND_ROSE
+        .lock()
+        .unwrap()
+        .configure(wind_rose_settings.period, &wind_rose_settings.bins);
+
+    #[cfg(not(feature = "synthetic"))]
+    let pulse_source = anemometer.pulse_source(pulses_per_revolution);
+    // bench test without anemometer: 5 rps varying by 3 rps within 5 min
+    #[cfg(feature = "synthetic")]
+    let pulse_source =
+        anemometer_core::pulse_source::SyntheticSource::new(MEASUREMENT_INTERVAL, 5.0, 3.0, 300.0)
+            .with_turbulence(1.0, 1);
+    let _anemometer_timer = anemometer
+        .set_measurement_timer(wind_vane, pulse_source)
+        .unwrap();
+
+    // the environmental sensor is optional, the station keeps measuring wind
+    // without it
+    #[cfg(feature = "bme280")]
+    let _environment_timer = match environment_sensor {
+        Ok(sensor) => sensor.set_measurement_timer().ok(),
+        Err(err) => {
+            error!("Failed to initialize BME280: {err}");
+            None
+        }
+    };
 
     let aws_iot_certificates: &'static AwsIoTCertificates =
         AWSCERTIFICATES.init(match AwsIoTCertificates::new("conf") {
@@ -131,15 +214,53 @@ fn main() -> core::result::Result<(), InitError> {
             }
         });
 
+    let broker_settings: &'static BrokerSettings =
+        BROKERSETTINGS.init(match BrokerSettings::new("conf") {
+            Ok(settings) => settings,
+            Err(err) => {
+                error!("Failed to load broker settings: {err}");
+                panic!();
+            }
+        });
+    broker_settings.apply_topic_layout(&mut AWSCONFIG.lock().unwrap());
+
+    let command_auth_settings = match CommandAuthSettings::new("conf") {
+        Ok(settings) => settings,
+        Err(err) => {
+            error!("Failed to load command authentication settings: {err}");
+            panic!();
+        }
+    };
+
     let (wifi, wifi_notif) = wifi(
         peripherals.modem,
         sysloop.clone(),
-        Some(nvs_default_partition),
+        Some(nvs_default_partition.clone()),
     )?;
 
     esp!(unsafe { esp_wifi_set_ps(wifi_ps_type_t_WIFI_PS_MIN_MODEM) })?;
 
-    let _sntp = utils::datetime::initialize();
+    let time_settings = match TimeSettings::new("conf") {
+        Ok(settings) => settings,
+        Err(err) => {
+            error!("Failed to load time settings: {err}");
+            panic!();
+        }
+    };
+    let _sntp = utils::datetime::initialize(&time_settings.timezone);
+
+    // restore the hourly and daily summaries from the last checkpoint
+    if let Err(err) = summary::init(SUMMARY_PARTITION) {
+        error!("Failed to restore summaries: {err}");
+    }
+    // record of a firmware update job which has been started before the restart
+    if let Err(err) = utils::job_store::init(nvs_default_partition.clone()) {
+        error!("Failed to open job storage: {err}");
+    }
+    // newest authenticated command, protects against replays after a restart
+    if let Err(err) = utils::auth_store::init(nvs_default_partition) {
+        error!("Failed to open command authentication storage: {err}");
+    }
 
     ThreadSpawnConfiguration {
         name: Some(b"high-prio-executor\0"),
@@ -175,10 +296,11 @@ fn main() -> core::result::Result<(), InitError> {
     let _mid_prio_execution = schedule::<8, _>(8000, move || {
         let executor = EspExecutor::new();
         let mut tasks = heapless::Vec::new();
-        let (mqtt_client, mqtt_conn) = services::mqtt(aws_iot_certificates).unwrap();
+        let (mqtt_client, mqtt_conn) =
+            services::mqtt(aws_iot_certificates, broker_settings, command_auth_settings).unwrap();
 
         executor.spawn_local_collect(
-            mqtt::send_task::<MQTT_MAX_TOPIC_LEN>(mqtt_client),
+            mqtt::send_task::<MQTT_MAX_TOPIC_LEN>(mqtt_client, broker_settings),
             &mut tasks,
         )?;
         executor.spawn_local_collect(mqtt::receive_task(mqtt_conn), &mut tasks)?;
@@ -196,7 +318,9 @@ fn main() -> core::result::Result<(), InitError> {
         let executor = EspExecutor::new();
         let mut tasks = heapless::Vec::new();
         executor.spawn_local_collect(publisher::wind_speed_task(), &mut tasks)?;
-        //executor.spawn_local_collect(httpd::http_server_task(), &mut tasks)?;
+        #[cfg(feature = "httpd")]
+        executor.spawn_local_collect(task::httpd::http_server_task(), &mut tasks)?;
+        executor.spawn_local_collect(summary::summary_task(), &mut tasks)?;
 
         Ok((executor, tasks))
     });
@@ -215,6 +339,22 @@ fn main() -> core::result::Result<(), InitError> {
     unreachable!();
 }
 
+// Sets the maximum level of the log crate and of the ESP-IDF components
+pub fn set_log_level(level: LevelFilter) {
+    let esp_level = match level {
+        LevelFilter::Off => sys::esp_log_level_t_ESP_LOG_NONE,
+        LevelFilter::Error => sys::esp_log_level_t_ESP_LOG_ERROR,
+        LevelFilter::Warn => sys::esp_log_level_t_ESP_LOG_WARN,
+        LevelFilter::Info => sys::esp_log_level_t_ESP_LOG_INFO,
+        LevelFilter::Debug => sys::esp_log_level_t_ESP_LOG_DEBUG,
+        LevelFilter::Trace => sys::esp_log_level_t_ESP_LOG_VERBOSE,
+    };
+
+    log::set_max_level(level);
+    unsafe { sys::esp_log_level_set(b"*\0".as_ptr() as *const _, esp_level) };
+    info!("Log level: {level}");
+}
+
 pub fn schedule<'a, const C: usize, M>(
     stack_size: usize,
     spawner: impl FnOnce() -> core::result::Result<
@@ -1,47 +1,30 @@
-use crate::state::OtaUrl;
-use core::str;
+use crate::data_processing::{Calibration, WindowStatistics};
+use anemometer_core::environment::EnvironmentReport;
+pub use anemometer_core::mqtt_msg::*;
+use anemometer_core::ota::OtaStatus;
+use anemometer_core::plausibility::SensorHealth;
+use anemometer_core::summary::SummaryReport;
+use anemometer_core::telemetry::WindReport;
+use anemometer_core::wind_rose::WindRose;
 use embedded_svc::mqtt::client::asynch::{Event, Message};
 use embedded_svc::mqtt::client::Details;
-use log::*;
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 use serde_json::json;
-#[allow(dead_code)]
-pub const MQTT_TOPIC_POSTFIX_COMMAND: &str = "/command/#";
-pub const MQTT_TOPIC_POSTFIX_COMMAND_OTA_UPDATE: &str = "/command/ota_update";
-pub const MQTT_TOPIC_POSTFIX_COMMAND_SYSTEM_RESTART: &str = "/command/system_restart";
-#[allow(dead_code)]
-pub const MQTT_TOPIC_POSTFIX_WIND_SPEED: &str = "/wind/speed";
-#[allow(dead_code)]
-pub const MQTT_TOPIC_POSTFIX_WIND_DIRECTION: &str = "/wind/direction";
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct CmdMqttMsg {
-    pub cmd: String,
-    pub arg: String,
-}
-
-#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
-pub enum MqttCommand {
-    ExecOTAUpdate(OtaUrl),
-    SystemRestart,
-}
-
-pub struct MessageParser {
-    #[allow(clippy::type_complexity)]
-    command_parser: Option<fn(&[u8]) -> Option<MqttCommand>>,
-    payload_buf: [u8; 128],
+// Feeds the events of the ESP-IDF MQTT client into the transport
+// independent MessageParser of anemometer-core
+pub trait MqttEventConverter {
+    fn convert<M, E>(
+        &mut self,
+        event: &Result<Event<M>, E>,
+    ) -> Result<Event<Option<MqttCommand>>, E>
+    where
+        M: Message,
+        E: Clone;
 }
 
-impl MessageParser {
-    pub fn new() -> Self {
-        MessageParser {
-            command_parser: None,
-            payload_buf: [0; 128],
-        }
-    }
-
-    pub fn convert<M, E>(
+impl MqttEventConverter for MessageParser {
+    fn convert<M, E>(
         &mut self,
         event: &Result<Event<M>, E>,
     ) -> Result<Event<Option<MqttCommand>>, E>
@@ -51,94 +34,23 @@ impl MessageParser {
     {
         event
             .as_ref()
-            .map(|event| event.transform_received(|message| self.process(message)))
+            .map(|event| {
+                event.transform_received(|message| {
+                    let chunk = match message.details() {
+                        Details::Complete => Chunk::Complete,
+                        Details::InitialChunk(initial_chunk_data) => Chunk::Initial {
+                            total_data_size: initial_chunk_data.total_data_size,
+                        },
+                        Details::SubsequentChunk(subsequent_chunk_data) => Chunk::Subsequent {
+                            current_data_offset: subsequent_chunk_data.current_data_offset,
+                            total_data_size: subsequent_chunk_data.total_data_size,
+                        },
+                    };
+                    self.process(message.topic(), message.data(), chunk)
+                })
+            })
             .map_err(|e| e.clone())
     }
-
-    fn process<M>(&mut self, message: &M) -> Option<MqttCommand>
-    where
-        M: Message,
-    {
-        info!(
-            "Message = {:?} {:?} {:?}",
-            message.topic(),
-            message.data(),
-            message.details()
-        );
-
-        match message.details() {
-            Details::Complete => Self::parse_command(message.topic().unwrap())
-                .and_then(|parser| parser(message.data())),
-            Details::InitialChunk(initial_chunk_data) => {
-                if initial_chunk_data.total_data_size > self.payload_buf.len() {
-                    self.command_parser = None;
-                } else {
-                    self.command_parser = Self::parse_command(message.topic().unwrap());
-
-                    self.payload_buf[..message.data().len()]
-                        .copy_from_slice(message.data().as_ref());
-                }
-
-                None
-            }
-            Details::SubsequentChunk(subsequent_chunk_data) => {
-                if let Some(command_parser) = self.command_parser.as_ref() {
-                    self.payload_buf
-                        [subsequent_chunk_data.current_data_offset..message.data().len()]
-                        .copy_from_slice(message.data().as_ref());
-
-                    if subsequent_chunk_data.total_data_size
-                        == subsequent_chunk_data.current_data_offset + message.data().len()
-                    {
-                        command_parser(&self.payload_buf[0..subsequent_chunk_data.total_data_size])
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            }
-        }
-    }
-
-    #[allow(clippy::type_complexity)]
-    fn parse_command(topic: &str) -> Option<fn(&[u8]) -> Option<MqttCommand>> {
-        info!("parse_command: {}", topic);
-        if topic.ends_with(MQTT_TOPIC_POSTFIX_COMMAND_OTA_UPDATE) {
-            Some(Self::parse_ota_update_command)
-        } else if topic.ends_with(MQTT_TOPIC_POSTFIX_COMMAND_SYSTEM_RESTART) {
-            Some(Self::parse_system_restart_command)
-        } else {
-            None
-        }
-    }
-
-    fn parse_ota_update_command(data: &[u8]) -> Option<MqttCommand> {
-        info!("parse_ota_update_command: {:?}", data);
-        Self::parse::<OtaUrl>(data).map(MqttCommand::ExecOTAUpdate)
-    }
-
-    fn parse_system_restart_command(data: &[u8]) -> Option<MqttCommand> {
-        info!("parse_system_restart_command: {:?}", data);
-        Self::parse_empty(data).map(|_| MqttCommand::SystemRestart)
-    }
-
-    fn parse<T>(data: &[u8]) -> Option<T>
-    where
-        T: str::FromStr,
-    {
-        str::from_utf8(data)
-            .ok()
-            .and_then(|s| str::parse::<T>(s).ok())
-    }
-
-    fn parse_empty(data: &[u8]) -> Option<()> {
-        if data.is_empty() {
-            Some(())
-        } else {
-            None
-        }
-    }
 }
 
 #[allow(non_snake_case)]
@@ -152,7 +64,17 @@ pub struct AWSShadowUpdate<'a> {
     pub windDir: &'a str,
     pub windSpeed: &'a str,
     pub windGust: &'a str,
+    pub speedUnit: &'a str,
+    pub gustTimeStamp: &'a str,
+    pub gustDir: &'a str,
     pub fwVer: &'a str,
+    pub calibration: &'a Calibration,
+    pub windows: &'a [WindowStatistics],
+    pub windowsUnit: &'a str,
+    pub sensorHealth: SensorHealth,
+    // only reported if the firmware is built with the BME280 sensor
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub environment: Option<EnvironmentReport>,
 }
 
 impl AWSShadowUpdate<'_> {
@@ -166,3 +88,67 @@ impl AWSShadowUpdate<'_> {
         *msg = serde_json::to_string(&json_msg).unwrap();
     }
 }
+
+// Shadow update in the typed telemetry schema
+pub fn format_wind_report_msg(report: &WindReport, msg: &mut String) {
+    let json_msg = json!({
+        "state": {
+            "reported": report
+        }
+    });
+
+    *msg = serde_json::to_string(&json_msg).unwrap();
+}
+
+// Wind rose of a completed accumulation period, published to
+// <topic_prefix>/<device_id>/wind/rose
+#[allow(non_snake_case)]
+#[derive(Serialize)]
+pub struct WindRoseUpdate<'a> {
+    pub deviceId: &'a str,
+    pub startTimeStamp: &'a str,
+    pub speedUnit: &'a str,
+    pub windRose: &'a WindRose,
+}
+
+impl WindRoseUpdate<'_> {
+    pub fn format_wind_rose_msg(&self, msg: &mut String) {
+        *msg = serde_json::to_string(self).unwrap();
+    }
+}
+
+// Hourly or daily summary of a completed period, published to
+// <topic_prefix>/<device_id>/summary/hourly or .../summary/daily
+#[allow(non_snake_case)]
+#[derive(Serialize)]
+pub struct SummaryUpdate<'a> {
+    pub deviceId: &'a str,
+    pub startTimeStamp: &'a str,
+    pub endTimeStamp: &'a str,
+    pub gustTimeStamp: &'a str,
+    pub speedUnit: &'a str,
+    pub summary: &'a SummaryReport,
+}
+
+impl SummaryUpdate<'_> {
+    pub fn format_summary_msg(&self, msg: &mut String) {
+        *msg = serde_json::to_string(self).unwrap();
+    }
+}
+
+// Progress and result of a firmware update, published to
+// <topic_prefix>/<device_id>/ota/status
+#[allow(non_snake_case)]
+#[derive(Serialize)]
+pub struct OtaStatusUpdate<'a> {
+    pub deviceId: &'a str,
+    pub epochTime: u64,
+    #[serde(flatten)]
+    pub status: &'a OtaStatus,
+}
+
+impl OtaStatusUpdate<'_> {
+    pub fn format_ota_status_msg(&self, msg: &mut String) {
+        *msg = serde_json::to_string(self).unwrap();
+    }
+}
@@ -30,11 +30,13 @@
  * limitations under the License.
  */
 use esp_idf_hal::gpio::*;
+use esp_idf_hal::i2c::I2C0;
 use esp_idf_hal::modem::Modem;
 use esp_idf_hal::peripherals::Peripherals;
 
 pub struct SystemPeripherals {
     pub pulse_counter: AnemometerPulseCounterPeripherals,
+    pub i2c_bus: I2cBusPeripherals,
     pub modem: Modem,
 }
 
@@ -47,6 +49,14 @@ impl SystemPeripherals {
                 pulse: peripherals.pins.gpio5.into(),
             },
 
+            // TinyS3 default I2C pins, the AS5600 and the optional BME280
+            // sit on the same connector as the hall sensor
+            i2c_bus: I2cBusPeripherals {
+                i2c: peripherals.i2c0,
+                sda: peripherals.pins.gpio8.into(),
+                scl: peripherals.pins.gpio9.into(),
+            },
+
             modem: peripherals.modem,
         }
     }
@@ -55,3 +65,9 @@ impl SystemPeripherals {
 pub struct AnemometerPulseCounterPeripherals {
     pub pulse: AnyIOPin,
 }
+
+pub struct I2cBusPeripherals {
+    pub i2c: I2C0,
+    pub sda: AnyIOPin,
+    pub scl: AnyIOPin,
+}
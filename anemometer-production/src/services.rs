@@ -29,21 +29,28 @@
  * See the License for the specific language governing permissions and
  * limitations under the License.
  */
-use crate::configuration::AwsIoTCertificates;
+use crate::configuration::{AwsIoTCertificates, BrokerAuth, BrokerSettings, CommandAuthSettings};
+use crate::global_settings::MQTT_MAX_PAYLOAD_SIZE;
 use crate::mqtt_msg::*;
+use crate::utils::auth_store;
 use crate::utils::errors::*;
+use anemometer_core::auth::CommandAuth;
+use anemometer_core::presence::Presence;
 use channel_bridge::{asynch::pubsub, asynch::*};
-use embedded_svc::mqtt::client::asynch::{Client, Connection, Publish};
+use embedded_svc::mqtt::client::asynch::{Client, Connection, Publish, QoS};
 use embedded_svc::utils::asyncify::Asyncify;
 use embedded_svc::wifi::{AuthMethod, ClientConfiguration, Configuration, Wifi as WifiTrait};
 use esp_idf_hal::modem::WifiModemPeripheral;
 use esp_idf_hal::peripheral::Peripheral;
 use esp_idf_svc::eventloop::EspSystemEventLoop;
-use esp_idf_svc::mqtt::client::{EspMqttClient, MqttClientConfiguration, MqttProtocolVersion};
+use esp_idf_svc::mqtt::client::{
+    EspMqttClient, LwtConfiguration, MqttClientConfiguration, MqttProtocolVersion,
+};
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
 use esp_idf_svc::wifi::{EspWifi, WifiEvent};
 use esp_idf_sys::EspError;
 use log::*;
+use std::time::SystemTime;
 
 const SSID: &str = env!("RUST_ESP32_ANEMOMETER_WIFI_SSID");
 const PASS: &str = env!("RUST_ESP32_ANEMOMETER_WIFI_PASS");
@@ -83,8 +90,49 @@ pub fn wifi<'d>(
     ))
 }
 
+// Signal strength of the access point the station is connected to [dBm]
+pub fn wifi_rssi() -> Option<i8> {
+    let mut ap_info = esp_idf_sys::wifi_ap_record_t::default();
+    match esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_wifi_sta_get_ap_info(&mut ap_info) }) {
+        Ok(()) => Some(ap_info.rssi),
+        Err(_) => None,
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+// Seconds since the boot
+fn uptime() -> u64 {
+    unsafe { esp_idf_sys::esp_timer_get_time() as u64 / 1_000_000 }
+}
+
+// Reason of the last reset as reported in the birth message
+pub fn reset_reason() -> &'static str {
+    #[allow(non_upper_case_globals)]
+    match unsafe { esp_idf_sys::esp_reset_reason() } {
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_POWERON => "powerOn",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_EXT => "external",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_SW => "software",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_INT_WDT => "interruptWatchdog",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_TASK_WDT => "taskWatchdog",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deepSleep",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_SDIO => "sdio",
+        _ => "unknown",
+    }
+}
+
 pub fn mqtt(
     aws_certificates: &'static AwsIoTCertificates,
+    broker: &'static BrokerSettings,
+    command_auth: CommandAuthSettings,
 ) -> Result<
     (
         impl Client + Publish,
@@ -92,11 +140,43 @@ pub fn mqtt(
     ),
     InitError,
 > {
-    let mut mqtt_parser = MessageParser::new();
-
-    let x509_client_cert = esp_idf_svc::tls::X509::pem_until_nul(&aws_certificates.device_cert[..]);
-    let x509_client_priv_key =
-        esp_idf_svc::tls::X509::pem_until_nul(&aws_certificates.private_key[..]);
+    let mut shadow_delta_topic = String::new();
+    let mut jobs_topic_prefix = String::new();
+    let mut presence_topic = String::new();
+    {
+        let aws_config = crate::AWSCONFIG.lock().unwrap();
+        shadow_delta_topic.push_str(&aws_config.things_prefix);
+        shadow_delta_topic.push('/');
+        shadow_delta_topic.push_str(&aws_config.device_id);
+        shadow_delta_topic.push_str(&aws_config.shadow_delta_postfix);
+
+        jobs_topic_prefix.push_str(&aws_config.things_prefix);
+        jobs_topic_prefix.push('/');
+        jobs_topic_prefix.push_str(&aws_config.device_id);
+
+        presence_topic.push_str(&aws_config.topic_prefix);
+        presence_topic.push('/');
+        presence_topic.push_str(&aws_config.device_id);
+        presence_topic.push_str(MQTT_TOPIC_POSTFIX_PRESENCE);
+    }
+    // published by the broker if the connection is lost without a
+    // disconnect, the birth message replaces it after the reconnect
+    let last_will = Presence::offline().format_msg();
+    let mut mqtt_parser = MessageParser::new().with_max_payload_size(MQTT_MAX_PAYLOAD_SIZE);
+    // on a generic broker anybody allowed to publish could change the
+    // settings through these topics
+    if broker.is_aws_iot() {
+        mqtt_parser = mqtt_parser
+            .with_shadow_delta_topic(&shadow_delta_topic)
+            .with_jobs_topic_prefix(&jobs_topic_prefix);
+    }
+    if command_auth.is_enabled() {
+        mqtt_parser = mqtt_parser.with_authentication(
+            CommandAuth::new(&command_auth.key, command_auth.window, unix_time)
+                .with_uptime(uptime)
+                .with_last_accepted(auth_store::load(), auth_store::save),
+        );
+    }
 
     // need to remove tailing zeros otherwise CString will complain
     let url = core::str::from_utf8(
@@ -118,15 +198,66 @@ pub fn mqtt(
     .unwrap();
     info!("AWS IoT device id = {device_id}");
 
+    let url = if broker.url.is_empty() {
+        url
+    } else {
+        broker.url.as_str()
+    };
+    let client_id = if broker.client_id.is_empty() {
+        device_id
+    } else {
+        broker.client_id.as_str()
+    };
+    info!("MQTT broker = {url}, client id = {client_id}");
+
+    let (client_certificate, private_key) = match broker.auth {
+        BrokerAuth::Certificate => (
+            Some(esp_idf_svc::tls::X509::pem_until_nul(
+                &aws_certificates.device_cert[..],
+            )),
+            Some(esp_idf_svc::tls::X509::pem_until_nul(
+                &aws_certificates.private_key[..],
+            )),
+        ),
+        BrokerAuth::Password | BrokerAuth::None => (None, None),
+    };
+    let (username, password) = match broker.auth {
+        BrokerAuth::Password => (
+            Some(broker.username.as_str()),
+            Some(broker.password.as_str()),
+        ),
+        BrokerAuth::Certificate | BrokerAuth::None => (None, None),
+    };
+    // a custom CA replaces the certificate bundle, e.g. for a self signed
+    // lab broker
+    let server_certificate = if broker.ca_cert.is_empty() {
+        None
+    } else {
+        Some(esp_idf_svc::tls::X509::pem_until_nul(&broker.ca_cert[..]))
+    };
+
     let (mqtt_client, mqtt_conn) = EspMqttClient::new_with_converting_async_conn(
         url,
         &MqttClientConfiguration {
-            client_id: Some(device_id),
-            client_certificate: Some(x509_client_cert),
-            private_key: Some(x509_client_priv_key),
-            crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+            client_id: Some(client_id),
+            client_certificate,
+            private_key,
+            crt_bundle_attach: if server_certificate.is_none() {
+                Some(esp_idf_sys::esp_crt_bundle_attach)
+            } else {
+                None
+            },
+            server_certificate,
+            username,
+            password,
             disable_clean_session: true,
             keep_alive_interval: Some(std::time::Duration::new(MQTT_SESSION_TIMEOUT, 0)),
+            lwt: Some(LwtConfiguration {
+                topic: &presence_topic,
+                payload: last_will.as_bytes(),
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
             protocol_version: Some(MqttProtocolVersion::V3_1_1),
             ..Default::default()
         },
@@ -183,60 +314,386 @@ pub mod http {
     }
 }
 
+// The AS5600 wind vane and the optional BME280 sensor share the I2C bus
+// of the sensor connector
+pub mod i2c_bus {
+    use crate::utils::errors::*;
+    use esp_idf_hal::gpio::*;
+    use esp_idf_hal::i2c::*;
+    use esp_idf_hal::peripheral::Peripheral;
+    use esp_idf_hal::units::*;
+    use std::sync::{Arc, Mutex};
+
+    pub type SharedI2cDriver = Arc<Mutex<I2cDriver<'static>>>;
+
+    pub fn new<I2C: I2c>(
+        i2c: impl Peripheral<P = I2C> + 'static,
+        sda: impl Peripheral<P = impl InputPin + OutputPin> + 'static,
+        scl: impl Peripheral<P = impl InputPin + OutputPin> + 'static,
+    ) -> Result<SharedI2cDriver, InitError> {
+        let config = I2cConfig::new().baudrate(400.kHz().into());
+
+        Ok(Arc::new(Mutex::new(I2cDriver::new(
+            i2c, sda, scl, &config,
+        )?)))
+    }
+}
+
+pub mod wind_vane {
+    use super::i2c_bus::SharedI2cDriver;
+    use esp_idf_hal::delay::BLOCK;
+    use esp_idf_sys::EspError;
+
+    // 7 bit I2C address of the AS5600 magnetic angle encoder
+    const AS5600_ADDRESS: u8 = 0x36;
+    const AS5600_REG_STATUS: u8 = 0x0b;
+    const AS5600_REG_ANGLE: u8 = 0x0e;
+    // STATUS register bit MD, set if a magnet has been detected
+    const AS5600_STATUS_MAGNET_DETECTED: u8 = 0x20;
+    // The AS5600 reports the angle with a 12 bit resolution
+    const AS5600_RESOLUTION: u32 = 4096;
+
+    pub struct As5600 {
+        i2c: SharedI2cDriver,
+    }
+
+    impl As5600 {
+        pub fn new(i2c: SharedI2cDriver) -> As5600 {
+            As5600 { i2c }
+        }
+
+        pub fn magnet_detected(&mut self) -> Result<bool, EspError> {
+            let mut status = [0_u8; 1];
+            self.i2c.lock().unwrap().write_read(
+                AS5600_ADDRESS,
+                &[AS5600_REG_STATUS],
+                &mut status,
+                BLOCK,
+            )?;
+
+            Ok(status[0] & AS5600_STATUS_MAGNET_DETECTED != 0)
+        }
+
+        // Returns the wind direction in degree [0..359]. The ANGLE register
+        // is used instead of RAW ANGLE, so a zero position programmed into
+        // the sensor (ZPOS) is taken into account.
+        pub fn read_direction(&mut self) -> Result<u16, EspError> {
+            let mut angle = [0_u8; 2];
+            self.i2c.lock().unwrap().write_read(
+                AS5600_ADDRESS,
+                &[AS5600_REG_ANGLE],
+                &mut angle,
+                BLOCK,
+            )?;
+
+            let raw = (u16::from_be_bytes(angle) & 0x0fff) as u32;
+
+            Ok((raw * 360 / AS5600_RESOLUTION) as u16)
+        }
+    }
+}
+
+#[cfg(feature = "bme280")]
+pub mod bme280 {
+    use super::i2c_bus::SharedI2cDriver;
+    use crate::state::*;
+    use anemometer_core::environment::{
+        Bme280Calibration, EnvironmentReading, ENVIRONMENT_SAMPLE_INTERVAL,
+    };
+    use esp_idf_hal::delay::BLOCK;
+    use esp_idf_svc::timer::*;
+    use esp_idf_sys::*;
+    use log::*;
+    use std::time::Duration;
+
+    // 7 bit I2C address of the BME280 with SDO connected to GND, most
+    // breakout boards use 0x76 (SDO high 0x77)
+    const BME280_ADDRESS: u8 = 0x76;
+    const BME280_CHIP_ID: u8 = 0x60;
+    const BME280_REG_CALIB_TP: u8 = 0x88;
+    const BME280_REG_CHIP_ID: u8 = 0xd0;
+    const BME280_REG_CALIB_H: u8 = 0xe1;
+    const BME280_REG_CTRL_HUM: u8 = 0xf2;
+    const BME280_REG_CTRL_MEAS: u8 = 0xf4;
+    const BME280_REG_CONFIG: u8 = 0xf5;
+    const BME280_REG_DATA: u8 = 0xf7;
+    // humidity oversampling x1
+    const BME280_CTRL_HUM: u8 = 0x01;
+    // temperature and pressure oversampling x1, normal mode
+    const BME280_CTRL_MEAS: u8 = 0x27;
+    // 1000 ms standby between the measurements, IIR filter off
+    const BME280_CONFIG: u8 = 0xa0;
+
+    // Weather monitoring setup of the datasheet: the sensor measures in
+    // normal mode once per second, so reading the data registers never
+    // waits for a conversion.
+    pub struct Bme280 {
+        i2c: SharedI2cDriver,
+        calibration: Bme280Calibration,
+    }
+
+    impl Bme280 {
+        pub fn new(i2c: SharedI2cDriver) -> Result<Bme280, EspError> {
+            let mut sensor = Bme280 {
+                i2c,
+                calibration: Bme280Calibration::default(),
+            };
+
+            let mut chip_id = [0_u8; 1];
+            sensor.read_registers(BME280_REG_CHIP_ID, &mut chip_id)?;
+            if chip_id[0] != BME280_CHIP_ID {
+                error!("no BME280 found, chip id {:#04x}", chip_id[0]);
+                return Err(EspError::from(ESP_ERR_NOT_FOUND as esp_err_t).unwrap());
+            }
+
+            let mut tp = [0_u8; 26];
+            let mut h = [0_u8; 7];
+            sensor.read_registers(BME280_REG_CALIB_TP, &mut tp)?;
+            sensor.read_registers(BME280_REG_CALIB_H, &mut h)?;
+            sensor.calibration = Bme280Calibration::from_registers(&tp, &h);
+
+            // ctrl_hum only becomes effective after writing ctrl_meas
+            let mut i2c = sensor.i2c.lock().unwrap();
+            i2c.write(BME280_ADDRESS, &[BME280_REG_CONFIG, BME280_CONFIG], BLOCK)?;
+            i2c.write(
+                BME280_ADDRESS,
+                &[BME280_REG_CTRL_HUM, BME280_CTRL_HUM],
+                BLOCK,
+            )?;
+            i2c.write(
+                BME280_ADDRESS,
+                &[BME280_REG_CTRL_MEAS, BME280_CTRL_MEAS],
+                BLOCK,
+            )?;
+            drop(i2c);
+
+            Ok(sensor)
+        }
+
+        pub fn read(&mut self) -> Result<EnvironmentReading, EspError> {
+            let mut data = [0_u8; 8];
+            self.read_registers(BME280_REG_DATA, &mut data)?;
+
+            Ok(self.calibration.compensate(&data))
+        }
+
+        // Reads the sensor every ENVIRONMENT_SAMPLE_INTERVAL and stores the
+        // readings in the environment history, which averages them over the
+        // reporting window
+        pub fn set_measurement_timer(mut self) -> Result<EspTimer, EspError> {
+            let periodic_timer = EspTimerService::new()?.timer(move || match self.read() {
+                Ok(reading) => {
+                    (*ENVIRONMENT).lock().unwrap().store(reading);
+                }
+                Err(err) => warn!("failed to read BME280: {err}"),
+            })?;
+
+            periodic_timer.every(Duration::from_millis(ENVIRONMENT_SAMPLE_INTERVAL))?;
+
+            Ok(periodic_timer)
+        }
+
+        fn read_registers(&mut self, register: u8, buffer: &mut [u8]) -> Result<(), EspError> {
+            self.i2c
+                .lock()
+                .unwrap()
+                .write_read(BME280_ADDRESS, &[register], buffer, BLOCK)
+        }
+    }
+}
+
 pub mod anemometer {
 
-    // This value is incremented by the ISR
-    static ANEMOMETER_PULSCOUNT: AtomicU32 = AtomicU32::new(0);
+    // The anemometer pulses are either timestamped by a GPIO ISR, which
+    // gives the best resolution at light wind, or counted by the PCNT
+    // hardware counter (feature "pcnt") which doesn't need a CPU wake-up
+    // per pulse and provides a glitch filter.
+
+    // Pulse edges are timestamped and counted by the ISR
+    #[cfg(not(feature = "pcnt"))]
+    static ANEMOMETER_PULSES: Mutex<RefCell<PulseCapture>> = Mutex::new(RefCell::new(
+        PulseCapture::new(global_settings::PULSE_DEBOUNCE_INTERVAL),
+    ));
+    use super::wind_vane::As5600;
     use crate::global_settings;
     use crate::state::*;
     use crate::utils::errors::*;
+    use anemometer_core::live_stream::LiveSample;
+    use anemometer_core::plausibility::{PlausibilityFilter, PlausibilityLimits};
+    #[cfg(not(feature = "pcnt"))]
+    use anemometer_core::pulse::PulseCapture;
+    use anemometer_core::pulse::RotationEstimator;
+    use anemometer_core::pulse_source::{PulseReading, PulseSource};
+    #[cfg(not(feature = "pcnt"))]
+    use core::cell::RefCell;
+    #[cfg(not(feature = "pcnt"))]
+    use critical_section::Mutex;
     use esp_idf_hal::gpio::*;
     use esp_idf_hal::peripheral::Peripheral;
     use esp_idf_svc::timer::*;
     use esp_idf_sys::*;
-    use fixed::{types::extra::U4, FixedU16};
-    use std::sync::atomic::*;
-    use std::time::Duration;
+    use log::*;
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, SystemTime};
 
     pub struct AnemometerDriver<P>
     where
         P: Pin,
     {
         _pin: PinDriver<'static, P, Input>,
+        #[cfg(feature = "pcnt")]
+        pulse_counter: Option<pcnt::PulseCounter>,
+    }
+
+    // Rotation rate from the pulse edges timestamped by the GPIO ISR, which
+    // gives a much better resolution at light wind than counting
+    #[cfg(not(feature = "pcnt"))]
+    pub struct IsrPulseSource {
+        estimator: RotationEstimator,
+    }
+
+    #[cfg(not(feature = "pcnt"))]
+    impl PulseSource for IsrPulseSource {
+        fn read(&mut self) -> Option<PulseReading> {
+            // load the captured pulses and start a new capture
+            let capture = critical_section::with(|cs| ANEMOMETER_PULSES.borrow_ref_mut(cs).take());
+
+            Some(PulseReading {
+                rps: self.estimator.update(&capture, timestamp()),
+                bounced: capture.bounced,
+            })
+        }
+    }
+
+    // Rotation rate from the pulses counted by the PCNT hardware counter
+    #[cfg(feature = "pcnt")]
+    pub struct PcntPulseSource {
+        pulse_counter: pcnt::PulseCounter,
+        estimator: RotationEstimator,
+    }
+
+    #[cfg(feature = "pcnt")]
+    impl PulseSource for PcntPulseSource {
+        fn read(&mut self) -> Option<PulseReading> {
+            Some(PulseReading {
+                rps: self.estimator.update_count(
+                    self.pulse_counter.take(),
+                    global_settings::MEASUREMENT_INTERVAL as u32 * 1000,
+                ),
+                bounced: 0,
+            })
+        }
     }
 
     impl<P: InputPin + OutputPin> AnemometerDriver<P> {
+        #[cfg(not(feature = "pcnt"))]
         pub fn new(
             pin: impl Peripheral<P = P> + 'static,
         ) -> Result<AnemometerDriver<P>, InitError> {
             Ok(AnemometerDriver {
-                _pin: subscribe_pin(pin, count_pulse)?,
+                _pin: subscribe_pin(pin, record_pulse)?,
             })
         }
 
-        // This timer reads at a defined frequence the counter for rotation
-        // pulses (incremented by the ISR) and stores the values in the
-        // wind historian to calculating averages which gets send via
-        // MQTT messages.
-        pub fn set_measurement_timer(&mut self) -> Result<EspTimer, EspError> {
+        #[cfg(feature = "pcnt")]
+        pub fn new(
+            pin: impl Peripheral<P = P> + 'static,
+        ) -> Result<AnemometerDriver<P>, InitError> {
+            let pin = PinDriver::input(pin)?;
+            let pulse_counter =
+                pcnt::PulseCounter::new(pin.pin(), global_settings::PULSE_GLITCH_FILTER)?;
+
+            Ok(AnemometerDriver {
+                _pin: pin,
+                pulse_counter: Some(pulse_counter),
+            })
+        }
+
+        #[cfg(not(feature = "pcnt"))]
+        pub fn pulse_source(&mut self, pulses_per_revolution: u8) -> IsrPulseSource {
+            IsrPulseSource {
+                estimator: RotationEstimator::new(pulses_per_revolution),
+            }
+        }
+
+        // The pulse counter can only be handed out once
+        #[cfg(feature = "pcnt")]
+        pub fn pulse_source(&mut self, pulses_per_revolution: u8) -> PcntPulseSource {
+            PcntPulseSource {
+                pulse_counter: self.pulse_counter.take().unwrap(),
+                estimator: RotationEstimator::new(pulses_per_revolution),
+            }
+        }
+
+        // This timer reads at a defined frequence the rotation rate of the
+        // pulse source together with the wind vane angle and stores the
+        // values in the wind historian to calculating averages which gets
+        // send via MQTT messages. The calibrated speed also feeds the wind
+        // rose and the summaries.
+        pub fn set_measurement_timer(
+            &mut self,
+            mut wind_vane: As5600,
+            mut pulse_source: impl PulseSource + Send + 'static,
+        ) -> Result<EspTimer, EspError> {
+            match wind_vane.magnet_detected() {
+                Ok(true) => info!("wind vane magnet detected"),
+                Ok(false) => warn!("wind vane magnet not detected"),
+                Err(err) => error!("failed to read wind vane status: {err}"),
+            }
+
+            // in case a reading fails the last known direction is used
+            let mut direction = 0;
+            let mut plausibility_filter = PlausibilityFilter::new(PlausibilityLimits {
+                max_rps: global_settings::MAX_PLAUSIBLE_RPS,
+                max_step: global_settings::MAX_PLAUSIBLE_RPS_STEP,
+            });
+
             let periodic_timer = EspTimerService::new()?.timer(move || {
-                // load puls count and set to zero
-                let cnt = ANEMOMETER_PULSCOUNT.fetch_and(0, Ordering::Relaxed);
-
-                // We receive 2 pulses per rotatio, therefor the counter needs
-                // to be devided by 2. MEASUREMENT_INTERVAL needs to be in [ms]
-                #[allow(unused_variables)]
-                let rps = (FixedU16::<U4>::from_num(cnt >> 2)
-                    / (FixedU16::<U4>::from_num(global_settings::MEASUREMENT_INTERVAL as u16)
-                        / FixedU16::<U4>::from_num(1000)))
-                .to_num::<u16>();
-
-                // let rps = cnt as f32 / 2.0 / (MEASUREMENT_INTERVAL as u32) as f32;
-
-                // TODO: Remove once anemometer is connected
-                let rps = (unsafe { esp_random() } % 0xf) as u16;
-                let mut wind_historian = (*WIND_DATA_HISTORY).lock().unwrap();
-                wind_historian.store_measurement(rps, 0);
+                // sources of the firmware never run out of samples
+                let reading = pulse_source.read().unwrap_or_default();
+                plausibility_filter.pulses_bounced(reading.bounced);
+                let rps = plausibility_filter.filter(reading.rps);
+
+                match wind_vane.read_direction() {
+                    Ok(angle) => {
+                        direction = angle;
+                        plausibility_filter.vane_reading(true);
+                    }
+                    Err(_) => plausibility_filter.vane_reading(false),
+                }
+
+                let time_ms = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map_or(0, |duration| duration.as_millis() as u64);
+
+                let time = time_ms / 1000;
+                let (speed, gust) = {
+                    let mut wind_historian = (*WIND_DATA_HISTORY).lock().unwrap();
+                    wind_historian.store_measurement(rps, direction, time);
+                    (
+                        wind_historian.calibration().rps_to_kmh(rps),
+                        wind_historian.current_gust(),
+                    )
+                };
+                (*WIND_ROSE).lock().unwrap().add(speed, direction, time);
+                (*SUMMARIES).lock().unwrap().store(
+                    speed,
+                    gust,
+                    time,
+                    UTC_OFFSET.load(Ordering::Relaxed),
+                );
+                *(*SENSOR_HEALTH).lock().unwrap() = plausibility_filter.health();
+
+                // the samples are published by the send_task
+                let sample = LiveSample {
+                    time: time_ms,
+                    rps,
+                    speed,
+                    direction,
+                };
+                if (*LIVE_STREAM).lock().unwrap().record(sample) {
+                    LIVE_SAMPLE_SIGNAL.signal(());
+                }
             })?;
 
             periodic_timer.every(Duration::from_millis(global_settings::MEASUREMENT_INTERVAL))?;
@@ -245,10 +702,20 @@ pub mod anemometer {
         }
     }
 
-    fn count_pulse() {
-        ANEMOMETER_PULSCOUNT.fetch_add(1, Ordering::Relaxed);
+    #[cfg(not(feature = "pcnt"))]
+    fn record_pulse() {
+        let now = timestamp();
+        critical_section::with(|cs| ANEMOMETER_PULSES.borrow_ref_mut(cs).record(now));
     }
 
+    // Free running µs timer, wraps around after ~71 min which is handled
+    // by the RotationEstimator
+    #[cfg(not(feature = "pcnt"))]
+    fn timestamp() -> u32 {
+        unsafe { esp_timer_get_time() as u32 }
+    }
+
+    #[cfg(not(feature = "pcnt"))]
     fn subscribe_pin<'d, P: InputPin + OutputPin>(
         pin: impl Peripheral<P = P> + 'd,
         notify: impl Fn() + 'static,
@@ -264,4 +731,67 @@ pub mod anemometer {
         }
         Ok(pin)
     }
+
+    #[cfg(feature = "pcnt")]
+    mod pcnt {
+        use esp_idf_sys::*;
+
+        const PCNT_UNIT: pcnt_unit_t = pcnt_unit_t_PCNT_UNIT_0;
+        // The counter is reset to 0 by the hardware when the limit is reached
+        const COUNTER_LIMIT: i16 = i16::MAX;
+
+        // PCNT unit counting the rising edges of the hall sensor. The PCNT
+        // is clocked by the APB, which is stopped in light sleep, therefore
+        // light sleep is disabled by global_settings with this backend.
+        pub struct PulseCounter {
+            last_count: i16,
+        }
+
+        impl PulseCounter {
+            // glitch_filter is the max. length of a glitch in APB clock
+            // cycles (12.5 ns at 80 MHz, longer while the frequency is
+            // scaled down), the hardware supports up to 1023 cycles
+            pub fn new(gpio: i32, glitch_filter: u16) -> Result<PulseCounter, EspError> {
+                let config = pcnt_config_t {
+                    pulse_gpio_num: gpio,
+                    ctrl_gpio_num: PCNT_PIN_NOT_USED,
+                    lctrl_mode: pcnt_ctrl_mode_t_PCNT_MODE_KEEP,
+                    hctrl_mode: pcnt_ctrl_mode_t_PCNT_MODE_KEEP,
+                    pos_mode: pcnt_count_mode_t_PCNT_COUNT_INC,
+                    neg_mode: pcnt_count_mode_t_PCNT_COUNT_DIS,
+                    counter_h_lim: COUNTER_LIMIT,
+                    counter_l_lim: 0,
+                    unit: PCNT_UNIT,
+                    channel: pcnt_channel_t_PCNT_CHANNEL_0,
+                };
+
+                unsafe {
+                    esp!(pcnt_unit_config(&config))?;
+                    esp!(pcnt_set_filter_value(PCNT_UNIT, glitch_filter.min(1023)))?;
+                    esp!(pcnt_filter_enable(PCNT_UNIT))?;
+                    esp!(pcnt_counter_pause(PCNT_UNIT))?;
+                    esp!(pcnt_counter_clear(PCNT_UNIT))?;
+                    esp!(pcnt_counter_resume(PCNT_UNIT))?;
+                }
+
+                Ok(PulseCounter { last_count: 0 })
+            }
+
+            // Number of pulses since the last call. The counter is never
+            // cleared, so no pulse gets lost between reading and clearing.
+            pub fn take(&mut self) -> u32 {
+                let mut count: i16 = 0;
+                if let Err(err) = esp!(unsafe { pcnt_get_counter_value(PCNT_UNIT, &mut count) }) {
+                    log::error!("failed to read pulse counter: {err}");
+                    return 0;
+                }
+
+                let pulses =
+                    (count as i32 - self.last_count as i32).rem_euclid(COUNTER_LIMIT as i32);
+                self.last_count = count;
+
+                pulses as u32
+            }
+        }
+    }
 }
@@ -30,19 +30,64 @@
  * limitations under the License.
  */
 use crate::data_processing::*;
+use crate::global_settings;
+use anemometer_core::command::CommandResponse;
+use anemometer_core::environment::EnvironmentHistory;
+use anemometer_core::jobs::JobStatusUpdate;
+use anemometer_core::live_stream::LiveStream;
+use anemometer_core::ota::OtaStatus;
+use anemometer_core::plausibility::SensorHealth;
+use anemometer_core::shadow::ShadowConfig;
+use anemometer_core::summary::SummaryTracker;
+use anemometer_core::telemetry::TELEMETRY_SCHEMA_VERSION;
+use anemometer_core::wind_rose::WindRoseTracker;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::pubsub::PubSubChannel;
+use embassy_sync::signal::Signal;
 use lazy_static::lazy_static;
+use std::sync::atomic::{AtomicI32, AtomicU64, AtomicU8};
 use std::sync::{Arc, Mutex};
 
 lazy_static! {
     pub static ref WIND_DATA_HISTORY: Arc<Mutex<WindDataHistory>> =
-        Arc::new(Mutex::new(WindDataHistory::default()));
+        Arc::new(Mutex::new(WindDataHistory::new(
+            global_settings::MEASUREMENT_INTERVAL,
+            &global_settings::STATISTICS_WINDOWS
+        )));
+    // raw samples of the live stream started by the start_stream command
+    pub static ref LIVE_STREAM: Arc<Mutex<LiveStream>> = Arc::new(Mutex::new(LiveStream::new()));
+    // wind rose of the running and of the last completed period
+    pub static ref WIND_ROSE: Arc<Mutex<WindRoseTracker>> = Arc::new(Mutex::new(
+        WindRoseTracker::new(global_settings::MEASUREMENT_INTERVAL)
+    ));
+    // hourly and daily summaries in local time
+    pub static ref SUMMARIES: Arc<Mutex<SummaryTracker>> = Arc::new(Mutex::new(
+        SummaryTracker::new(global_settings::MEASUREMENT_INTERVAL)
+    ));
+    // readings of the optional environmental sensor within the reporting
+    // window
+    pub static ref ENVIRONMENT: Arc<Mutex<EnvironmentHistory>> = Arc::new(Mutex::new(
+        EnvironmentHistory::new(global_settings::STATISTICS_WINDOWS[0])
+    ));
+    // status of the plausibility filter in front of the wind historian
+    pub static ref SENSOR_HEALTH: Arc<Mutex<SensorHealth>> =
+        Arc::new(Mutex::new(SensorHealth::default()));
 }
 
 use serde::{Deserialize, Serialize};
 
-pub type OtaUrl = heapless::String<128>;
+// Data reporting interval [s], can be changed through the device shadow
+pub static REPORTING_INTERVAL: AtomicU64 = AtomicU64::new(global_settings::DATA_REPORTING_INTERVAL);
+// Offset of the local time zone to UTC [s], the summaries roll over at
+// the local hour and midnight
+pub static UTC_OFFSET: AtomicI32 = AtomicI32::new(0);
+// Version of the telemetry schema used for the shadow update
+pub static TELEMETRY_SCHEMA: AtomicU8 = AtomicU8::new(TELEMETRY_SCHEMA_VERSION);
+
+pub use anemometer_core::mqtt_msg::OtaUrl;
+
+// Raised by the measurement timer once a live sample is waiting
+pub static LIVE_SAMPLE_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
 pub static NETWORK_EVENT_CHANNEL: PubSubChannel<
     CriticalSectionRawMutex,
@@ -93,4 +138,35 @@ pub enum ApplicationStateChange {
 #[allow(dead_code)]
 pub enum ApplicationDataChange {
     ReportWindData,
+    // settings applied from the desired state of the device shadow
+    ReportConfig(ShadowConfig),
+    // status of an AWS IoT job execution, the firmware update is started
+    // once IN_PROGRESS has been reported. The job record of a completed job
+    // is removed once the status has been published or queued.
+    ReportJobStatus {
+        job_id: String,
+        update: JobStatusUpdate,
+        firmware: Option<OtaUrl>,
+        completed: bool,
+    },
+    // progress and result of a firmware update, the mqtt tasks keep running
+    // during the update to publish it
+    ReportOtaStatus(OtaStatus),
+    // response to a command, the default response topic is used if the
+    // request doesn't name a valid one
+    ReportCommandResponse {
+        topic: Option<String>,
+        response: CommandResponse,
+        effect: CommandEffect,
+    },
+}
+
+// Side effect of a command, executed by the send_task once the response
+// has been published
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CommandEffect {
+    None,
+    Restart,
+    FactoryReset,
+    OtaUpdate(OtaUrl),
 }
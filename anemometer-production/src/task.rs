@@ -33,3 +33,4 @@ pub mod httpd;
 pub mod mqtt;
 pub mod ota;
 pub mod publisher;
+pub mod summary;
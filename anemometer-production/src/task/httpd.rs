@@ -29,9 +29,12 @@
  * See the License for the specific language governing permissions and
  * limitations under the License.
  */
+use crate::configuration::UnitSettings;
 use crate::data_processing::*;
 use crate::services::http::*;
 use crate::state::*;
+use crate::units::SpeedUnit;
+use anemometer_core::environment::EnvironmentReport;
 use embassy_futures::select::{select, Either};
 use log::*;
 
@@ -84,14 +87,36 @@ pub async fn http_server_task() {
                 if let Err(err) = s.fn_handler("/", embedded_svc::http::Method::Get, move |req| {
                     let mut avg_speed = 0.0;
                     let mut wind_gust = 0.0;
+                    let mut avg_direction = 0.0;
+                    let mut windows = Vec::new();
+                    let mut environment = None;
                     let mut headers = Headers::<1>::new();
                     headers.set_cache_control("no-store");
 
                     if let Ok(wind_historian) = (*WIND_DATA_HISTORY).lock() {
                         avg_speed = wind_historian.avg_speed();
                         wind_gust = wind_historian.gust_speed();
+                        avg_direction = wind_historian.avg_direction();
+                        windows = match requested_window(req.uri()) {
+                            Some(window) => wind_historian
+                                .window_statistics(window)
+                                .into_iter()
+                                .collect(),
+                            None => wind_historian.statistics(),
+                        };
                     };
-                    let html = windspeed(avg_speed, wind_gust);
+                    if let Ok(history) = (*ENVIRONMENT).lock() {
+                        environment = history.report(avg_speed);
+                    }
+                    let units = *crate::UNITS.lock().unwrap();
+                    let html = windspeed(
+                        units,
+                        avg_speed,
+                        wind_gust,
+                        avg_direction,
+                        &windows,
+                        environment,
+                    );
 
                     let mut resp = req.into_response(200, None, headers.as_slice())?;
                     resp.write_all(html.as_bytes())?;
@@ -107,6 +132,101 @@ pub async fn http_server_task() {
                         esp_idf_sys::esp_restart();
                     }
                 }
+
+                // Wind statistics as JSON. Without query all configured
+                // windows are returned, /api/wind?window=300 returns the
+                // statistics for the last 5 min. Speeds are given in the
+                // configured unit (m/s if Beaufort is configured).
+                if let Err(err) =
+                    s.fn_handler("/api/wind", embedded_svc::http::Method::Get, move |req| {
+                        let mut headers = Headers::<2>::new();
+                        headers.set_cache_control("no-store");
+                        headers.set_content_type("application/json");
+
+                        let speed_unit = crate::UNITS.lock().unwrap().speed_unit;
+                        // Err is the status of the failed request
+                        let json = if let Ok(wind_historian) = (*WIND_DATA_HISTORY).lock() {
+                            match requested_window(req.uri()) {
+                                Some(window) if window > wind_historian.max_window() => Err(400),
+                                Some(window) => wind_historian
+                                    .window_statistics(window)
+                                    .map(|stats| {
+                                        serde_json::to_string(&stats.convert(speed_unit)).unwrap()
+                                    })
+                                    .ok_or(404),
+                                None => Ok(serde_json::to_string(&convert_windows(
+                                    &wind_historian.statistics(),
+                                    speed_unit,
+                                ))
+                                .unwrap()),
+                            }
+                        } else {
+                            Err(404)
+                        };
+
+                        match json {
+                            Ok(json) => {
+                                let mut resp = req.into_response(200, None, headers.as_slice())?;
+                                resp.write_all(json.as_bytes())?;
+                            }
+                            Err(status) => {
+                                req.into_status_response(status)?;
+                            }
+                        }
+
+                        info!("Processing '/api/wind' request");
+                        Ok(())
+                    })
+                {
+                    info!(
+                        "http_server_task: failed to register http handler /api/wind: {:?} - restarting device",
+                        err
+                    );
+                    unsafe {
+                        esp_idf_sys::esp_restart();
+                    }
+                }
+
+                // Wind rose of the running and of the last completed period
+                // as JSON, the speed bins are given in km/h
+                if let Err(err) = s.fn_handler(
+                    "/api/windrose",
+                    embedded_svc::http::Method::Get,
+                    move |req| {
+                        let mut headers = Headers::<2>::new();
+                        headers.set_cache_control("no-store");
+                        headers.set_content_type("application/json");
+
+                        let json = if let Ok(tracker) = (*WIND_ROSE).lock() {
+                            serde_json::to_string(&serde_json::json!({
+                                "speedUnit": "km/h",
+                                "current": tracker.current(),
+                                "last": tracker.last(),
+                            }))
+                            .ok()
+                        } else {
+                            None
+                        };
+
+                        if let Some(json) = json {
+                            let mut resp = req.into_response(200, None, headers.as_slice())?;
+                            resp.write_all(json.as_bytes())?;
+                        } else {
+                            req.into_status_response(500)?;
+                        }
+
+                        info!("Processing '/api/windrose' request");
+                        Ok(())
+                    },
+                ) {
+                    info!(
+                        "http_server_task: failed to register http handler /api/windrose: {:?} - restarting device",
+                        err
+                    );
+                    unsafe {
+                        esp_idf_sys::esp_restart();
+                    }
+                }
             }
             Some(NetworkStateChange::WifiDisconnected) => {
                 info!("http_server_task: stopping httpd");
@@ -136,9 +256,69 @@ fn templated(content: impl AsRef<str>) -> String {
     )
 }
 
-fn windspeed(speed: f32, gust: f32) -> String {
-    templated(format!(
-        "Wind speed: {:.2} km/h\nWind gust: {:.2} km/h",
-        speed, gust
-    ))
+fn windspeed(
+    units: UnitSettings,
+    speed: f32,
+    gust: f32,
+    direction: f32,
+    windows: &[WindowStatistics],
+    environment: Option<EnvironmentReport>,
+) -> String {
+    let speed_unit = units.speed_unit;
+    let decimals = speed_unit.decimals();
+    let mut content = format!(
+        "Wind speed: {:.*} {}\nWind gust: {:.*} {}\nWind direction: {:.0}° {}\n",
+        decimals,
+        speed_unit.convert(speed),
+        speed_unit.symbol(),
+        decimals,
+        speed_unit.convert(gust),
+        speed_unit.symbol(),
+        direction,
+        units.compass_points.name(direction)
+    );
+
+    let unit = speed_unit.linear_unit().symbol();
+    for stats in convert_windows(windows, speed_unit) {
+        content.push_str(&format!(
+            "<p>{} sec: mean {:.2} {unit}, min {:.2} {unit}, max {:.2} {unit}, std dev {:.2} {unit}, turbulence intensity {:.2}, gust factor {:.2}, direction {:.0}°</p>\n",
+            stats.window,
+            stats.mean,
+            stats.min,
+            stats.max,
+            stats.std_dev,
+            stats.turbulence_intensity,
+            stats.gust_factor,
+            stats.direction
+        ));
+    }
+
+    if let Some(environment) = environment {
+        content.push_str(&format!(
+            "<p>Temperature: {:.1} °C, humidity {:.0} %, pressure {:.1} hPa, air density {:.3} kg/m³</p>\n",
+            environment.temperature,
+            environment.humidity,
+            environment.pressure,
+            environment.air_density
+        ));
+        if let Some(wind_chill) = environment.wind_chill {
+            content.push_str(&format!("<p>Wind chill: {wind_chill:.1} °C</p>\n"));
+        }
+    }
+
+    templated(content)
+}
+
+fn convert_windows(windows: &[WindowStatistics], unit: SpeedUnit) -> Vec<WindowStatistics> {
+    windows.iter().map(|stats| stats.convert(unit)).collect()
+}
+
+// Extracts the window length [sec] from a request uri like /?window=600
+fn requested_window(uri: &str) -> Option<u64> {
+    uri.split_once('?')?
+        .1
+        .split('&')
+        .find_map(|param| param.strip_prefix("window="))?
+        .parse()
+        .ok()
 }
@@ -30,22 +30,59 @@
  * limitations under the License.
  */
 
+use crate::configuration::{self, BrokerSettings};
 use crate::data_processing::*;
-use crate::mqtt_msg::{AWSShadowUpdate, MqttCommand};
+use crate::global_settings;
+use crate::mqtt_msg::{
+    format_wind_report_msg, AWSShadowUpdate, MqttCommand, OtaStatusUpdate, OtaUrl, SummaryUpdate,
+    WindRoseUpdate, MQTT_TOPIC_POSTFIX_COMMAND_RESPONSE, MQTT_TOPIC_POSTFIX_JOBS,
+    MQTT_TOPIC_POSTFIX_JOBS_GET_NEXT, MQTT_TOPIC_POSTFIX_JOBS_GET_NEXT_ACCEPTED,
+    MQTT_TOPIC_POSTFIX_JOBS_NOTIFY_NEXT, MQTT_TOPIC_POSTFIX_JOB_UPDATE,
+    MQTT_TOPIC_POSTFIX_OTA_STATUS, MQTT_TOPIC_POSTFIX_PRESENCE, MQTT_TOPIC_POSTFIX_STATE,
+    MQTT_TOPIC_POSTFIX_SUMMARY_DAILY, MQTT_TOPIC_POSTFIX_SUMMARY_HOURLY,
+    MQTT_TOPIC_POSTFIX_WIND_DIRECTION, MQTT_TOPIC_POSTFIX_WIND_LIVE, MQTT_TOPIC_POSTFIX_WIND_ROSE,
+    MQTT_TOPIC_POSTFIX_WIND_SPEED,
+};
+use crate::services::{reset_reason, wifi_rssi};
 use crate::state::*;
 use crate::utils::datetime;
 use crate::utils::error;
-use embassy_futures::select::{select, select3, Either, Either3};
+use crate::utils::job_store;
+use crate::utils::report_spill::NvsReportSpill;
+use anemometer_core::command::{
+    Command, CommandRequest, COMMAND_OTA_UPDATE, COMMAND_SYSTEM_RESTART,
+};
+use anemometer_core::homeassistant::{discovery_messages, HomeAssistantState};
+use anemometer_core::jobs::{self, JobAction, JobStatus, JobStatusUpdate};
+use anemometer_core::ota::OtaStatus;
+use anemometer_core::plausibility::{SensorHealth, SensorStatus};
+use anemometer_core::presence::Presence;
+use anemometer_core::report_queue::{QueuedReport, ReportQueue};
+use anemometer_core::shadow::{CalibrationConfig, ShadowConfig, UnitsConfig};
+use anemometer_core::summary::SummaryPeriod;
+use anemometer_core::telemetry::{
+    format_rfc3339, round_decimals, TelemetrySchema, WindDirectionReport, WindReport,
+    WindRoseReport, WindSpeedReport, WindSummaryReport, DIRECTION_UNIT, TELEMETRY_SCHEMA_VERSION,
+};
+use anemometer_core::units::{CompassPoints, SpeedUnit};
+use embassy_futures::select::{select, select4, Either, Either4};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
 use embedded_svc::mqtt::client::asynch::{Client, Connection, Event, Publish, QoS};
 use log::*;
+use serde_json::json;
+use std::sync::atomic::Ordering;
 use std::time::SystemTime;
 
 static MQTT_CONNECT_SIGNAL: Signal<CriticalSectionRawMutex, bool> = Signal::new();
 
 pub async fn receive_task(mut connection: impl Connection<Message = Option<MqttCommand>>) {
     let mut app_event = APPLICATION_EVENT_CHANNEL.subscriber().unwrap();
+    // job of the firmware update started by this boot, further notifications
+    // of this job are ignored until the restart
+    let mut started_job: Option<String> = None;
+    let mut ota_running = false;
     info!("Receive Task Started");
 
     loop {
@@ -62,20 +99,85 @@ pub async fn receive_task(mut connection: impl Connection<Message = Option<MqttC
             info!("receive_task [MQTT/CONNECTION]: {:?}", message);
 
             if let Ok(Event::Received(Some(cmd))) = &message {
+                if ota_running {
+                    info!("receive_task OTA update running, ignoring {:?}", cmd);
+                    continue;
+                }
                 match cmd {
                     MqttCommand::ExecOTAUpdate(url) => {
                         info!(
                             "receive_task MQTT received OTA update request. url = {}",
                             url
                         );
-                        let publisher = APPLICATION_EVENT_CHANNEL.publisher().unwrap();
-                        let data = ApplicationStateChange::OTAUpdateRequest(url.clone());
-                        publisher.publish(data).await;
+                        let request = CommandRequest::new(
+                            COMMAND_OTA_UPDATE,
+                            Command::OtaUpdate(url.clone()),
+                        );
+                        handle_command_request(&request).await;
                     }
                     MqttCommand::SystemRestart => {
                         info!("receive_task MQTT received system restart request");
-                        unsafe {
-                            esp_idf_sys::esp_restart();
+                        let request =
+                            CommandRequest::new(COMMAND_SYSTEM_RESTART, Command::SystemRestart);
+                        handle_command_request(&request).await;
+                    }
+                    MqttCommand::Request(request) => {
+                        info!("receive_task MQTT received command request {:?}", request);
+                        handle_command_request(request).await;
+                    }
+                    MqttCommand::ShadowDelta(desired) => {
+                        info!("receive_task MQTT received shadow delta {:?}", desired);
+                        let config = desired.clone().validate();
+                        if !config.is_empty() {
+                            let config = apply_shadow_config(config);
+                            let publisher = APPLICATION_DATA_CHANNEL.publisher().unwrap();
+                            publisher
+                                .publish(ApplicationDataChange::ReportConfig(config))
+                                .await;
+                        }
+                    }
+                    MqttCommand::JobExecution(execution) => {
+                        info!("receive_task MQTT received job execution {:?}", execution);
+                        if started_job.as_ref() == Some(&execution.job_id) {
+                            continue;
+                        }
+
+                        let record = job_store::load();
+                        let publisher = APPLICATION_DATA_CHANNEL.publisher().unwrap();
+                        match jobs::next_action(
+                            execution,
+                            record.as_ref(),
+                            env!("CARGO_PKG_VERSION"),
+                        ) {
+                            JobAction::StartUpdate { firmware, record } => {
+                                let mut url = OtaUrl::new();
+                                let (update, firmware) = if url.push_str(&firmware).is_ok() {
+                                    job_store::save(&record);
+                                    started_job = Some(record.job_id);
+                                    (JobStatusUpdate::new(JobStatus::InProgress), Some(url))
+                                } else {
+                                    (JobStatusUpdate::failed("InvalidJobDocument"), None)
+                                };
+                                publisher
+                                    .publish(ApplicationDataChange::ReportJobStatus {
+                                        job_id: execution.job_id.clone(),
+                                        update,
+                                        firmware,
+                                        completed: false,
+                                    })
+                                    .await;
+                            }
+                            JobAction::Complete(update) => {
+                                publisher
+                                    .publish(ApplicationDataChange::ReportJobStatus {
+                                        job_id: execution.job_id.clone(),
+                                        update,
+                                        firmware: None,
+                                        completed: true,
+                                    })
+                                    .await;
+                            }
+                            JobAction::Ignore => {}
                         }
                     }
                 }
@@ -86,31 +188,228 @@ pub async fn receive_task(mut connection: impl Connection<Message = Option<MqttC
             }
         }
 
+        // The connection events still need to be processed, otherwise the
+        // send_task can't report the progress of the update
         if let Some(ApplicationStateChange::OTAUpdateStarted) = app_state_change {
-            info!("receive_task OTA Update started shutting down mqtt receive_task");
-            // No clean-up of the mqtt object here as this has been done in
-            // send_task
-            break;
+            info!("receive_task OTA Update started, ignoring commands until the restart");
+            ota_running = true;
+        }
+    }
+}
+
+// Executes a command and publishes the response. Side effects like a
+// restart are left to the send_task, so the response is published first.
+async fn handle_command_request(request: &CommandRequest) {
+    let publisher = APPLICATION_DATA_CHANNEL.publisher().unwrap();
+
+    let (response, effect) = match &request.command {
+        Ok(Command::GetStatus) => (request.ok(Some(device_status())), CommandEffect::None),
+        Ok(Command::GetConfig) => (
+            request.ok(Some(serde_json::to_value(current_config()).unwrap())),
+            CommandEffect::None,
+        ),
+        Ok(Command::SetLogLevel(level)) => {
+            let config = apply_shadow_config(ShadowConfig {
+                log_level: Some(level.clone()),
+                ..Default::default()
+            });
+            // keeps the reported state of the device shadow up to date
+            publisher
+                .publish(ApplicationDataChange::ReportConfig(config))
+                .await;
+            (request.ok(None), CommandEffect::None)
+        }
+        Ok(Command::ReportNow) => {
+            publisher
+                .publish(ApplicationDataChange::ReportWindData)
+                .await;
+            (request.ok(None), CommandEffect::None)
+        }
+        Ok(Command::StartStream(stream)) => {
+            info!(
+                "receive_task live stream for {} min, decimation {}",
+                stream.minutes, stream.decimation
+            );
+            LIVE_STREAM
+                .lock()
+                .unwrap()
+                .start(*stream, global_settings::MEASUREMENT_INTERVAL);
+            (
+                request.ok(Some(json!({
+                    "duration": stream.minutes as u64 * 60,
+                    "interval": stream.decimation as u64 * global_settings::MEASUREMENT_INTERVAL,
+                }))),
+                CommandEffect::None,
+            )
+        }
+        Ok(Command::StopStream) => {
+            LIVE_STREAM.lock().unwrap().stop();
+            (request.ok(None), CommandEffect::None)
         }
+        Ok(Command::FactoryReset) => (request.ok(None), CommandEffect::FactoryReset),
+        Ok(Command::SystemRestart) => (request.ok(None), CommandEffect::Restart),
+        Ok(Command::OtaUpdate(url)) => (request.ok(None), CommandEffect::OtaUpdate(url.clone())),
+        Err(err) => {
+            warn!("receive_task rejected command {}: {:?}", request.cmd, err);
+            (request.error(*err), CommandEffect::None)
+        }
+    };
+
+    publisher
+        .publish(ApplicationDataChange::ReportCommandResponse {
+            topic: request
+                .response_topic(&device_topic_prefix())
+                .map(String::from),
+            response,
+            effect,
+        })
+        .await;
+}
+
+// <topic_prefix>/<device_id>, the response topics need to be below it
+fn device_topic_prefix() -> String {
+    let aws_config = super::super::AWSCONFIG.lock().unwrap();
+    format!("{}/{}", aws_config.topic_prefix, aws_config.device_id)
+}
+
+fn device_status() -> serde_json::Value {
+    let sensor_health = (*SENSOR_HEALTH)
+        .lock()
+        .map(|sensor_health| *sensor_health)
+        .unwrap_or_default();
+    // remaining duration of the live stream [s]
+    let live_stream = LIVE_STREAM
+        .lock()
+        .unwrap()
+        .remaining(global_settings::MEASUREMENT_INTERVAL);
+
+    json!({
+        "firmware": env!("CARGO_PKG_VERSION"),
+        "uptime": unsafe { esp_idf_sys::esp_timer_get_time() } / 1_000_000,
+        "freeHeap": unsafe { esp_idf_sys::esp_get_free_heap_size() },
+        "rssi": wifi_rssi(),
+        "sensorHealth": sensor_health,
+        "liveStream": live_stream,
+    })
+}
+
+// Settings in use, in the format of the device shadow
+fn current_config() -> ShadowConfig {
+    let units = *super::super::UNITS.lock().unwrap();
+    let calibration = (*WIND_DATA_HISTORY)
+        .lock()
+        .map(|wind_historian| CalibrationConfig::from(wind_historian.calibration()))
+        .ok();
+
+    ShadowConfig {
+        reporting_interval: Some(REPORTING_INTERVAL.load(Ordering::Relaxed) as u32),
+        units: Some(UnitsConfig {
+            speed_unit: Some(units.speed_unit.symbol().into()),
+            compass_points: Some(units.compass_points.count()),
+        }),
+        calibration,
+        log_level: Some(log::max_level().as_str().to_ascii_lowercase()),
+        timezone: std::env::var("TZ").ok(),
     }
 }
 
+// Applies the validated desired state of the device shadow and persists it
+// in the conf partition. Returns the settings to be reported back.
+fn apply_shadow_config(mut config: ShadowConfig) -> ShadowConfig {
+    if let Err(err) = configuration::save_shadow_config("conf", &config) {
+        error!("Failed to save shadow configuration: {err}");
+    }
+
+    if let Some(interval) = config.reporting_interval {
+        REPORTING_INTERVAL.store(interval as u64, Ordering::Relaxed);
+    }
+
+    if let Some(units) = &config.units {
+        let mut settings = super::super::UNITS.lock().unwrap();
+        if let Some(speed_unit) = units.speed_unit.as_deref().and_then(|u| u.parse().ok()) {
+            settings.speed_unit = speed_unit;
+        }
+        if let Some(compass_points) = units.compass_points.and_then(CompassPoints::from_count) {
+            settings.compass_points = compass_points;
+        }
+    }
+
+    if let Some(calibration) = &mut config.calibration {
+        // the pulses per revolution are used by the pulse source which is
+        // created during startup, they are reported after the restart
+        if let Some(ppr) = calibration.pulses_per_revolution.take() {
+            warn!("Pulses per revolution changed to {ppr}, restart required");
+        }
+        if let Ok(mut wind_historian) = (*WIND_DATA_HISTORY).lock() {
+            let mut current = wind_historian.calibration().clone();
+            calibration.apply(&mut current);
+            wind_historian.set_calibration(current);
+        }
+    }
+    if config.calibration == Some(CalibrationConfig::default()) {
+        config.calibration = None;
+    }
+
+    if let Some(level) = config.log_level_filter() {
+        super::super::set_log_level(level);
+    }
+
+    if let Some(timezone) = &config.timezone {
+        datetime::set_timezone(timezone);
+    }
+
+    info!("Applied shadow configuration {:?}", config);
+
+    config
+}
+
 // send will react on application state change event and then send the MQTT message
 // the application state change event will be fired if new wind data is availbale.
 // the requence in which MQTT messages are send depends on how often the application
 // state change events gets fired.
 // we are not implementing explicit re-connect logic, as this is already implemented
-// in ESP IDF for MQTT.
-pub async fn send_task<const L: usize>(mut mqtt: impl Client + Publish) {
+// in ESP IDF for MQTT. Reports which can't be published while disconnected are
+// queued and replayed in order after the reconnect.
+pub async fn send_task<const L: usize>(
+    mut mqtt: impl Client + Publish,
+    broker: &'static BrokerSettings,
+) {
     let mut connected = false;
+    let mut ota_running = false;
+    // published once after the start, confirms the result of an OTA update
+    let mut running_slot_status = super::ota::running_slot_status();
     info!("Send Task Started");
 
     let mut app_event = APPLICATION_EVENT_CHANNEL.subscriber().unwrap();
     let mut app_data = APPLICATION_DATA_CHANNEL.subscriber().unwrap();
     let mut cmd_topic = String::new();
     let mut shadow_update_topic = String::new();
+    let mut shadow_delta_topic = String::new();
+    let mut jobs_topic_prefix = String::new();
+    let mut wind_rose_topic = String::new();
+    let mut wind_speed_topic = String::new();
+    let mut wind_direction_topic = String::new();
+    let mut summary_topic_prefix = String::new();
+    let mut ota_status_topic = String::new();
+    let mut state_topic = String::new();
+    let mut presence_topic = String::new();
+    let mut command_response_topic = String::new();
+    let mut live_topic = String::new();
     let mut device_id = String::new();
     let mut boot_timestamp = datetime::get_datetime().unwrap();
+    let mut boot_epoch = 0;
+    let report_spill = match NvsReportSpill::new(
+        global_settings::REPORT_QUEUE_PARTITION,
+        global_settings::REPORT_QUEUE_FLASH_CAPACITY,
+    ) {
+        Ok(spill) => Some(spill),
+        Err(err) => {
+            warn!("No flash for the report queue, queueing in RAM only: {err}");
+            None
+        }
+    };
+    let mut report_queue =
+        ReportQueue::new(global_settings::REPORT_QUEUE_RAM_CAPACITY, report_spill);
 
     {
         let aws_config = super::super::AWSCONFIG.lock().unwrap();
@@ -129,42 +428,154 @@ pub async fn send_task<const L: usize>(mut mqtt: impl Client + Publish) {
         shadow_update_topic.push_str(&aws_config.device_id);
         shadow_update_topic.push_str(&aws_config.shadow_update_postfix);
         info!("posting to {shadow_update_topic}");
+
+        shadow_delta_topic.push_str(&aws_config.things_prefix);
+        shadow_delta_topic.push('/');
+        shadow_delta_topic.push_str(&aws_config.device_id);
+        shadow_delta_topic.push_str(&aws_config.shadow_delta_postfix);
+
+        jobs_topic_prefix.push_str(&aws_config.things_prefix);
+        jobs_topic_prefix.push('/');
+        jobs_topic_prefix.push_str(&aws_config.device_id);
+
+        wind_rose_topic.push_str(&aws_config.topic_prefix);
+        wind_rose_topic.push('/');
+        wind_rose_topic.push_str(&aws_config.device_id);
+        wind_rose_topic.push_str(MQTT_TOPIC_POSTFIX_WIND_ROSE);
+
+        wind_speed_topic.push_str(&aws_config.topic_prefix);
+        wind_speed_topic.push('/');
+        wind_speed_topic.push_str(&aws_config.device_id);
+        wind_speed_topic.push_str(MQTT_TOPIC_POSTFIX_WIND_SPEED);
+
+        wind_direction_topic.push_str(&aws_config.topic_prefix);
+        wind_direction_topic.push('/');
+        wind_direction_topic.push_str(&aws_config.device_id);
+        wind_direction_topic.push_str(MQTT_TOPIC_POSTFIX_WIND_DIRECTION);
+
+        summary_topic_prefix.push_str(&aws_config.topic_prefix);
+        summary_topic_prefix.push('/');
+        summary_topic_prefix.push_str(&aws_config.device_id);
+
+        ota_status_topic.push_str(&aws_config.topic_prefix);
+        ota_status_topic.push('/');
+        ota_status_topic.push_str(&aws_config.device_id);
+        ota_status_topic.push_str(MQTT_TOPIC_POSTFIX_OTA_STATUS);
+
+        state_topic.push_str(&aws_config.topic_prefix);
+        state_topic.push('/');
+        state_topic.push_str(&aws_config.device_id);
+        state_topic.push_str(MQTT_TOPIC_POSTFIX_STATE);
+
+        presence_topic.push_str(&aws_config.topic_prefix);
+        presence_topic.push('/');
+        presence_topic.push_str(&aws_config.device_id);
+        presence_topic.push_str(MQTT_TOPIC_POSTFIX_PRESENCE);
+
+        command_response_topic.push_str(&aws_config.topic_prefix);
+        command_response_topic.push('/');
+        command_response_topic.push_str(&aws_config.device_id);
+        command_response_topic.push_str(MQTT_TOPIC_POSTFIX_COMMAND_RESPONSE);
+
+        live_topic.push_str(&aws_config.topic_prefix);
+        live_topic.push('/');
+        live_topic.push_str(&aws_config.device_id);
+        live_topic.push_str(MQTT_TOPIC_POSTFIX_WIND_LIVE);
     }
 
+    // kept across the passes of the loop, otherwise frequent events like
+    // the live samples would restart the interval over and over
+    let mut replay_deadline: Option<Instant> = None;
+
     loop {
-        let (conn_state, app_state_change, app_data) = match select3(
+        if connected && !report_queue.is_empty() {
+            replay_deadline.get_or_insert_with(|| {
+                Instant::now() + Duration::from_millis(global_settings::REPORT_REPLAY_INTERVAL)
+            });
+        } else {
+            replay_deadline = None;
+        }
+
+        let (conn_state, app_state_change, app_data, replay_due, live_due) = match select4(
             MQTT_CONNECT_SIGNAL.wait(),
             app_event.next_message_pure(),
             app_data.next_message_pure(),
+            select(replay_tick(replay_deadline), LIVE_SAMPLE_SIGNAL.wait()),
         )
         .await
         {
-            Either3::First(conn_state) => {
+            Either4::First(conn_state) => {
                 info!("send_task recv MQTT_CONNECT_SIGNAL");
-                (Some(conn_state), None, None)
+                (Some(conn_state), None, None, false, false)
             }
-            Either3::Second(app_state_change) => {
+            Either4::Second(app_state_change) => {
                 info!("send_task recv app_state_change");
-                (None, Some(app_state_change), None)
+                (None, Some(app_state_change), None, false, false)
             }
-            Either3::Third(app_data) => {
+            Either4::Third(app_data) => {
                 info!("send_task recv app_state_change");
-                (None, None, Some(app_data))
+                (None, None, Some(app_data), false, false)
             }
+            Either4::Fourth(Either::First(_)) => (None, None, None, true, false),
+            Either4::Fourth(Either::Second(_)) => (None, None, None, false, true),
         };
 
+        let mut reports = Vec::new();
+        let mut job_completed = false;
+
         if let Some(new_conn_state) = conn_state {
             if new_conn_state {
-                info!("send_task MQTT is now connected, subscribing {}", cmd_topic);
-                match mqtt.subscribe(cmd_topic.as_str(), QoS::AtLeastOnce).await {
-                    Ok(_) => {
-                        connected = true;
-                    }
-                    Err(err) => {
-                        error!("Subscribe failed: {:?}", err);
+                connected = true;
+                let jobs_notify_topic =
+                    format!("{jobs_topic_prefix}{MQTT_TOPIC_POSTFIX_JOBS_NOTIFY_NEXT}");
+                let jobs_next_topic =
+                    format!("{jobs_topic_prefix}{MQTT_TOPIC_POSTFIX_JOBS_GET_NEXT_ACCEPTED}");
+                let mut topics = vec![&cmd_topic];
+                if broker.is_aws_iot() {
+                    topics.extend([&shadow_delta_topic, &jobs_notify_topic, &jobs_next_topic]);
+                }
+                info!("send_task MQTT is now connected, subscribing {:?}", topics);
+                for topic in topics {
+                    if let Err(err) = mqtt.subscribe(topic.as_str(), QoS::AtLeastOnce).await {
+                        error!("Subscribe to {topic} failed: {:?}", err);
                         connected = false;
                     }
                 }
+
+                // fetch the pending job, this resumes a firmware update job
+                // started before the restart
+                if connected && broker.is_aws_iot() {
+                    let request = QueuedReport {
+                        epoch: 0,
+                        topic: format!("{jobs_topic_prefix}{MQTT_TOPIC_POSTFIX_JOBS_GET_NEXT}"),
+                        payload: String::from("{}"),
+                    };
+                    connected = publish(&mut mqtt, &request).await;
+                }
+
+                // replaces the retained last will of the broker
+                if connected {
+                    let birth =
+                        Presence::online(env!("CARGO_PKG_VERSION"), boot_time(), reset_reason());
+                    connected =
+                        publish_retained(&mut mqtt, &presence_topic, &birth.format_msg()).await;
+                }
+
+                if connected && broker.home_assistant_discovery() {
+                    connected = publish_discovery(
+                        &mut mqtt,
+                        broker,
+                        &device_id,
+                        &state_topic,
+                        &presence_topic,
+                    )
+                    .await;
+                }
+
+                if let Some(status) = running_slot_status.take() {
+                    info!("send_task running from slot {:?}", status.slot);
+                    reports.push(ota_status_report(&device_id, &ota_status_topic, &status));
+                }
             } else {
                 info!("send_task MQTT disconnected");
                 connected = false;
@@ -172,90 +583,613 @@ pub async fn send_task<const L: usize>(mut mqtt: impl Client + Publish) {
         }
 
         if let Some(ApplicationStateChange::OTAUpdateStarted) = app_state_change {
-            info!("send_task OTA Update started shutting down mqtt send_task");
-            drop(mqtt);
-            break;
+            info!("send_task OTA Update started, only reporting the update status");
+            ota_running = true;
+        }
+
+        if let Some(ApplicationDataChange::ReportCommandResponse {
+            topic,
+            response,
+            effect,
+        }) = &app_data
+        {
+            let report = QueuedReport {
+                epoch: SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+                topic: topic
+                    .clone()
+                    .unwrap_or_else(|| command_response_topic.clone()),
+                payload: serde_json::to_string(response).unwrap(),
+            };
+
+            if *effect == CommandEffect::None {
+                reports.push(report);
+            } else {
+                if !(connected && publish(&mut mqtt, &report).await) {
+                    warn!(
+                        "send_task failed to publish the response to {}",
+                        response.cmd
+                    );
+                }
+                execute_command_effect(effect).await;
+            }
+        }
+
+        if let Some(ApplicationDataChange::ReportOtaStatus(status)) = &app_data {
+            reports.push(ota_status_report(&device_id, &ota_status_topic, status));
+        }
+
+        if let Some(ApplicationDataChange::ReportConfig(config)) = &app_data {
+            let msg = json!({
+                "state": {
+                    "reported": config
+                }
+            });
+            reports.push(QueuedReport {
+                epoch: SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+                topic: shadow_update_topic.clone(),
+                payload: msg.to_string(),
+            });
+
+            // the unit of the speed entities follows the units setting
+            if connected && config.units.is_some() && broker.home_assistant_discovery() {
+                connected =
+                    publish_discovery(&mut mqtt, broker, &device_id, &state_topic, &presence_topic)
+                        .await;
+            }
         }
-        if let Some(ApplicationDataChange::ReportWindData) = app_data {
+
+        if let Some(ApplicationDataChange::ReportJobStatus {
+            job_id,
+            update,
+            firmware,
+            completed,
+        }) = &app_data
+        {
+            let mut buffer = String::new();
+            update.format_msg(&mut buffer);
+            let report = QueuedReport {
+                epoch: SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+                topic: format!(
+                    "{jobs_topic_prefix}{MQTT_TOPIC_POSTFIX_JOBS}{job_id}{MQTT_TOPIC_POSTFIX_JOB_UPDATE}"
+                ),
+                payload: buffer,
+            };
+
+            if let Some(firmware) = firmware {
+                // IN_PROGRESS needs to be published before the OTA update
+                // shuts down this task, the result is reported after the
+                // restart
+                if !(connected && publish(&mut mqtt, &report).await) {
+                    warn!("send_task failed to report job {job_id} as in progress");
+                }
+                info!("send_task starting firmware update of job {job_id}");
+                let publisher = APPLICATION_EVENT_CHANNEL.publisher().unwrap();
+                publisher
+                    .publish(ApplicationStateChange::OTAUpdateRequest(firmware.clone()))
+                    .await;
+            } else {
+                reports.push(report);
+                job_completed = *completed;
+            }
+        }
+
+        if !ota_running && matches!(app_data, Some(ApplicationDataChange::ReportWindData)) {
             let mut avg_speed = 0.0;
-            let mut wind_gust = 0.0;
+            let mut wind_gust = Gust::default();
+            let mut avg_direction = 0.0;
+            let mut calibration = Calibration::default();
+            let mut windows = Vec::new();
+            let mut sensor_health = SensorHealth::default();
+            let mut environment = None;
 
             if let Ok(mut wind_historian) = (*WIND_DATA_HISTORY).lock() {
                 avg_speed = wind_historian.avg_speed();
-                wind_gust = wind_historian.gust_speed();
+                wind_gust = wind_historian.peak_gust().unwrap_or_default();
+                avg_direction = wind_historian.avg_direction();
+                calibration = wind_historian.calibration().clone();
+                windows = wind_historian.statistics();
                 wind_historian.clear_wind_gust();
             };
+            if let Ok(health) = (*SENSOR_HEALTH).lock() {
+                sensor_health = *health;
+            }
+            if let Ok(history) = (*ENVIRONMENT).lock() {
+                environment = history.report(avg_speed);
+            }
+
+            info!(
+                "send_task send wind speed = {avg_speed}, wind gust = {}, wind direction = {avg_direction}",
+                wind_gust.speed
+            );
+            if sensor_health.status != SensorStatus::Ok {
+                warn!("send_task sensor health: {:?}", sensor_health);
+            }
 
-            info!("send_task send wind speed = {avg_speed}, wind gust = {wind_gust}");
+            if let Ok(now) = datetime::get_datetime() {
+                // check if we have a valid system time
+                if now.year() > 1970 {
+                    let format = time::format_description::parse(
+                        "[day].[month].[year] [hour]:[minute]:[second]",
+                    )
+                    .expect("Invalid format.");
 
-            if connected {
-                if let Ok(now) = datetime::get_datetime() {
-                    // check if we have a valid system time
-                    if now.year() > 1970 {
-                        let format = time::format_description::parse(
-                            "[day].[month].[year] [hour]:[minute]:[second]",
-                        )
-                        .expect("Invalid format.");
+                    if boot_timestamp.year() == 1970 {
+                        boot_timestamp = now;
+                    }
+                    // the completed wind rose and summaries are kept by the
+                    // trackers until they can be time stamped and queued
+                    let wind_rose = (*WIND_ROSE)
+                        .lock()
+                        .ok()
+                        .and_then(|mut tracker| tracker.take_completed());
+                    let summaries = (*SUMMARIES)
+                        .lock()
+                        .map(|mut tracker| tracker.take_completed())
+                        .unwrap_or_default();
+
+                    let epoch_time = SystemTime::now()
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs();
+                    if boot_epoch == 0 {
+                        boot_epoch = epoch_time;
+                    }
+                    let units = *super::super::UNITS.lock().unwrap();
+                    let speed_unit = units.speed_unit;
+                    let windows: Vec<WindowStatistics> = windows
+                        .iter()
+                        .map(|stats| stats.convert(speed_unit))
+                        .collect();
+                    let schema =
+                        TelemetrySchema::from_version(TELEMETRY_SCHEMA.load(Ordering::Relaxed))
+                            .unwrap_or_default();
+                    let mut buffer: String = String::new();
 
-                        if boot_timestamp.year() == 1970 {
-                            boot_timestamp = now;
+                    match schema {
+                        TelemetrySchema::Legacy => {
+                            let time = now.format(&format).expect("Could not format time.");
+                            let boot_time = boot_timestamp
+                                .format(&format)
+                                .expect("Could not format time.");
+                            let avg_speed_string = format!(
+                                "{:.*}",
+                                speed_unit.decimals(),
+                                speed_unit.convert(avg_speed)
+                            )
+                            .trim()
+                            .replace('.', ",");
+                            let wind_gust_string = format!(
+                                "{:.*}",
+                                speed_unit.decimals(),
+                                speed_unit.convert(wind_gust.speed)
+                            )
+                            .trim()
+                            .replace('.', ",");
+                            let gust_direction_string = format!("{:.1}", wind_gust.direction)
+                                .trim()
+                                .replace('.', ",");
+                            // no gust is available if less than 3 sec have been sampled
+                            let gust_time = if wind_gust.time > 0 {
+                                datetime::get_datetime_from_unixtime(wind_gust.time)
+                                    .map(|gust_time| {
+                                        gust_time.format(&format).expect("Could not format time.")
+                                    })
+                                    .unwrap_or_default()
+                            } else {
+                                String::new()
+                            };
+                            let avg_direction_string =
+                                format!("{avg_direction:.1}").trim().replace('.', ",");
+                            let epoch = (epoch_time as i64).to_string();
+
+                            let msg = AWSShadowUpdate {
+                                windDirText: units.compass_points.name(avg_direction),
+                                deviceId: device_id.as_str(),
+                                timeStamp: time.as_str(),
+                                epochTime: epoch.as_str(),
+                                bootTimeStamp: boot_time.as_str(),
+                                windDir: avg_direction_string.as_str(),
+                                windSpeed: avg_speed_string.as_str(),
+                                windGust: wind_gust_string.as_str(),
+                                speedUnit: speed_unit.symbol(),
+                                gustTimeStamp: gust_time.as_str(),
+                                gustDir: gust_direction_string.as_str(),
+                                fwVer: env!("CARGO_PKG_VERSION"),
+                                calibration: &calibration,
+                                windows: &windows,
+                                windowsUnit: speed_unit.linear_unit().symbol(),
+                                sensorHealth: sensor_health,
+                                environment,
+                            };
+                            msg.format_aws_device_update_msg(&mut buffer);
                         }
+                        TelemetrySchema::Typed => {
+                            let report = WindReport {
+                                schema_version: TELEMETRY_SCHEMA_VERSION,
+                                device_id: device_id.as_str(),
+                                timestamp: format_rfc3339(epoch_time),
+                                epoch_time,
+                                boot_timestamp: format_rfc3339(boot_epoch),
+                                firmware_version: env!("CARGO_PKG_VERSION"),
+                                speed_unit: speed_unit.symbol(),
+                                direction_unit: DIRECTION_UNIT,
+                                speed: round_decimals(
+                                    speed_unit.convert(avg_speed),
+                                    speed_unit.decimals(),
+                                ),
+                                direction: round_decimals(avg_direction, 1),
+                                direction_text: units.compass_points.name(avg_direction),
+                                gust: round_decimals(
+                                    speed_unit.convert(wind_gust.speed),
+                                    speed_unit.decimals(),
+                                ),
+                                gust_direction: round_decimals(wind_gust.direction, 1),
+                                gust_timestamp: (wind_gust.time > 0)
+                                    .then(|| format_rfc3339(wind_gust.time)),
+                                windows_unit: speed_unit.linear_unit().symbol(),
+                                windows: &windows,
+                                calibration: &calibration,
+                                sensor_health,
+                                environment,
+                            };
+                            format_wind_report_msg(&report, &mut buffer);
+                        }
+                    }
+                    reports.push(QueuedReport {
+                        epoch: epoch_time,
+                        topic: shadow_update_topic.clone(),
+                        payload: buffer.clone(),
+                    });
 
-                        let time = now.format(&format).expect("Could not format time.");
-                        let boot_time = boot_timestamp
-                            .format(&format)
-                            .expect("Could not format time.");
-                        let avg_speed_string = format!("{avg_speed:.2}").trim().replace('.', ",");
-                        let wind_gust_string = format!("{wind_gust:.2}").trim().replace('.', ",");
-                        let epoch = (SystemTime::now()
-                            .duration_since(SystemTime::UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs() as i64)
-                            .to_string();
-
-                        let msg = AWSShadowUpdate {
-                            windDirText: "NN",
-                            deviceId: device_id.as_str(),
-                            timeStamp: time.as_str(),
-                            epochTime: epoch.as_str(),
-                            bootTimeStamp: boot_time.as_str(),
-                            windDir: "0,0",
-                            windSpeed: avg_speed_string.as_str(),
-                            windGust: wind_gust_string.as_str(),
-                            fwVer: env!("CARGO_PKG_VERSION"),
+                    // the wind speed and direction topics are always published
+                    // in the typed schema, they have no legacy consumers
+                    let msg = WindSpeedReport {
+                        schema_version: TELEMETRY_SCHEMA_VERSION,
+                        device_id: device_id.as_str(),
+                        timestamp: format_rfc3339(epoch_time),
+                        speed: round_decimals(speed_unit.convert(avg_speed), speed_unit.decimals()),
+                        gust: round_decimals(
+                            speed_unit.convert(wind_gust.speed),
+                            speed_unit.decimals(),
+                        ),
+                        unit: speed_unit.symbol(),
+                    };
+                    reports.push(QueuedReport {
+                        epoch: epoch_time,
+                        topic: wind_speed_topic.clone(),
+                        payload: serde_json::to_string(&msg).unwrap(),
+                    });
+                    let msg = WindDirectionReport {
+                        schema_version: TELEMETRY_SCHEMA_VERSION,
+                        device_id: device_id.as_str(),
+                        timestamp: format_rfc3339(epoch_time),
+                        direction: round_decimals(avg_direction, 1),
+                        direction_text: units.compass_points.name(avg_direction),
+                        unit: DIRECTION_UNIT,
+                    };
+                    reports.push(QueuedReport {
+                        epoch: epoch_time,
+                        topic: wind_direction_topic.clone(),
+                        payload: serde_json::to_string(&msg).unwrap(),
+                    });
+
+                    if broker.home_assistant_discovery() {
+                        let msg = HomeAssistantState {
+                            speed: round_decimals(
+                                speed_unit.convert(avg_speed),
+                                speed_unit.decimals(),
+                            ),
+                            gust: round_decimals(
+                                speed_unit.convert(wind_gust.speed),
+                                speed_unit.decimals(),
+                            ),
+                            direction: round_decimals(avg_direction, 1),
+                            direction_text: units.compass_points.name(avg_direction),
+                            rssi: wifi_rssi(),
+                            firmware: env!("CARGO_PKG_VERSION"),
                         };
-                        let mut buffer: String = String::new();
-
-                        info!("Posting update to {}", shadow_update_topic);
-                        msg.format_aws_device_update_msg(&mut buffer);
-                        // TODO: check error handling this panics
-                        if let Ok(_msg_id) = error::check!(
-                            mqtt.publish(
-                                shadow_update_topic.as_str(),
-                                QoS::AtLeastOnce,
-                                false,
-                                buffer.as_bytes()
-                            )
-                            .await
-                        ) {
-                            info!("send_task published to {}", shadow_update_topic.as_str());
-                        } else {
-                            connected = false;
-                            error!(
-                                "send_task failed to publish to {}",
-                                shadow_update_topic.as_str()
-                            );
+                        reports.push(QueuedReport {
+                            epoch: epoch_time,
+                            topic: state_topic.clone(),
+                            payload: serde_json::to_string(&msg).unwrap(),
+                        });
+                    }
+
+                    if let Some(wind_rose) = wind_rose {
+                        match schema {
+                            TelemetrySchema::Legacy => {
+                                let start_time =
+                                    datetime::get_datetime_from_unixtime(wind_rose.start_time)
+                                        .map(|start_time| {
+                                            start_time
+                                                .format(&format)
+                                                .expect("Could not format time.")
+                                        })
+                                        .unwrap_or_default();
+                                let msg = WindRoseUpdate {
+                                    deviceId: device_id.as_str(),
+                                    startTimeStamp: start_time.as_str(),
+                                    speedUnit: SpeedUnit::KilometersPerHour.symbol(),
+                                    windRose: &wind_rose,
+                                };
+                                msg.format_wind_rose_msg(&mut buffer);
+                            }
+                            TelemetrySchema::Typed => {
+                                let msg =
+                                    WindRoseReport::new(device_id.as_str(), &wind_rose, speed_unit);
+                                buffer = serde_json::to_string(&msg).unwrap();
+                            }
                         }
-                    } else {
-                        info!("no vaild system time");
+                        reports.push(QueuedReport {
+                            epoch: epoch_time,
+                            topic: wind_rose_topic.clone(),
+                            payload: buffer.clone(),
+                        });
                     }
+
+                    for summary in summaries {
+                        match schema {
+                            TelemetrySchema::Legacy => {
+                                let format_time = |unixtime| {
+                                    datetime::get_datetime_from_unixtime(unixtime)
+                                        .map(|datetime| {
+                                            datetime
+                                                .format(&format)
+                                                .expect("Could not format time.")
+                                        })
+                                        .unwrap_or_default()
+                                };
+                                let start_time = format_time(summary.start_time);
+                                let end_time = format_time(summary.end_time);
+                                let gust_time = summary
+                                    .peak_gust
+                                    .map(|gust| format_time(gust.time))
+                                    .unwrap_or_default();
+                                let msg = SummaryUpdate {
+                                    deviceId: device_id.as_str(),
+                                    startTimeStamp: start_time.as_str(),
+                                    endTimeStamp: end_time.as_str(),
+                                    gustTimeStamp: gust_time.as_str(),
+                                    speedUnit: SpeedUnit::KilometersPerHour.symbol(),
+                                    summary: &summary,
+                                };
+                                msg.format_summary_msg(&mut buffer);
+                            }
+                            TelemetrySchema::Typed => {
+                                let msg = WindSummaryReport::new(
+                                    device_id.as_str(),
+                                    &summary,
+                                    speed_unit,
+                                );
+                                buffer = serde_json::to_string(&msg).unwrap();
+                            }
+                        }
+
+                        let mut summary_topic = summary_topic_prefix.clone();
+                        summary_topic.push_str(match summary.period {
+                            SummaryPeriod::Hourly => MQTT_TOPIC_POSTFIX_SUMMARY_HOURLY,
+                            SummaryPeriod::Daily => MQTT_TOPIC_POSTFIX_SUMMARY_DAILY,
+                        });
+
+                        reports.push(QueuedReport {
+                            epoch: epoch_time,
+                            topic: summary_topic,
+                            payload: buffer.clone(),
+                        });
+                    }
+                } else {
+                    info!("no vaild system time");
                 }
-            } else {
-                info!(
-                    "send_task client not connected, skipping publishment to {}",
-                    shadow_update_topic.as_str()
-                );
             }
         }
+
+        // Live samples are neither queued nor retried, a sample missed is
+        // of no use later on
+        if live_due {
+            let (samples, active) = {
+                let mut live_stream = LIVE_STREAM.lock().unwrap();
+                (live_stream.take_samples(), live_stream.is_active())
+            };
+            if connected && !ota_running {
+                for sample in samples {
+                    if !publish_live(&mut mqtt, &live_topic, &sample.format_msg()).await {
+                        connected = false;
+                        break;
+                    }
+                }
+            }
+            if !active {
+                info!("send_task live stream ended");
+            }
+        }
+
+        // Reports are published right away only if no older reports are
+        // waiting, otherwise the order would be lost
+        for report in reports {
+            if connected && report_queue.is_empty() {
+                if publish(&mut mqtt, &report).await {
+                    continue;
+                }
+                connected = false;
+            }
+            report_queue.push(report);
+            info!(
+                "send_task report queued, {} reports pending, {} dropped",
+                report_queue.len(),
+                report_queue.dropped()
+            );
+        }
+
+        // the record is kept until the final job status is on its way,
+        // otherwise it would be lost if the device restarts before
+        if job_completed {
+            job_store::remove();
+        }
+
+        // replay one queued report per tick, so the reconnect doesn't flood
+        // the broker and new events are still handled in between
+        if replay_due {
+            // the next report is replayed one interval after this one
+            replay_deadline = None;
+            if let Some(report) = report_queue.front() {
+                if publish(&mut mqtt, &report).await {
+                    report_queue.pop_front();
+                    if report_queue.is_empty() {
+                        info!("send_task all queued reports replayed");
+                    }
+                } else {
+                    connected = false;
+                }
+            }
+        }
+    }
+}
+
+fn ota_status_report(device_id: &str, topic: &str, status: &OtaStatus) -> QueuedReport {
+    let msg = OtaStatusUpdate {
+        deviceId: device_id,
+        epochTime: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+        status,
+    };
+    let mut buffer = String::new();
+    msg.format_ota_status_msg(&mut buffer);
+
+    QueuedReport {
+        epoch: msg.epochTime,
+        topic: topic.into(),
+        payload: buffer,
+    }
+}
+
+async fn publish(mqtt: &mut impl Publish, report: &QueuedReport) -> bool {
+    if let Ok(_msg_id) = error::check!(
+        mqtt.publish(
+            report.topic.as_str(),
+            QoS::AtLeastOnce,
+            false,
+            report.payload.as_bytes()
+        )
+        .await
+    ) {
+        info!("send_task published to {}", report.topic);
+        true
+    } else {
+        error!("send_task failed to publish to {}", report.topic);
+        false
+    }
+}
+
+async fn execute_command_effect(effect: &CommandEffect) {
+    match effect {
+        CommandEffect::None => {}
+        CommandEffect::OtaUpdate(url) => {
+            let publisher = APPLICATION_EVENT_CHANNEL.publisher().unwrap();
+            publisher
+                .publish(ApplicationStateChange::OTAUpdateRequest(url.clone()))
+                .await;
+        }
+        CommandEffect::Restart | CommandEffect::FactoryReset => {
+            if *effect == CommandEffect::FactoryReset {
+                info!("send_task resetting settings to the firmware defaults");
+                if let Err(err) = configuration::reset_settings("conf") {
+                    error!("Failed to reset settings: {err}");
+                }
+            }
+            super::summary::save_checkpoint();
+            // the response is still in the outbox of the MQTT client
+            Timer::after(Duration::from_millis(global_settings::COMMAND_EFFECT_DELAY)).await;
+            unsafe {
+                esp_idf_sys::esp_restart();
+            }
+        }
+    }
+}
+
+// Retained discovery configs, Home Assistant creates the entities of the
+// device from these
+async fn publish_discovery(
+    mqtt: &mut impl Publish,
+    broker: &BrokerSettings,
+    device_id: &str,
+    state_topic: &str,
+    presence_topic: &str,
+) -> bool {
+    let speed_unit = super::super::UNITS.lock().unwrap().speed_unit;
+    for message in discovery_messages(
+        &broker.discovery_prefix,
+        device_id,
+        state_topic,
+        presence_topic,
+        speed_unit,
+        env!("CARGO_PKG_VERSION"),
+    ) {
+        if !publish_retained(mqtt, &message.topic, &message.payload).await {
+            return false;
+        }
+    }
+    info!("send_task published Home Assistant discovery");
+    true
+}
+
+async fn publish_retained(mqtt: &mut impl Publish, topic: &str, payload: &str) -> bool {
+    if let Ok(_msg_id) = error::check!(
+        mqtt.publish(topic, QoS::AtLeastOnce, true, payload.as_bytes())
+            .await
+    ) {
+        info!("send_task published retained to {}", topic);
+        true
+    } else {
+        error!("send_task failed to publish to {}", topic);
+        false
+    }
+}
+
+// Sent at most once, the sample rate is too high to wait for an
+// acknowledge of every sample
+async fn publish_live(mqtt: &mut impl Publish, topic: &str, payload: &str) -> bool {
+    if let Ok(_msg_id) = error::check!(
+        mqtt.publish(topic, QoS::AtMostOnce, false, payload.as_bytes())
+            .await
+    ) {
+        true
+    } else {
+        error!("send_task failed to publish to {}", topic);
+        false
+    }
+}
+
+// Unix time of the boot, unknown as long as the time hasn't been set by SNTP
+fn boot_time() -> Option<u64> {
+    let now = datetime::get_datetime().ok()?;
+    if now.year() == 1970 {
+        return None;
+    }
+    let epoch_time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    let uptime = unsafe { esp_idf_sys::esp_timer_get_time() } as u64 / 1_000_000;
+
+    Some(epoch_time.saturating_sub(uptime))
+}
+
+// Completes at the deadline of the next replay, never if no reports are
+// waiting
+async fn replay_tick(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => Timer::at(deadline).await,
+        None => core::future::pending::<()>().await,
     }
 }
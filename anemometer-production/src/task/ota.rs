@@ -31,7 +31,10 @@
  */
 use crate::configuration::AwsIoTCertificates;
 use crate::state::*;
-use crate::utils::{aws_credential_service::*, errors::*};
+use crate::utils::{aws_credential_service::*, errors::*, job_store};
+use anemometer_core::ota::{
+    format_update_summary, FirmwareSummary, OtaProgress, OtaStatus, SlotSummary,
+};
 use core::mem;
 use core::ptr;
 use embassy_time::{Duration, Timer};
@@ -69,9 +72,11 @@ pub async fn ota_task(aws_certificates: &'static AwsIoTCertificates) {
             let publisher = APPLICATION_EVENT_CHANNEL.publisher().unwrap();
 
             // Notify all tasks that the OTA update started. These tasks are
-            // expected to shutdown
+            // expected to shutdown, except for the mqtt tasks which report
+            // the progress of the update
             let data = ApplicationStateChange::OTAUpdateStarted;
             publisher.publish(data).await;
+            report_status(OtaStatus::started(&firmware_file_name)).await;
             Timer::after(Duration::from_secs(2)).await;
 
             if let Err(err) = perform_update(
@@ -82,11 +87,18 @@ pub async fn ota_task(aws_certificates: &'static AwsIoTCertificates) {
                 aws_certificates,
             ) {
                 error!("Firmware update failed: {err}");
+                let reason = format!("{err:?}");
+                report_status(OtaStatus::failed(&firmware_file_name, &reason)).await;
+                // reported as reason of the failed job after the restart
+                job_store::set_failed(&reason);
             } else {
                 info!("Firmware update successful. Restarting device.");
+                report_status(OtaStatus::succeeded(&firmware_file_name)).await;
             }
 
+            // gives the mqtt tasks time to publish the result
             esp_idf_hal::delay::FreeRtos::delay_ms(5000);
+            super::summary::save_checkpoint();
             unsafe {
                 esp_idf_sys::esp_restart();
             }
@@ -94,6 +106,52 @@ pub async fn ota_task(aws_certificates: &'static AwsIoTCertificates) {
     }
 }
 
+// Slot and firmware version the device is running from, published after
+// the restart
+pub fn running_slot_status() -> Option<OtaStatus> {
+    let ota = match EspOta::new() {
+        Ok(ota) => ota,
+        Err(err) => {
+            error!("Failed to access OTA API: {err}");
+            return None;
+        }
+    };
+
+    match ota.get_running_slot() {
+        Ok(slot) => Some(OtaStatus::running(
+            slot.label.as_str(),
+            slot.firmware.as_ref().map(|fw| fw.version.as_str()),
+        )),
+        Err(err) => {
+            error!("Failed to read running slot: {err}");
+            None
+        }
+    }
+}
+
+// Waits for space in the channel, the start and the result of the update
+// must reach the mqtt task
+async fn report_status(status: OtaStatus) {
+    APPLICATION_DATA_CHANNEL
+        .publisher()
+        .unwrap()
+        .publish(ApplicationDataChange::ReportOtaStatus(status))
+        .await;
+}
+
+// Called from the blocking update, the progress is dropped if the channel
+// is full. publish_immediate would drop the oldest message instead, which
+// could be another report.
+fn report_progress(status: OtaStatus) {
+    if APPLICATION_DATA_CHANNEL
+        .immediate_publisher()
+        .try_publish(ApplicationDataChange::ReportOtaStatus(status))
+        .is_err()
+    {
+        warn!("OTA progress dropped, mqtt task lagging behind");
+    }
+}
+
 // TODO: as of Dec 2022 there is no async http client implementation for ESP IDF.
 // once an async implementation becomes available rework this code to become async
 fn perform_update(
@@ -200,6 +258,7 @@ fn perform_update(
 
     let mut bytes_read_total = 0;
     let mut image_header_was_checked = false;
+    let mut progress = OtaProgress::new(content_length);
 
     loop {
         let data_read = match client.read(&mut ota_write_data) {
@@ -234,10 +293,10 @@ fn perform_update(
 
             format_update_summary(
                 &mut update_summary,
-                boot_slot.clone(),
-                run_slot.clone(),
-                update_slot.clone(),
-                fw_info.clone(),
+                &slot_summary(&boot_slot),
+                &slot_summary(&run_slot),
+                &slot_summary(&update_slot),
+                &firmware_summary(&fw_info),
             );
             info!("\n{update_summary}\n");
 
@@ -250,6 +309,9 @@ fn perform_update(
         }
 
         bytes_read_total += data_read;
+        if let Some(percent) = progress.update(bytes_read_total) {
+            report_progress(OtaStatus::downloading(firmware_file_name, percent));
+        }
 
         if data_read > 0 {
             if let Err(err) = ota_update.write(&ota_write_data) {
@@ -279,72 +341,17 @@ fn perform_update(
     Ok(())
 }
 
-fn format_update_summary<const N: usize>(
-    update_summary: &mut heapless::String<N>,
-    boot_slot: Slot,
-    run_slot: Slot,
-    update_slot: Slot,
-    ota_image_info: FirmwareInfo,
-) {
-    let mut label: heapless::String<10> = heapless::String::new();
-
-    update_summary.push_str("OTA Update Summary\n").unwrap();
-    update_summary.push_str("==================\n").unwrap();
-    update_summary.push_str("Boot   partition: ").unwrap();
-    copy_truncated_string(&mut label, boot_slot.label);
-    update_summary.push_str(label.as_str()).unwrap();
-    update_summary.push_str(", ").unwrap();
-    add_firmware_info(update_summary, boot_slot.firmware);
-
-    update_summary.push_str("\nRun    partition: ").unwrap();
-    label = heapless::String::new();
-    copy_truncated_string(&mut label, run_slot.label);
-    update_summary.push_str(label.as_str()).unwrap();
-    update_summary.push_str(", ").unwrap();
-    add_firmware_info(update_summary, run_slot.firmware);
-
-    update_summary.push_str("\nUpdate partition: ").unwrap();
-    label = heapless::String::new();
-    copy_truncated_string(&mut label, update_slot.label);
-    update_summary.push_str(label.as_str()).unwrap();
-    update_summary.push_str(", ").unwrap();
-    add_firmware_info(update_summary, update_slot.firmware);
-    update_summary.push_str("\n").unwrap();
-
-    update_summary.push_str("\nDownloaded FW  : ").unwrap();
-    add_firmware_info(update_summary, Some(ota_image_info));
-    update_summary.push_str("\n").unwrap();
-}
-
-fn add_firmware_info<const N: usize>(
-    update_summary: &mut heapless::String<N>,
-    firmware: Option<FirmwareInfo>,
-) {
-    let mut version: heapless::String<10> = heapless::String::new();
-    let mut released: heapless::String<19> = heapless::String::new();
-    let mut description: heapless::String<32> = heapless::String::new();
-
-    if let Some(fw) = firmware {
-        copy_truncated_string(&mut version, fw.version);
-        update_summary.push_str(version.as_str()).unwrap();
-        update_summary.push_str(", ").unwrap();
-        copy_truncated_string(&mut released, fw.released);
-        update_summary.push_str(released.as_str()).unwrap();
-        if let Some(desc) = fw.description {
-            update_summary.push_str(", ").unwrap();
-            copy_truncated_string(&mut description, desc);
-            update_summary.push_str(description.as_str()).unwrap();
-        }
+fn slot_summary(slot: &Slot) -> SlotSummary<'_> {
+    SlotSummary {
+        label: slot.label.as_str(),
+        firmware: slot.firmware.as_ref().map(firmware_summary),
     }
 }
 
-fn copy_truncated_string<const N: usize, const M: usize>(
-    dest: &mut heapless::String<N>,
-    src: heapless::String<M>,
-) {
-    src.as_str()
-        .chars()
-        .enumerate()
-        .take_while(|c| c.0 < N)
-        .for_each(|c| dest.push(c.1).unwrap());
+fn firmware_summary(firmware: &FirmwareInfo) -> FirmwareSummary<'_> {
+    FirmwareSummary {
+        version: firmware.version.as_str(),
+        released: firmware.released.as_str(),
+        description: firmware.description.as_ref().map(|desc| desc.as_str()),
+    }
 }
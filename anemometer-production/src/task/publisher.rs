@@ -29,8 +29,8 @@
  * See the License for the specific language governing permissions and
  * limitations under the License.
  */
-use crate::global_settings;
 use crate::state::*;
+use core::sync::atomic::Ordering;
 use embassy_futures::select::{select, Either};
 use embassy_time::{Duration, Timer};
 use log::*;
@@ -42,7 +42,7 @@ pub async fn wind_speed_task() {
     loop {
         let (timer_fired, app_state_change) = match select(
             Timer::after(Duration::from_secs(
-                global_settings::DATA_REPORTING_INTERVAL,
+                REPORTING_INTERVAL.load(Ordering::Relaxed),
             )),
             app_event.next_message_pure(),
         )
@@ -0,0 +1,146 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::global_settings;
+use crate::state::*;
+use crate::utils::datetime;
+use anemometer_core::summary::SummaryCheckpoint;
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Timer};
+use esp_idf_svc::nvs::{EspCustomNvs, EspCustomNvsPartition};
+use esp_idf_sys::EspError;
+use log::*;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+// The summaries are checkpointed to the namespace "summary" of their own
+// partition. The checkpoint is rewritten every few minutes and would wear
+// out the small default partition, which also holds the Wi-Fi settings.
+const SUMMARY_NAMESPACE: &str = "summary";
+const CHECKPOINT_KEY: &str = "checkpoint";
+// Interval for updating the UTC offset of the local time zone [sec]
+const UTC_OFFSET_UPDATE_INTERVAL: u64 = 60;
+
+static SUMMARY_STORAGE: Mutex<Option<EspCustomNvs>> = Mutex::new(None);
+
+// Opens the checkpoint storage and restores the summaries of the last
+// checkpoint. The time zone needs to be initialized before.
+pub fn init(partition: &str) -> Result<(), EspError> {
+    let part = EspCustomNvsPartition::take(partition)?;
+    let nvs = EspCustomNvs::new(part, SUMMARY_NAMESPACE, true)?;
+
+    if let Some(len) = nvs.len(CHECKPOINT_KEY)? {
+        let mut buffer = vec![0; len];
+        if let Some(data) = nvs.get_raw(CHECKPOINT_KEY, &mut buffer)? {
+            match postcard::from_bytes::<SummaryCheckpoint>(data) {
+                Ok(checkpoint) => {
+                    info!(
+                        "Restoring summaries, {} completed summaries pending",
+                        checkpoint.completed.len()
+                    );
+                    SUMMARIES.lock().unwrap().restore(checkpoint);
+                }
+                Err(err) => warn!("Invalid summary checkpoint, discarding it: {err}"),
+            }
+        }
+    }
+    update_utc_offset();
+
+    *SUMMARY_STORAGE.lock().unwrap() = Some(nvs);
+
+    Ok(())
+}
+
+// Writes the running and the not yet published summaries to flash. Called
+// periodically and before the device restarts.
+pub fn save_checkpoint() {
+    let checkpoint = SUMMARIES.lock().unwrap().checkpoint();
+    let data = match postcard::to_allocvec(&checkpoint) {
+        Ok(data) => data,
+        Err(err) => {
+            error!("Failed to serialize summary checkpoint: {err}");
+            return;
+        }
+    };
+
+    if let Some(nvs) = SUMMARY_STORAGE.lock().unwrap().as_mut() {
+        match nvs.set_raw(CHECKPOINT_KEY, &data) {
+            Ok(_) => info!("Summary checkpoint written ({} bytes)", data.len()),
+            Err(err) => error!("Failed to write summary checkpoint: {err}"),
+        }
+    }
+}
+
+// The UTC offset changes with daylight saving time, the summaries need it
+// to roll over at the local hour and midnight
+fn update_utc_offset() {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    match datetime::get_utc_offset(now) {
+        Ok(utc_offset) => UTC_OFFSET.store(utc_offset, Ordering::Relaxed),
+        Err(err) => warn!("Failed to determine UTC offset: {err}"),
+    }
+}
+
+pub async fn summary_task() {
+    let mut app_event = APPLICATION_EVENT_CHANNEL.subscriber().unwrap();
+    let mut elapsed = 0;
+    info!("Summary Task Started");
+
+    loop {
+        match select(
+            Timer::after(Duration::from_secs(UTC_OFFSET_UPDATE_INTERVAL)),
+            app_event.next_message_pure(),
+        )
+        .await
+        {
+            Either::First(_) => {
+                update_utc_offset();
+
+                elapsed += UTC_OFFSET_UPDATE_INTERVAL;
+                if elapsed >= global_settings::SUMMARY_CHECKPOINT_INTERVAL {
+                    elapsed = 0;
+                    save_checkpoint();
+                }
+            }
+            Either::Second(ApplicationStateChange::OTAUpdateStarted) => {
+                info!("summary_task OTA Update started shutting down summary_task");
+                save_checkpoint();
+                break;
+            }
+            Either::Second(_) => (),
+        }
+    }
+}
@@ -29,9 +29,12 @@
  * See the License for the specific language governing permissions and
  * limitations under the License.
  */
+pub mod auth_store;
 pub mod aws_credential_service;
 pub mod cstr;
 pub mod datetime;
 pub mod error;
 pub mod errors;
+pub mod job_store;
 pub mod nvs_ext;
+pub mod report_spill;
@@ -0,0 +1,77 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use esp_idf_sys::EspError;
+use log::*;
+use std::sync::Mutex;
+
+// The time stamp of the newest authenticated command is stored in the
+// namespace "cmd_replay" of the default nvs partition. Commands signed up to
+// this time are rejected after a restart, as the nonces seen before are lost.
+const AUTH_NAMESPACE: &str = "cmd_replay";
+const LAST_ACCEPTED_KEY: &str = "last_accepted";
+
+static AUTH_STORAGE: Mutex<Option<EspDefaultNvs>> = Mutex::new(None);
+
+pub fn init(partition: EspDefaultNvsPartition) -> Result<(), EspError> {
+    let nvs = EspDefaultNvs::new(partition, AUTH_NAMESPACE, true)?;
+
+    *AUTH_STORAGE.lock().unwrap() = Some(nvs);
+
+    Ok(())
+}
+
+// unix time [s] of the newest accepted command, 0 if none has been stored
+pub fn load() -> u64 {
+    let storage = AUTH_STORAGE.lock().unwrap();
+    let Some(nvs) = storage.as_ref() else {
+        return 0;
+    };
+
+    let mut buffer = [0; 8];
+    match nvs.get_raw(LAST_ACCEPTED_KEY, &mut buffer) {
+        Ok(Some(data)) => data.try_into().map(u64::from_le_bytes).unwrap_or_default(),
+        Ok(None) => 0,
+        Err(err) => {
+            warn!("Failed to read the last accepted command: {err}");
+            0
+        }
+    }
+}
+
+pub fn save(timestamp: u64) {
+    if let Some(nvs) = AUTH_STORAGE.lock().unwrap().as_mut() {
+        if let Err(err) = nvs.set_raw(LAST_ACCEPTED_KEY, &timestamp.to_le_bytes()) {
+            error!("Failed to write the last accepted command: {err}");
+        }
+    }
+}
@@ -33,19 +33,24 @@ use esp_idf_sys::EspError;
 use std::{convert::TryFrom, time::SystemTime};
 use time::*;
 
-pub fn initialize() -> core::result::Result<esp_idf_svc::sntp::EspSntp, EspError> {
+// timezone is a POSIX TZ string like "CET-1CEST-2,M3.5.0/02:00:00,M10.5.0/03:00:00"
+pub fn initialize(timezone: &str) -> core::result::Result<esp_idf_svc::sntp::EspSntp, EspError> {
     let sntp = esp_idf_svc::sntp::EspSntp::new_default()?;
 
-    // set time zone for Berlin/Germany
-    // taken from here https://sites.google.com/a/usapiens.com/opnode/time-zones
-    let german_tz = std::ffi::CString::new("CET-1CEST-2,M3.5.0/02:00:00,M10.5.0/03:00:00").unwrap();
+    set_timezone(timezone);
+
+    Ok(sntp)
+}
+
+// Changes the time zone used for the local time, the summaries pick up the
+// new UTC offset with their next update
+pub fn set_timezone(timezone: &str) {
+    let tz = std::ffi::CString::new(timezone).unwrap();
     let tz_var = std::ffi::CString::new("TZ").unwrap();
     unsafe {
-        esp_idf_sys::setenv(tz_var.as_ptr(), german_tz.as_ptr(), 1);
+        esp_idf_sys::setenv(tz_var.as_ptr(), tz.as_ptr(), 1);
         esp_idf_sys::tzset();
     }
-
-    Ok(sntp)
 }
 
 pub fn get_datetime() -> Result<PrimitiveDateTime> {
@@ -53,10 +58,23 @@ pub fn get_datetime() -> Result<PrimitiveDateTime> {
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap();
 
-    let tm = unsafe { *esp_idf_sys::localtime(&(unixtime.as_secs() as i64)) };
+    get_datetime_from_unixtime(unixtime.as_secs())
+}
+
+// Converts a unix time [sec] into the local time of the configured time zone
+pub fn get_datetime_from_unixtime(unixtime: u64) -> Result<PrimitiveDateTime> {
+    let tm = unsafe { *esp_idf_sys::localtime(&(unixtime as i64)) };
     let month = Month::try_from(1u8 + tm.tm_mon as u8)?;
     let date = Date::from_calendar_date(1900 + tm.tm_year, month, tm.tm_mday as _)?;
     let time = Time::from_hms(tm.tm_hour as _, tm.tm_min as _, tm.tm_sec as _)?;
 
     Ok(PrimitiveDateTime::new(date, time))
 }
+
+// Offset of the configured time zone to UTC at the given unix time [sec],
+// including daylight saving time
+pub fn get_utc_offset(unixtime: u64) -> Result<i32> {
+    let local = get_datetime_from_unixtime(unixtime)?;
+
+    Ok((local.assume_utc().unix_timestamp() - unixtime as i64) as i32)
+}
@@ -0,0 +1,108 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use anemometer_core::jobs::{JobOutcome, JobRecord};
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use esp_idf_sys::EspError;
+use log::*;
+use std::sync::Mutex;
+
+// The record of the firmware update job is stored in the namespace "jobs"
+// of the default nvs partition, it survives the restart into the new
+// firmware
+const JOB_NAMESPACE: &str = "jobs";
+const RECORD_KEY: &str = "record";
+
+static JOB_STORAGE: Mutex<Option<EspDefaultNvs>> = Mutex::new(None);
+
+pub fn init(partition: EspDefaultNvsPartition) -> Result<(), EspError> {
+    let nvs = EspDefaultNvs::new(partition, JOB_NAMESPACE, true)?;
+
+    *JOB_STORAGE.lock().unwrap() = Some(nvs);
+
+    if let Some(record) = load() {
+        info!("Pending job record {:?}", record);
+    }
+
+    Ok(())
+}
+
+pub fn load() -> Option<JobRecord> {
+    let storage = JOB_STORAGE.lock().unwrap();
+    let nvs = storage.as_ref()?;
+
+    let len = nvs.len(RECORD_KEY).ok()??;
+    let mut buffer = vec![0; len];
+    let data = nvs.get_raw(RECORD_KEY, &mut buffer).ok()??;
+
+    match postcard::from_bytes(data) {
+        Ok(record) => Some(record),
+        Err(err) => {
+            warn!("Invalid job record, discarding it: {err}");
+            None
+        }
+    }
+}
+
+pub fn save(record: &JobRecord) {
+    let data = match postcard::to_allocvec(record) {
+        Ok(data) => data,
+        Err(err) => {
+            error!("Failed to serialize job record: {err}");
+            return;
+        }
+    };
+
+    if let Some(nvs) = JOB_STORAGE.lock().unwrap().as_mut() {
+        if let Err(err) = nvs.set_raw(RECORD_KEY, &data) {
+            error!("Failed to write job record: {err}");
+        }
+    }
+}
+
+pub fn remove() {
+    if let Some(nvs) = JOB_STORAGE.lock().unwrap().as_mut() {
+        if let Err(err) = nvs.remove(RECORD_KEY) {
+            error!("Failed to remove job record: {err}");
+        }
+    }
+}
+
+// Stores the reason of a failed update, it is reported after the restart.
+// Updates which have not been started by a job have no record.
+pub fn set_failed(reason: &str) {
+    if let Some(mut record) = load() {
+        if record.outcome == JobOutcome::Pending {
+            record.outcome = JobOutcome::Failed(reason.into());
+            save(&record);
+        }
+    }
+}
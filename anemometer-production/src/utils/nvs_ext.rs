@@ -38,6 +38,7 @@ pub trait EspNvsExtention {
     fn len_str(&self, name: &str) -> Result<Option<usize>, EspError>;
     fn get_str<'a>(&self, name: &str, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>, EspError>;
     fn set_str(&mut self, name: &str, val: &str) -> Result<bool, EspError>;
+    fn erase_all(&mut self) -> Result<bool, EspError>;
     fn get_u8<'a>(&self, name: &str, out_val: &'a mut u8) -> Result<Option<&'a u8>, EspError>;
     fn set_u8(&self, name: &str, val: u8) -> Result<bool, EspError>;
     fn get_i8<'a>(&self, name: &str, out_val: &'a mut i8) -> Result<Option<&'a i8>, EspError>;
@@ -119,6 +120,14 @@ impl EspNvsExtention for EspCustomNvs {
         Ok(true)
     }
 
+    fn erase_all(&mut self) -> Result<bool, EspError> {
+        esp!(unsafe { nvs_erase_all(self.handle()) })?;
+
+        esp!(unsafe { nvs_commit(self.handle()) })?;
+
+        Ok(true)
+    }
+
     fn get_u8<'a>(&self, name: &str, out_val: &'a mut u8) -> Result<Option<&'a u8>, EspError> {
         let c_key = CString::new(name).unwrap();
 
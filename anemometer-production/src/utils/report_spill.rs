@@ -0,0 +1,166 @@
+/*
+ * ESP32 Anemometer
+ *
+ * MIT license
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ * Apache license, Version 2.0
+ *
+ * Copyright (c) 2021-2023 Michael Zill
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use anemometer_core::report_queue::{QueuedReport, SpillStorage};
+use esp_idf_svc::nvs::*;
+use esp_idf_sys::EspError;
+use log::*;
+
+const REPORT_NAMESPACE: &str = "reports";
+// head slot, number of reports and capacity as little endian u32
+const INDEX_KEY: &str = "index";
+
+// Ring buffer of queued reports in a dedicated NVS partition. Every report
+// is stored as postcard encoded blob in its own slot "r<n>", NVS takes
+// care of the wear leveling. The reports survive a reboot.
+pub struct NvsReportSpill {
+    nvs: EspCustomNvs,
+    capacity: u32,
+    // slot of the oldest report
+    head: u32,
+    len: u32,
+}
+
+impl NvsReportSpill {
+    pub fn new(partition: &str, capacity: usize) -> Result<Self, EspError> {
+        let part = EspCustomNvsPartition::take(partition)?;
+        let nvs = EspCustomNvs::new(part, REPORT_NAMESPACE, true)?;
+        let capacity = capacity as u32;
+
+        let mut index = [0_u8; 12];
+        let (head, len) = match nvs.get_raw(INDEX_KEY, &mut index)? {
+            Some(index) if index.len() == 12 => {
+                let field = |idx: usize| {
+                    u32::from_le_bytes([index[idx], index[idx + 1], index[idx + 2], index[idx + 3]])
+                };
+                // the slots don't match after a change of the capacity
+                if field(8) == capacity {
+                    (field(0), field(4).min(capacity))
+                } else {
+                    warn!("Report queue capacity changed, discarding queued reports");
+                    (0, 0)
+                }
+            }
+            _ => (0, 0),
+        };
+        info!("Report queue: {len} reports in flash");
+
+        Ok(NvsReportSpill {
+            nvs,
+            capacity,
+            head,
+            len,
+        })
+    }
+
+    fn slot_key(slot: u32) -> String {
+        format!("r{slot}")
+    }
+
+    fn write_index(&mut self) -> Result<bool, EspError> {
+        let mut index = [0_u8; 12];
+        index[0..4].copy_from_slice(&self.head.to_le_bytes());
+        index[4..8].copy_from_slice(&self.len.to_le_bytes());
+        index[8..12].copy_from_slice(&self.capacity.to_le_bytes());
+
+        self.nvs.set_raw(INDEX_KEY, &index)
+    }
+
+    fn read_slot(&self, slot: u32) -> Option<QueuedReport> {
+        let key = Self::slot_key(slot);
+        let len = self.nvs.len(&key).ok()??;
+        let mut buffer = vec![0; len];
+        let data = self.nvs.get_raw(&key, &mut buffer).ok()??;
+
+        postcard::from_bytes(data).ok()
+    }
+}
+
+impl SpillStorage for NvsReportSpill {
+    fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity as usize
+    }
+
+    fn push_back(&mut self, report: &QueuedReport) -> bool {
+        if self.capacity == 0 || self.len >= self.capacity {
+            return false;
+        }
+
+        let slot = (self.head + self.len) % self.capacity;
+        let written = postcard::to_allocvec(report)
+            .map_err(|err| error!("Failed to serialize report: {err}"))
+            .and_then(|data| {
+                self.nvs
+                    .set_raw(&Self::slot_key(slot), &data)
+                    .map_err(|err| error!("Failed to write report to flash: {err}"))
+            })
+            .is_ok();
+
+        if written {
+            self.len += 1;
+            if let Err(err) = self.write_index() {
+                error!("Failed to write report queue index: {err}");
+            }
+        }
+        written
+    }
+
+    fn front(&mut self) -> Option<QueuedReport> {
+        // an unreadable report would block the queue forever
+        while self.len > 0 {
+            match self.read_slot(self.head) {
+                Some(report) => return Some(report),
+                None => {
+                    warn!("Discarding unreadable report in slot {}", self.head);
+                    self.pop_front();
+                }
+            }
+        }
+        None
+    }
+
+    fn pop_front(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+
+        let _ = self.nvs.remove(&Self::slot_key(self.head));
+        self.head = (self.head + 1) % self.capacity;
+        self.len -= 1;
+        if let Err(err) = self.write_index() {
+            error!("Failed to write report queue index: {err}");
+        }
+    }
+}